@@ -1,12 +1,11 @@
 use std::io::Result;
 
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio_util::compat::Compat;
-
 use serde::{Deserialize, Serialize};
 
 use kurosabi::{
-    connection::Connection, http::method::HttpMethod, router::DefaultContext, server::tokio::KurosabiTokioServerBuilder,
+    extract::Json,
+    http::HttpStatusCode,
+    server::tokio::{KurosabiTokioServerBuilder, TokioPathRouter},
 };
 
 const HTML: &str = r#"<!DOCTYPE html>
@@ -60,55 +59,36 @@ async fn main() -> Result<()> {
     env_logger::Builder::new()
         .filter_level(log::LevelFilter::Info)
         .init();
-    let server = KurosabiTokioServerBuilder::default().router_and_build(
-        |mut conn: Connection<DefaultContext, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>| async move {
-            let method = conn.req.method();
-
-            match method {
-                HttpMethod::GET => match conn.path_segs().as_ref() {
-                    // GET /
-                    [""] => conn.html_body(HTML),
-
-                    _ => conn.set_status_code(404u16).no_body(),
-                },
-                HttpMethod::POST => match conn.path_segs().as_ref() {
-                    ["coffee"] => {
-                        let order = match conn.req.read_json_de::<CoffeeOrder>().await {
-                            Ok(o) => o,
-                            Err(_) => {
-                                let conn = conn.set_status_code(400u16);
-                                return match conn.json_body_serialized(&Coffee::Error) {
-                                    Ok(conn) => conn,
-                                    Err(p) => p.connection.set_status_code(500u16).text_body(""),
-                                };
-                            },
-                        };
-
-                        let coffee = match (order.milk, order.sugar) {
-                            (false, Sugar::None) => Coffee::Black,
-                            (true, Sugar::None) => Coffee::WithMilk,
-                            (false, Sugar::One) => Coffee::WithSugar(1),
-                            (false, Sugar::Two) => Coffee::WithSugar(2),
-                            (false, Sugar::Three) => Coffee::WithSugar(3),
-                            (true, Sugar::One) => Coffee::WithMilkAndSugar(1),
-                            (true, Sugar::Two) => Coffee::WithMilkAndSugar(2),
-                            (true, Sugar::Three) => Coffee::WithMilkAndSugar(3),
-                        };
-
-                        match conn.json_body_serialized(&coffee) {
-                            Ok(conn) => conn,
-                            Err(p) => p.connection.set_status_code(500u16).text_body(""),
-                        }
-                    },
-                    _ => conn.set_status_code(404u16).no_body(),
-                },
-                _ => conn.set_status_code(405u16).no_body(),
-            }
-        },
-    );
+    let router = TokioPathRouter::builder()
+        .get("/", |conn| async move { conn.html_body(HTML) })
+        // Connection を手放して値を返せば、ルーターが書き込んでくれるので `?` が使える
+        .post("/coffee", |mut conn| async move {
+            let order = conn
+                .req
+                .read_json_de::<CoffeeOrder>()
+                .await
+                .map_err(|_| (HttpStatusCode::BadRequest, Json(Coffee::Error)))?;
+            Ok::<_, (HttpStatusCode, Json<Coffee>)>(Json(brew(order)))
+        })
+        .build()
+        .expect("route conflict");
+    let server = KurosabiTokioServerBuilder::default().handler_and_build(router);
     server.run().await
 }
 
+fn brew(order: CoffeeOrder) -> Coffee {
+    match (order.milk, order.sugar) {
+        (false, Sugar::None) => Coffee::Black,
+        (true, Sugar::None) => Coffee::WithMilk,
+        (false, Sugar::One) => Coffee::WithSugar(1),
+        (false, Sugar::Two) => Coffee::WithSugar(2),
+        (false, Sugar::Three) => Coffee::WithSugar(3),
+        (true, Sugar::One) => Coffee::WithMilkAndSugar(1),
+        (true, Sugar::Two) => Coffee::WithMilkAndSugar(2),
+        (true, Sugar::Three) => Coffee::WithMilkAndSugar(3),
+    }
+}
+
 #[derive(Deserialize)]
 struct CoffeeOrder {
     milk: bool,
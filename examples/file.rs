@@ -5,6 +5,7 @@ use kurosabi::{
     utils::url_encode,
 };
 
+#[allow(deprecated)]
 #[tokio::main(flavor = "multi_thread", worker_threads = 16)]
 async fn main() -> Result<()> {
     env_logger::Builder::new()
@@ -27,12 +28,12 @@ async fn main() -> Result<()> {
                             Err(Some(file_paths)) => {
                                 let html = file_paths
                                     .into_iter()
-                                    .filter_map(|p| p.to_str().map(|s| s.to_string()))
+                                    .filter_map(|p| p.path.to_str().map(|s| s.to_string()))
                                     .map(|p| {
                                         format!(
                                             "<li><a href=\"/file/{}\">{}</a></li>",
                                             p.split(&['/', '\\'][..])
-                                                .map(|s| url_encode(s))
+                                                .map(url_encode)
                                                 .collect::<Vec<_>>()
                                                 .join("/"),
                                             p
@@ -0,0 +1,48 @@
+use std::io::Result;
+
+use kurosabi::{
+    http::HttpStatusCode,
+    server::tokio::{KurosabiTokioServerBuilder, TokioPathRouter},
+};
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 16)]
+async fn main() -> Result<()> {
+    let router = TokioPathRouter::builder()
+        // GET /
+        .get("/", |conn| async move {
+            conn.text_body("Welcome to the Kurosabi HTTP Server!")
+        })
+        // GET /hello/:name
+        .get("/hello/:name", |conn| async move {
+            let body = format!("Hello, {}!", conn.param("name").unwrap_or_default());
+            conn.text_body(body)
+        })
+        // GET /users/:id/files/*rest
+        .get("/users/:id/files/*rest", |conn| async move {
+            let body = format!(
+                "user {} file {}",
+                conn.param("id").unwrap_or_default(),
+                conn.param("rest").unwrap_or_default()
+            );
+            conn.text_body(body)
+        })
+        // POST /echo
+        .post("/echo", |mut conn| async move {
+            match conn.req.read_body_bytes().await {
+                Ok(body) => conn.binary_body(&body),
+                Err(_) => conn.set_status_code(HttpStatusCode::BadRequest).no_body(),
+            }
+        })
+        .fallback(|conn| async move {
+            conn.set_status_code(HttpStatusCode::NotFound)
+                .text_body("not found")
+        })
+        .build()
+        .expect("route conflict");
+
+    let server = KurosabiTokioServerBuilder::default()
+        .bind([0, 0, 0, 0])
+        .port(8080)
+        .handler_and_build(router);
+    server.run().await
+}
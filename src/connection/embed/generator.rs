@@ -0,0 +1,282 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use miniz_oxide::deflate::compress_to_vec;
+
+/// build.rs 用の埋め込みファイル一覧の生成器
+///
+/// ディレクトリ以下の全ファイルを `include_bytes!` する Rust コードを `OUT_DIR` に書き出します
+/// `foo.js.gz` / `foo.js.br` が `foo.js` と並んでいればその圧縮版として扱います
+pub struct EmbedGenerator {
+    dir: PathBuf,
+    name: String,
+    gzip: bool,
+}
+
+/// 生成時に集めた1ファイル分の情報
+struct Entry {
+    path: String,
+    source: PathBuf,
+    mime_type: String,
+    etag: String,
+    gzip: Option<PathBuf>,
+    brotli: Option<PathBuf>,
+}
+
+impl EmbedGenerator {
+    /// 埋め込むディレクトリを指定
+    /// 相対パスは `CARGO_MANIFEST_DIR` 基準です
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let dir = match std::env::var_os("CARGO_MANIFEST_DIR") {
+            Some(root) => Path::new(&root).join(dir),
+            None => dir.as_ref().to_path_buf(),
+        };
+        EmbedGenerator {
+            dir,
+            name: "ASSETS".to_string(),
+            gzip: false,
+        }
+    }
+
+    /// 生成する static の名前
+    pub fn name<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.name = name.into();
+        self
+    }
+
+    /// 圧縮版がないテキスト系ファイルに gzip 版を生成する
+    pub fn gzip(mut self, enable: bool) -> Self {
+        self.gzip = enable;
+        self
+    }
+
+    /// コードを書き出す
+    /// 相対パスは `OUT_DIR` 基準です
+    pub fn write_to<P>(self, file: P) -> std::io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let out_dir = std::env::var_os("OUT_DIR")
+            .map(PathBuf::from)
+            .ok_or_else(|| std::io::Error::other("OUT_DIR is not set (call from build.rs)"))?;
+        println!("cargo:rerun-if-changed={}", self.dir.display());
+
+        let mut sources = Vec::new();
+        collect_files(&self.dir, &mut sources)?;
+        let mut entries = Vec::new();
+        for source in &sources {
+            if let Some(entry) = self.entry(source, &sources, &out_dir)? {
+                entries.push(entry);
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut code = String::new();
+        let _ = writeln!(
+            code,
+            "// generated by kurosabi::connection::embed::EmbedGenerator"
+        );
+        let _ = writeln!(
+            code,
+            "pub static {}: ::kurosabi::connection::embed::EmbeddedAssets = ::kurosabi::connection::embed::EmbeddedAssets::new(&[",
+            self.name
+        );
+        for e in &entries {
+            let _ = writeln!(code, "    ::kurosabi::connection::embed::EmbeddedFile {{");
+            let _ = writeln!(code, "        path: {:?},", e.path);
+            let _ = writeln!(code, "        mime_type: {:?},", e.mime_type);
+            let _ = writeln!(code, "        etag: {:?},", e.etag);
+            let _ = writeln!(
+                code,
+                "        data: include_bytes!({:?}),",
+                path_str(&e.source)?
+            );
+            let _ = writeln!(code, "        gzip: {},", include_opt(e.gzip.as_deref())?);
+            let _ = writeln!(
+                code,
+                "        brotli: {},",
+                include_opt(e.brotli.as_deref())?
+            );
+            let _ = writeln!(code, "    }},");
+        }
+        let _ = writeln!(code, "]);");
+
+        std::fs::write(out_dir.join(file), code)
+    }
+
+    /// 1ファイル分の情報を作る
+    /// 他ファイルの圧縮版なら None
+    fn entry(&self, source: &Path, sources: &[PathBuf], out_dir: &Path) -> std::io::Result<Option<Entry>> {
+        let ext = source.extension().and_then(|e| e.to_str());
+        if matches!(ext, Some("gz" | "br")) && sources.contains(&source.with_extension("")) {
+            return Ok(None);
+        }
+        let rel = source
+            .strip_prefix(&self.dir)
+            .map_err(std::io::Error::other)?;
+        let path = rel
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let data = std::fs::read(source)?;
+        let mime = mime_guess::from_path(source).first_or_octet_stream();
+        let is_text = matches!(mime.type_(), mime_guess::mime::TEXT)
+            || matches!(
+                mime.subtype(),
+                mime_guess::mime::JSON | mime_guess::mime::JAVASCRIPT | mime_guess::mime::XML
+            )
+            || mime.suffix() == Some(mime_guess::mime::XML);
+        let mut mime_type = mime.essence_str().to_string();
+        if is_text && std::str::from_utf8(&data).is_ok() {
+            mime_type.push_str("; charset=utf-8");
+        }
+
+        let sibling = |ext: &str| {
+            let mut p = source.as_os_str().to_owned();
+            p.push(".");
+            p.push(ext);
+            let p = PathBuf::from(p);
+            sources.contains(&p).then_some(p)
+        };
+        let brotli = sibling("br");
+        let gzip = match sibling("gz") {
+            Some(p) => Some(p),
+            None if self.gzip && is_text => {
+                let gz = gzip_encode(&data);
+                if gz.len() < data.len() {
+                    let dst = out_dir
+                        .join("kurosabi-embed")
+                        .join(format!("{:016x}.gz", fnv1a64(path.as_bytes())));
+                    std::fs::create_dir_all(dst.parent().unwrap_or(out_dir))?;
+                    std::fs::write(&dst, gz)?;
+                    Some(dst)
+                } else {
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Ok(Some(Entry {
+            path,
+            source: source.canonicalize()?,
+            mime_type,
+            etag: format!("\"{:016x}\"", fnv1a64(&data)),
+            gzip: gzip.map(|p| p.canonicalize()).transpose()?,
+            brotli: brotli.map(|p| p.canonicalize()).transpose()?,
+        }))
+    }
+}
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), out)?;
+        } else if file_type.is_file() {
+            out.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+fn path_str(path: &Path) -> std::io::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| std::io::Error::other(format!("non utf-8 path: {}", path.display())))
+}
+
+fn include_opt(path: Option<&Path>) -> std::io::Result<String> {
+    Ok(match path {
+        Some(p) => format!("Some(include_bytes!({:?}))", path_str(p)?),
+        None => "None".to_string(),
+    })
+}
+
+/// FNV-1a 64bit
+fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// gzip (RFC 1952) 形式で圧縮する
+fn gzip_encode(data: &[u8]) -> Vec<u8> {
+    let deflated = compress_to_vec(data, 9);
+    let mut out = Vec::with_capacity(deflated.len() + 18);
+    // magic, CM=deflate, FLG=0, MTIME=0, XFL=2 (max compression), OS=255 (unknown)
+    out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2, 255]);
+    out.extend_from_slice(&deflated);
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use miniz_oxide::inflate::decompress_to_vec;
+
+    use super::*;
+
+    #[test]
+    fn crc32_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn fnv1a64_known_values() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a64(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let data: Vec<u8> = "<p>こんにちは</p>\n".repeat(200).into_bytes();
+        for data in [&b""[..], b"a", &data] {
+            let gz = gzip_encode(data);
+            assert_eq!(&gz[..4], &[0x1f, 0x8b, 8, 0]);
+            let (body, trailer) = gz[10..].split_at(gz.len() - 18);
+            assert_eq!(decompress_to_vec(body).unwrap(), data);
+            assert_eq!(trailer[..4], crc32(data).to_le_bytes());
+            assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn gzip_compresses_repetitive_data() {
+        let data = "kurosabi ".repeat(1000).into_bytes();
+        assert!(gzip_encode(&data).len() < data.len() / 10);
+    }
+}
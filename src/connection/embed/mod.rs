@@ -0,0 +1,189 @@
+//! バイナリに埋め込んだ静的ファイルの配信
+//!
+//! build.rs で `EmbedGenerator` を使ってファイル一覧を生成し、
+//! `include_assets!` でクレートに取り込みます
+//!
+//! ```ignore
+//! // build.rs
+//! kurosabi::connection::embed::EmbedGenerator::new("static")
+//!     .name("ASSETS")
+//!     .gzip(true)
+//!     .write_to("assets.rs")
+//!     .unwrap();
+//!
+//! // main.rs
+//! kurosabi::include_assets!("assets.rs");
+//!
+//! match ASSETS.get(conn.req.path()) {
+//!     Some(file) => conn.embedded_body(file).await,
+//!     None => conn.set_status_code(HttpStatusCode::NotFound).no_body(),
+//! }
+//! ```
+
+mod generator;
+
+pub use generator::EmbedGenerator;
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::connection::{Connection, NoneBody, ResponseReadyToSend};
+use crate::http::HttpStatusCode;
+use crate::http::request::{accept_encoding_allows, etag_matches_strong, etag_matches_weak, select_byte_range};
+
+/// 埋め込まれた1ファイル
+/// MIME と ETag はビルド時に計算済み
+#[derive(Debug)]
+pub struct EmbeddedFile {
+    /// ルートからの相対パス (先頭の `/` なし, 区切りは `/`)
+    pub path: &'static str,
+    pub mime_type: &'static str,
+    /// 元データの強い ETag
+    pub etag: &'static str,
+    pub data: &'static [u8],
+    /// 事前圧縮した gzip
+    pub gzip: Option<&'static [u8]>,
+    /// 事前圧縮した brotli
+    pub brotli: Option<&'static [u8]>,
+}
+
+/// 埋め込みファイルの一覧
+/// path でソート済みである必要があります (生成コードは常にソート済み)
+#[derive(Debug)]
+pub struct EmbeddedAssets {
+    files: &'static [EmbeddedFile],
+}
+
+impl EmbeddedAssets {
+    pub const fn new(files: &'static [EmbeddedFile]) -> Self {
+        EmbeddedAssets { files }
+    }
+
+    /// パスからファイルを探す
+    /// 先頭の `/` は無視します
+    #[inline]
+    pub fn get(&self, path: &str) -> Option<&'static EmbeddedFile> {
+        let path = path.trim_start_matches('/');
+        self.files
+            .binary_search_by(|f| f.path.cmp(path))
+            .ok()
+            .map(|i| &self.files[i])
+    }
+
+    #[inline]
+    pub fn iter(&self) -> std::slice::Iter<'static, EmbeddedFile> {
+        self.files.iter()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// 埋め込みファイルの送信するエンコーディング
+enum Encoding {
+    Identity,
+    Gzip(&'static [u8]),
+    Brotli(&'static [u8]),
+}
+
+impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, NoneBody> {
+    /// 埋め込みファイルをレスポンスボディとして送信する
+    /// file_body と同じく Range / If-None-Match / If-Range を考慮します
+    /// Range がない場合は Accept-Encoding に応じて事前圧縮版を返します
+    pub async fn embedded_body(mut self, file: &'static EmbeddedFile) -> Connection<C, R, W, ResponseReadyToSend> {
+        let has_variants = file.gzip.is_some() || file.brotli.is_some();
+        let header_range = self.req.header_get("Range").await.map(|v| v.to_string());
+        let range_allowed = match self.req.header_get("If-Range").await {
+            Some(v) => etag_matches_strong(v, file.etag),
+            None => true,
+        };
+        let header_range = header_range.filter(|_| range_allowed);
+
+        // Range は元データに対して適用するので圧縮版は使わない
+        let encoding = match (
+            header_range.is_some(),
+            self.req.header_get("Accept-Encoding").await,
+        ) {
+            (false, Some(accept)) => match (file.brotli, file.gzip) {
+                (Some(br), _) if accept_encoding_allows(accept, "br") => Encoding::Brotli(br),
+                (_, Some(gz)) if accept_encoding_allows(accept, "gzip") => Encoding::Gzip(gz),
+                _ => Encoding::Identity,
+            },
+            _ => Encoding::Identity,
+        };
+        // 表現ごとに ETag を分ける
+        let etag = match encoding {
+            Encoding::Identity => file.etag.to_string(),
+            Encoding::Gzip(_) => variant_etag(file.etag, "gzip"),
+            Encoding::Brotli(_) => variant_etag(file.etag, "br"),
+        };
+
+        self.res.header_add("ETag", etag.as_str());
+        if has_variants {
+            self.res.header_add("Vary", "Accept-Encoding");
+        }
+        let not_modified = match self.req.header_get("If-None-Match").await {
+            Some(v) => etag_matches_weak(v, &etag),
+            None => false,
+        };
+        if not_modified {
+            return self
+                .set_status_code(HttpStatusCode::NotModified)
+                .headers_only();
+        }
+
+        self.res.header_add("Content-Type", file.mime_type);
+        self.res.header_add("Accept-Ranges", "bytes");
+        let full_size = file.data.len() as u64;
+        match encoding {
+            Encoding::Identity => {},
+            Encoding::Gzip(data) => {
+                self.res.header_add("Content-Encoding", "gzip");
+                return self.binary_body(data);
+            },
+            Encoding::Brotli(data) => {
+                self.res.header_add("Content-Encoding", "br");
+                return self.binary_body(data);
+            },
+        }
+        match select_byte_range(header_range.as_deref(), 0..full_size, full_size) {
+            Some(range) if range.end - range.start < full_size => {
+                self.res.header_add(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, full_size),
+                );
+                self.set_status_code(HttpStatusCode::PartialContent)
+                    .binary_body(&file.data[range.start as usize..range.end as usize])
+            },
+            Some(_) => self.binary_body(file.data),
+            None => self
+                .set_status_code(HttpStatusCode::RangeNotSatisfiable)
+                .add_header("Content-Range", format!("bytes */{}", full_size))
+                .no_body(),
+        }
+    }
+}
+
+/// `"abc"` -> `"abc-gzip"`
+#[inline]
+fn variant_etag(etag: &str, suffix: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(inner) => format!("{}-{}\"", inner, suffix),
+        None => format!("{}-{}", etag, suffix),
+    }
+}
+
+/// build.rs で生成したファイル一覧を取り込む
+/// `OUT_DIR` からの相対パスを指定します
+#[macro_export]
+macro_rules! include_assets {
+    ($file:expr) => {
+        include!(concat!(env!("OUT_DIR"), "/", $file));
+    };
+}
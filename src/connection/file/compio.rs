@@ -0,0 +1,77 @@
+use std::{io::Cursor, path::Path};
+
+use compio::{
+    fs::File,
+    io::{AsyncReadAt, AsyncReadExt, compat::AsyncStream, util::Take},
+};
+
+use crate::connection::file::{AsyncFile, DirEntryInfo, FileKind, VfsFile, VfsMetadata, metadata_etag};
+
+impl VfsFile for File {
+    type RangeReader = AsyncStream<Take<Cursor<File>>>;
+
+    #[inline]
+    async fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata().await?.len())
+    }
+
+    async fn etag(&self) -> std::io::Result<Option<String>> {
+        let meta = self.metadata().await?;
+        Ok(metadata_etag(meta.len(), meta.modified()))
+    }
+
+    async fn read_head(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // compio は所有バッファを要求するので一度コピーする
+        let owned = Vec::with_capacity(buf.len());
+        let compio::BufResult(res, owned) = self.read_at(owned, 0).await;
+        let n = res?;
+        buf[..n].copy_from_slice(&owned[..n]);
+        Ok(n)
+    }
+
+    async fn range_reader(self, start: u64, len: u64) -> std::io::Result<Self::RangeReader> {
+        let mut cursor = Cursor::new(self);
+        cursor.set_position(start);
+        Ok(AsyncStream::new(cursor.take(len)))
+    }
+}
+
+impl AsyncFile for File {
+    #[inline]
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        File::open(path).await
+    }
+
+    async fn metadata(path: &Path) -> std::io::Result<VfsMetadata> {
+        let meta = compio::fs::metadata(path).await?;
+        let kind = if meta.is_file() {
+            FileKind::File
+        } else if meta.is_dir() {
+            FileKind::Dir
+        } else {
+            FileKind::Other
+        };
+        Ok(VfsMetadata { kind, len: meta.len() })
+    }
+
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<DirEntryInfo>> {
+        // compio には read_dir が無いのでブロッキングスレッドで std を使う
+        let path = path.to_path_buf();
+        let res = compio::runtime::spawn_blocking(move || {
+            let mut entries = Vec::new();
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                entries.push(DirEntryInfo {
+                    path: entry.path(),
+                    kind: FileKind::from(entry.file_type()?),
+                });
+            }
+            Ok(entries)
+        })
+        .await;
+        match res {
+            Ok(entries) => entries,
+            Err(_) => Err(std::io::Error::other("read_dir task panicked")),
+        }
+    }
+}
@@ -2,92 +2,148 @@ use crate::connection::{Connection, NoneBody};
 use crate::error::ErrorPare;
 use crate::error::RouterError;
 use crate::http::HttpStatusCode;
-use crate::http::request::parse_range_header_value;
-use crate::http::request::{RangeParse, RangeSpec};
+use crate::http::request::{etag_matches_strong, etag_matches_weak, select_byte_range};
 use crate::utils::url_decode_fast;
 use crate::{connection::ResponseReadyToSend, error::ConnectionResult};
 use futures_io::{AsyncRead, AsyncWrite};
 use mime_guess::mime;
-use std::fs::FileType;
 use std::marker::PhantomData;
 use std::ops::Range;
-use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncSeekExt;
-use tokio_util::compat::TokioAsyncReadCompatExt;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "compio-server")]
+mod compio;
+#[cfg(feature = "tokio-server")]
+mod tokio;
+pub mod vfs;
+
+pub use vfs::{OsFs, Vfs, VfsFile, VfsMetadata};
+
+/// `OsFs` が標準で使うファイル実装
+/// `tokio-server` が有効なら tokio、なければ compio
+///
+/// `tokio-server` と `compio-server` を両方有効にすると tokio のファイルになります
+/// そのまま compio のサーバーで使うと tokio のリアクタがなく panic するので、
+/// compio 側では `file_io::<compio::fs::File>()` で切り替えてください
+#[cfg(feature = "tokio-server")]
+pub type DefaultFile = ::tokio::fs::File;
+#[cfg(all(feature = "compio-server", not(feature = "tokio-server")))]
+pub type DefaultFile = ::compio::fs::File;
+
+/// ランタイムごとの OS ファイル操作の抽象
+/// tokio と compio(io_uring) の File に実装されています
+pub trait AsyncFile: VfsFile {
+    fn open(path: &Path) -> impl Future<Output = std::io::Result<Self>>;
+
+    fn metadata(path: &Path) -> impl Future<Output = std::io::Result<VfsMetadata>>;
+
+    fn read_dir(path: &Path) -> impl Future<Output = std::io::Result<Vec<DirEntryInfo>>>;
+}
+
+/// ファイルの種類
+/// メモリやアーカイブ上のものも表すため `std::fs::FileType` の代わりに使います
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Other,
+}
+
+impl FileKind {
+    /// `std::fs::FileType::is_file` と同じ
+    #[inline]
+    pub fn is_file(self) -> bool {
+        self == FileKind::File
+    }
+
+    /// `std::fs::FileType::is_dir` と同じ
+    #[inline]
+    pub fn is_dir(self) -> bool {
+        self == FileKind::Dir
+    }
+}
+
+/// サイズと更新時刻から ETag を作る (nginx と同じ形式)
+/// 更新時刻が取れない場合は None
+pub(crate) fn metadata_etag(len: u64, modified: std::io::Result<std::time::SystemTime>) -> Option<String> {
+    let mtime = modified
+        .ok()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    Some(format!("\"{:x}-{:x}\"", mtime, len))
+}
+
+impl From<std::fs::FileType> for FileKind {
+    fn from(file_type: std::fs::FileType) -> Self {
+        if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_dir() {
+            FileKind::Dir
+        } else {
+            FileKind::Other
+        }
+    }
+}
 
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, NoneBody> {
     /// ファイルをレスポンスボディとして送信する
     /// rangeヘッダを考慮します
     #[inline]
     #[deprecated(note = "十分な検証ができていません。streamingメソッドで代替できます。")]
-    #[cfg(feature = "tokio-server")]
-    pub async fn file_body(
+    pub async fn file_body<V: Vfs>(
         mut self,
-        file_content: FileContentBuilder<FileContentBuilderReady>,
+        file_content: FileContentBuilder<FileContentBuilderReady, V>,
     ) -> ConnectionResult<Connection<C, R, W, ResponseReadyToSend>> {
-        let mut file_content = match file_content.build().await {
+        let file_content = match file_content.build().await {
             Ok(fc) => fc,
             Err(_) => {
                 return Ok(self.set_status_code(HttpStatusCode::NotFound).no_body());
             },
         };
-        let header_range = self.req.header_get("Range").await;
+        if let Some(etag) = &file_content.etag {
+            let not_modified = match self.req.header_get("If-None-Match").await {
+                Some(v) => etag_matches_weak(v, etag),
+                None => false,
+            };
+            if not_modified {
+                let etag = etag.clone();
+                return Ok(self
+                    .set_status_code(HttpStatusCode::NotModified)
+                    .add_header("ETag", etag)
+                    .headers_only());
+            }
+            self.res.header_add("ETag", etag.clone());
+        }
         let range = if file_content.force_range {
             file_content.default_range
         } else {
-            match &header_range {
-                Some(r) => {
-                    match parse_range_header_value(r) {
-                        RangeParse::Valid(RangeSpec::FromToInclusive { start, end }) => {
-                            let range = file_content.default_range;
-                            let start = start.max(range.start);
-                            // HTTPのRangeヘッダはendがinclusiveなので+1する
-                            let end = (end.saturating_add(1))
-                                .min(range.end)
-                                .min(start.saturating_add(file_content.max_size));
-                            if start < end {
-                                start..end
-                            } else {
-                                return Ok(self
-                                    .set_status_code(HttpStatusCode::RangeNotSatisfiable)
-                                    .no_body());
-                            }
-                        },
-                        RangeParse::Valid(RangeSpec::From { start }) => {
-                            let range = file_content.default_range;
-                            let start = start.max(range.start);
-                            let end = range.end.min(start + file_content.max_size);
-                            if start < end {
-                                start..end
-                            } else {
-                                return Ok(self
-                                    .set_status_code(HttpStatusCode::RangeNotSatisfiable)
-                                    .no_body());
-                            }
-                        },
-                        RangeParse::Valid(RangeSpec::Suffix { len }) => {
-                            let range = file_content.default_range;
-                            let end = range.end;
-                            let start = end
-                                .saturating_sub(len.min(file_content.max_size))
-                                .max(range.start);
-                            if start < end {
-                                start..end
-                            } else {
-                                return Ok(self
-                                    .set_status_code(HttpStatusCode::RangeNotSatisfiable)
-                                    .no_body());
-                            }
-                        },
-                        RangeParse::Invalid => {
-                            // return Ok(self.set_status_code(HttpStatusCode::RangeNotSatisfiable).no_body())
-                            file_content.default_range
-                        },
-                    }
+            // If-Range が現在の etag と一致しない場合は Range を無視する
+            let range_allowed = match self.req.header_get("If-Range").await {
+                Some(v) => file_content
+                    .etag
+                    .as_deref()
+                    .is_some_and(|etag| etag_matches_strong(v, etag)),
+                None => true,
+            };
+            let header_range = if range_allowed {
+                self.req.header_get("Range").await
+            } else {
+                None
+            };
+            match select_byte_range(
+                header_range,
+                file_content.default_range,
+                file_content.max_size,
+            ) {
+                Some(range) => range,
+                None => {
+                    let full_size = file_content.full_size;
+                    return Ok(self
+                        .set_status_code(HttpStatusCode::RangeNotSatisfiable)
+                        .add_header("Content-Range", format!("bytes */{}", full_size))
+                        .no_body());
                 },
-                None => file_content.default_range,
             }
         };
 
@@ -111,11 +167,7 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
         let end = range.end;
         let size = end - start;
         let is_partial = size < file_content.full_size;
-        let reader = file_content
-            .file
-            .seek(std::io::SeekFrom::Start(start))
-            .await
-            .and_then(|_| Ok(file_content.file.take(size).compat()));
+        let reader = file_content.file.range_reader(start, size).await;
         match reader {
             Ok(r) => {
                 if is_partial {
@@ -139,10 +191,10 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
                     res: self.res,
                     phantom: std::marker::PhantomData,
                 };
-                return Err(ErrorPare {
+                Err(ErrorPare {
                     router_error: RouterError::IoError(e),
                     connection: conn,
-                });
+                })
             },
         }
     }
@@ -155,7 +207,9 @@ pub struct FileContentBuilderReady;
 impl FileContentBuilderState for FileContentBuilderReady {}
 
 /// ファイルレスポンスのビルダ
-pub struct FileContentBuilder<S = FileContentBuilderInit> {
+/// V で読み出し元のファイルシステムを切り替えられます
+pub struct FileContentBuilder<S = FileContentBuilderInit, V: Vfs = OsFs> {
+    vfs: V,
     base: std::path::PathBuf,
     path: std::path::PathBuf,
     content_type: ContentType,
@@ -165,8 +219,8 @@ pub struct FileContentBuilder<S = FileContentBuilderInit> {
     phantom: PhantomData<S>,
 }
 
-pub struct FileContent {
-    pub file: File,
+pub struct FileContent<F: VfsFile = DefaultFile> {
+    pub file: F,
     pub mime_type: String,
     pub full_size: u64,
     /// range ヘッダがない場合に使える範囲
@@ -175,6 +229,7 @@ pub struct FileContent {
     pub disposition: ContentDisposition,
     pub is_partly: bool,
     pub force_range: bool,
+    pub etag: Option<String>,
 }
 
 pub enum ContentType {
@@ -203,11 +258,22 @@ pub enum ContentDisposition {
 impl FileContentBuilder<FileContentBuilderInit> {
     /// ベースパスを指定
     pub fn base<P>(base: P) -> Self
+    where
+        P: AsRef<std::path::Path>,
+    {
+        Self::with_vfs(OsFs::new(), base)
+    }
+}
+
+impl<V: Vfs> FileContentBuilder<FileContentBuilderInit, V> {
+    /// 読み出し元の vfs とその中のベースパスを指定
+    pub fn with_vfs<P>(vfs: V, base: P) -> Self
     where
         P: AsRef<std::path::Path>,
     {
         let disposition = ContentDisposition::Attachment;
         FileContentBuilder {
+            vfs,
             base: base.as_ref().to_path_buf(),
             path: PathBuf::new(),
             content_type: ContentType::Guess,
@@ -219,7 +285,7 @@ impl FileContentBuilder<FileContentBuilderInit> {
     }
 
     /// ベースパスからの相対パスを指定
-    pub fn path<P>(self, path: P) -> FileContentBuilder<FileContentBuilderReady>
+    pub fn path<P>(self, path: P) -> FileContentBuilder<FileContentBuilderReady, V>
     where
         P: AsRef<std::path::Path>,
     {
@@ -230,6 +296,7 @@ impl FileContentBuilder<FileContentBuilderInit> {
             .map(|fname_str| ContentDisposition::AttachmentWithFilename(fname_str.to_string()))
             .unwrap_or(ContentDisposition::Attachment);
         FileContentBuilder {
+            vfs: self.vfs,
             base: self.base,
             path: path.to_path_buf(),
             content_type: self.content_type,
@@ -240,14 +307,32 @@ impl FileContentBuilder<FileContentBuilderInit> {
         }
     }
 
-    pub fn path_url_segs(self, path: &[&str]) -> FileContentBuilder<FileContentBuilderReady> {
+    pub fn path_url_segs(self, path: &[&str]) -> FileContentBuilder<FileContentBuilderReady, V> {
         let joined_path = path.join("/");
         let decoded_path = url_decode_fast(&joined_path);
         self.path(decoded_path.as_ref())
     }
 }
 
-impl FileContentBuilder<FileContentBuilderReady> {
+impl<S, F: AsyncFile> FileContentBuilder<S, OsFs<F>> {
+    /// OS ファイルシステムのファイル実装を切り替える
+    /// 例: compio 上で `file_io::<compio::fs::File>()`
+    /// tokio と compio を両方有効にしたときは、compio 側で必ず呼んでください (`DefaultFile` を参照)
+    pub fn file_io<G: AsyncFile>(self) -> FileContentBuilder<S, OsFs<G>> {
+        FileContentBuilder {
+            vfs: OsFs::new(),
+            base: self.base,
+            path: self.path,
+            content_type: self.content_type,
+            content_range: self.content_range,
+            content_disposition: self.content_disposition,
+            to_safe: self.to_safe,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<V: Vfs> FileContentBuilder<FileContentBuilderReady, V> {
     /// ファイル名を指定
     /// (inlineでなくなります)
     pub fn name<S>(mut self, file_name: Option<S>) -> Self
@@ -295,74 +380,38 @@ impl FileContentBuilder<FileContentBuilderReady> {
         self
     }
 
-    pub(crate) fn safe_path_under(&mut self) -> std::io::Result<PathBuf> {
-        // base を実体パス化（相対のままだと starts_with が壊れる）
-        let base = self.base.canonicalize()?;
-
-        // 絶対パス拒否
-        if self.path.is_absolute() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::PermissionDenied,
-                "absolute path not allowed",
-            ));
-        }
-
-        let joined = base.join(&self.path);
-        let canon = joined.canonicalize()?;
-
-        // base 配下チェック（symlink脱出も防ぐ）
-        if !canon.starts_with(&base) {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::PermissionDenied,
-                "path traversal detected",
-            ));
-        }
-
-        // 正規化済みを保存（base も canonical に揃えると後工程も楽）
-        self.base = base;
-        Ok(canon)
+    pub(crate) fn safe_path_under(&self) -> std::io::Result<PathBuf> {
+        self.vfs.resolve(&self.base, &self.path)
     }
 
     /// ファイルの存在チェックを行う
     /// 無ければ Err としてディレクトリがあるか あればその相対パス一覧を返す
-    pub async fn check_file_exists(mut self) -> Result<Self, Option<Vec<DirEntryInfo>>> {
+    pub async fn check_file_exists(self) -> Result<Self, Option<Vec<DirEntryInfo>>> {
         let path = if self.to_safe {
             self.safe_path_under().map_err(|_| None)?
         } else {
             self.base.join(&self.path)
         };
-        match tokio::fs::metadata(&path).await {
-            Ok(meta) => {
-                if meta.is_file() {
-                    Ok(self)
-                } else if meta.is_dir() {
-                    let mut dir = tokio::fs::read_dir(&path).await.map_err(|_| None)?;
-                    let mut entries = Vec::new();
-                    while let Some(entry) = dir.next_entry().await.map_err(|_| None)? {
-                        let file_type = entry.file_type().await.map_err(|_| None)?;
-                        entries.push(DirEntryInfo {
-                            path: entry.path(),
-                            kind: file_type,
-                        });
-                    }
-                    Err(Some(entries))
-                } else {
-                    Err(None)
-                }
+        match self.vfs.metadata(&path).await.map(|meta| meta.kind) {
+            Ok(FileKind::File) => Ok(self),
+            Ok(FileKind::Dir) => {
+                let entries = self.vfs.read_dir(&path).await.map_err(|_| None)?;
+                Err(Some(entries))
             },
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(None),
+            Ok(FileKind::Other) => Err(None),
             Err(_) => Err(None),
         }
     }
 
-    pub async fn build(mut self) -> std::io::Result<FileContent> {
+    pub async fn build(mut self) -> std::io::Result<FileContent<V::File>> {
         self.path = if self.to_safe {
             self.safe_path_under()?
         } else {
             self.base.join(&self.path)
         };
-        let mut file = File::open(&self.path).await?;
-        let metadata = file.metadata().await?;
+        let mut file = self.vfs.open(&self.path).await?;
+        let full_size = file.size().await?;
+        let etag = file.etag().await?;
         let mime_type = match &self.content_type {
             ContentType::Guess => {
                 let mime = mime_guess::from_path(&self.path).first_or_octet_stream();
@@ -375,11 +424,9 @@ impl FileContentBuilder<FileContentBuilderReady> {
                     | mime::HTML
                     | mime::CSS => {
                         let mut buf = vec![0; 4096];
-                        let n = file.read(&mut buf).await?;
+                        let n = file.read_head(&mut buf).await?;
                         buf.truncate(n);
 
-                        file.seek(std::io::SeekFrom::Start(0)).await?;
-
                         let mut det = chardetng::EncodingDetector::new();
                         det.feed(&buf, true);
                         let encoding = det.guess(None, true);
@@ -411,7 +458,6 @@ impl FileContentBuilder<FileContentBuilderReady> {
             },
             ContentType::Custom(s) => s.clone(),
         };
-        let full_size = metadata.len();
         let mut is_partly = false;
         let mut default_range = 0..full_size;
         let mut max_size = full_size;
@@ -445,11 +491,12 @@ impl FileContentBuilder<FileContentBuilderReady> {
             max_size,
             force_range,
             is_partly,
+            etag,
         })
     }
 }
 
 pub struct DirEntryInfo {
     pub path: PathBuf,
-    pub kind: FileType,
-}
\ No newline at end of file
+    pub kind: FileKind,
+}
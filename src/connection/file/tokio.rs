@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, Take},
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+use crate::connection::file::{AsyncFile, DirEntryInfo, FileKind, VfsFile, VfsMetadata, metadata_etag};
+
+impl VfsFile for File {
+    type RangeReader = Compat<Take<File>>;
+
+    #[inline]
+    async fn size(&self) -> std::io::Result<u64> {
+        Ok(self.metadata().await?.len())
+    }
+
+    async fn etag(&self) -> std::io::Result<Option<String>> {
+        let meta = self.metadata().await?;
+        Ok(metadata_etag(meta.len(), meta.modified()))
+    }
+
+    async fn read_head(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.seek(std::io::SeekFrom::Start(0)).await?;
+        let n = self.read(buf).await?;
+        self.seek(std::io::SeekFrom::Start(0)).await?;
+        Ok(n)
+    }
+
+    async fn range_reader(mut self, start: u64, len: u64) -> std::io::Result<Self::RangeReader> {
+        self.seek(std::io::SeekFrom::Start(start)).await?;
+        Ok(self.take(len).compat())
+    }
+}
+
+impl AsyncFile for File {
+    #[inline]
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        File::open(path).await
+    }
+
+    async fn metadata(path: &Path) -> std::io::Result<VfsMetadata> {
+        let meta = tokio::fs::metadata(path).await?;
+        Ok(VfsMetadata {
+            kind: FileKind::from(meta.file_type()),
+            len: meta.len(),
+        })
+    }
+
+    async fn read_dir(path: &Path) -> std::io::Result<Vec<DirEntryInfo>> {
+        let mut dir = tokio::fs::read_dir(path).await?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            entries.push(DirEntryInfo {
+                path: entry.path(),
+                kind: FileKind::from(file_type),
+            });
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,501 @@
+use std::{path::Path, sync::Arc};
+
+use crate::connection::file::{
+    DirEntryInfo,
+    vfs::{MemoryFile, MemoryFs, SharedBytes, Vfs, VfsMetadata},
+};
+
+/// tar / zip アーカイブを読み取り専用のファイルシステムとして扱う
+/// 無圧縮のエントリはアーカイブのバッファを共有し、deflate のエントリは読み込み時に展開します
+#[derive(Clone)]
+pub struct ArchiveFs {
+    inner: MemoryFs,
+}
+
+impl ArchiveFs {
+    /// tar (ustar / GNU long name / pax path) を読み込む
+    pub fn from_tar<D>(data: D) -> std::io::Result<Self>
+    where
+        D: Into<Arc<[u8]>>,
+    {
+        let data: Arc<[u8]> = data.into();
+        let mut fs = MemoryFs::new();
+        let mut offset = 0usize;
+        let mut next_name: Option<String> = None;
+
+        while offset + 512 <= data.len() {
+            let header = &data[offset..offset + 512];
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let size = parse_octal(&header[124..136]).ok_or_else(|| invalid("invalid tar entry size"))? as usize;
+            let body_start = offset + 512;
+            let body_end = body_start
+                .checked_add(size)
+                .filter(|&end| end <= data.len())
+                .ok_or_else(|| invalid("truncated tar entry"))?;
+            let body = &data[body_start..body_end];
+
+            match header[156] {
+                // 通常ファイル
+                b'0' | 0 | b'7' => {
+                    let name = match next_name.take() {
+                        Some(name) => name,
+                        None => tar_header_name(header)?,
+                    };
+                    let bytes = SharedBytes::slice(data.clone(), body_start..body_end)
+                        .ok_or_else(|| invalid("truncated tar entry"))?;
+                    fs.insert(name, bytes);
+                },
+                // GNU long name
+                b'L' => {
+                    next_name = Some(cstr(body)?.to_string());
+                },
+                // pax 拡張ヘッダ (path のみ対応)
+                b'x' => {
+                    next_name = pax_path(body)?;
+                },
+                // ディレクトリ, リンク, その他は無視
+                _ => {
+                    next_name = None;
+                },
+            }
+
+            offset = body_start + size.div_ceil(512) * 512;
+        }
+        Ok(ArchiveFs { inner: fs })
+    }
+
+    /// zip (無圧縮 / deflate) を読み込む
+    /// zip64 と暗号化には対応していません
+    pub fn from_zip<D>(data: D) -> std::io::Result<Self>
+    where
+        D: Into<Arc<[u8]>>,
+    {
+        let data: Arc<[u8]> = data.into();
+        let eocd = find_eocd(&data).ok_or_else(|| invalid("zip end of central directory not found"))?;
+        let entries = read_u16(&data, eocd + 10)? as usize;
+        let mut cursor = read_u32(&data, eocd + 16)? as usize;
+        let mut fs = MemoryFs::new();
+
+        for _ in 0..entries {
+            if read_u32(&data, cursor)? != 0x0201_4b50 {
+                return Err(invalid("invalid zip central directory"));
+            }
+            let flags = read_u16(&data, cursor + 8)?;
+            let method = read_u16(&data, cursor + 10)?;
+            let comp_size = read_u32(&data, cursor + 20)?;
+            let uncomp_size = read_u32(&data, cursor + 24)?;
+            let name_len = read_u16(&data, cursor + 28)? as usize;
+            let extra_len = read_u16(&data, cursor + 30)? as usize;
+            let comment_len = read_u16(&data, cursor + 32)? as usize;
+            let local_offset = read_u32(&data, cursor + 42)? as usize;
+            let name = data
+                .get(cursor + 46..cursor + 46 + name_len)
+                .ok_or_else(|| invalid("truncated zip central directory"))?;
+            let name = std::str::from_utf8(name).map_err(|_| invalid("non UTF-8 zip entry name"))?;
+            cursor += 46 + name_len + extra_len + comment_len;
+
+            if name.ends_with('/') {
+                continue;
+            }
+            if comp_size == u32::MAX || uncomp_size == u32::MAX || local_offset == u32::MAX as usize {
+                return Err(invalid("zip64 is not supported"));
+            }
+            if flags & 0x1 != 0 {
+                return Err(invalid("encrypted zip entries are not supported"));
+            }
+
+            if read_u32(&data, local_offset)? != 0x0403_4b50 {
+                return Err(invalid("invalid zip local header"));
+            }
+            let local_name_len = read_u16(&data, local_offset + 26)? as usize;
+            let local_extra_len = read_u16(&data, local_offset + 28)? as usize;
+            let body_start = local_offset + 30 + local_name_len + local_extra_len;
+            let body_end = body_start + comp_size as usize;
+
+            let bytes = match method {
+                0 => SharedBytes::slice(data.clone(), body_start..body_end),
+                8 => {
+                    let raw = data
+                        .get(body_start..body_end)
+                        .ok_or_else(|| invalid("truncated zip entry"))?;
+                    let inflated = miniz_oxide::inflate::decompress_to_vec_with_limit(raw, uncomp_size as usize)
+                        .map_err(|_| invalid("broken deflate stream in zip entry"))?;
+                    Some(SharedBytes::new(inflated))
+                },
+                _ => return Err(invalid("unsupported zip compression method")),
+            }
+            .ok_or_else(|| invalid("truncated zip entry"))?;
+            fs.insert(name, bytes);
+        }
+        Ok(ArchiveFs { inner: fs })
+    }
+
+    /// 展開済みのファイルシステムを参照する
+    pub fn as_memory_fs(&self) -> &MemoryFs {
+        &self.inner
+    }
+}
+
+impl Vfs for ArchiveFs {
+    type File = MemoryFile;
+
+    #[inline]
+    async fn open(&self, path: &Path) -> std::io::Result<MemoryFile> {
+        self.inner.open(path).await
+    }
+
+    #[inline]
+    async fn metadata(&self, path: &Path) -> std::io::Result<VfsMetadata> {
+        self.inner.metadata(path).await
+    }
+
+    #[inline]
+    async fn read_dir(&self, path: &Path) -> std::io::Result<Vec<DirEntryInfo>> {
+        self.inner.read_dir(path).await
+    }
+}
+
+fn invalid(msg: &'static str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// NUL 終端の文字列を取り出す
+fn cstr(bytes: &[u8]) -> std::io::Result<&str> {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).map_err(|_| invalid("non UTF-8 tar entry name"))
+}
+
+fn parse_octal(bytes: &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    let mut seen = false;
+    for &b in bytes {
+        match b {
+            b'0'..=b'7' => {
+                n = n.checked_mul(8)?.checked_add((b - b'0') as u64)?;
+                seen = true;
+            },
+            b' ' | 0 if seen => break,
+            b' ' | 0 => {},
+            _ => return None,
+        }
+    }
+    Some(n)
+}
+
+fn tar_header_name(header: &[u8]) -> std::io::Result<String> {
+    let name = cstr(&header[0..100])?;
+    // ustar は prefix を持つ
+    if &header[257..262] == b"ustar" {
+        let prefix = cstr(&header[345..500])?;
+        if !prefix.is_empty() {
+            return Ok(format!("{}/{}", prefix, name));
+        }
+    }
+    Ok(name.to_string())
+}
+
+/// pax レコード (`"<len> <key>=<value>\n"`) から path を取り出す
+fn pax_path(body: &[u8]) -> std::io::Result<Option<String>> {
+    let mut rest = body;
+    while !rest.is_empty() {
+        let sp = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let len: usize = std::str::from_utf8(&rest[..sp])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&len| len > sp && len <= rest.len())
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let record = &rest[sp + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(path) = record.strip_prefix(b"path=") {
+            let path = std::str::from_utf8(path).map_err(|_| invalid("non UTF-8 tar entry name"))?;
+            return Ok(Some(path.to_string()));
+        }
+        rest = &rest[len..];
+    }
+    Ok(None)
+}
+
+fn find_eocd(data: &[u8]) -> Option<usize> {
+    // EOCD は 22 byte + コメント (最大 65535 byte)
+    if data.len() < 22 {
+        return None;
+    }
+    let lowest = data.len().saturating_sub(22 + u16::MAX as usize);
+    (lowest..=data.len() - 22)
+        .rev()
+        .find(|&i| data[i..i + 4] == [0x50, 0x4b, 0x05, 0x06])
+}
+
+fn read_u16(data: &[u8], at: usize) -> std::io::Result<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated zip"))
+}
+
+fn read_u32(data: &[u8], at: usize) -> std::io::Result<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated zip"))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::connection::file::vfs::VfsFile;
+
+    fn read(fs: &ArchiveFs, path: &str) -> Option<Vec<u8>> {
+        let mut file = fs.open(Path::new(path)).now_or_never().unwrap().ok()?;
+        let size = file.size().now_or_never().unwrap().unwrap() as usize;
+        let mut buf = vec![0; size];
+        let n = file.read_head(&mut buf).now_or_never().unwrap().unwrap();
+        assert_eq!(n, size);
+        Some(buf)
+    }
+
+    /// tar のエントリ (ヘッダ + 512 byte 境界まで埋めたボディ)
+    fn tar_entry(name: &str, kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", body.len()).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        let mut out = header.to_vec();
+        out.extend_from_slice(body);
+        out.resize(out.len().div_ceil(512) * 512, 0);
+        out
+    }
+
+    fn tar(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = entries.concat();
+        out.extend_from_slice(&[0; 1024]);
+        out
+    }
+
+    /// (名前, 圧縮方式, 展開後の中身) から zip を作る
+    fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, method, data) in entries {
+            let stored = match method {
+                8 => miniz_oxide::deflate::compress_to_vec(data, 6),
+                _ => data.to_vec(),
+            };
+            let offset = out.len() as u32;
+            out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&[0; 8]);
+            out.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&stored);
+
+            central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            central.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            central.extend_from_slice(&method.to_le_bytes());
+            central.extend_from_slice(&[0; 8]);
+            central.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&central_offset.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    fn sample_tar() -> Vec<u8> {
+        let long = format!("{}/deep.txt", "d".repeat(120));
+        tar(&[
+            tar_entry("index.html", b'0', b"<h1>hi</h1>"),
+            tar_entry("assets/", b'5', b""),
+            tar_entry("assets/app.js", b'0', &[b'x'; 700]),
+            tar_entry("././@LongLink", b'L', format!("{}\0", long).as_bytes()),
+            tar_entry("truncated-name", b'0', b"long"),
+            tar_entry("PaxHeader", b'x', b"20 path=pax/name.txt\n"),
+            tar_entry("ignored", b'0', b"pax"),
+        ])
+    }
+
+    fn sample_zip() -> Vec<u8> {
+        zip(&[
+            ("index.html", 0, b"<h1>hi</h1>"),
+            ("assets/", 0, b""),
+            (
+                "assets/app.js",
+                8,
+                "console.log(1);\n".repeat(50).as_bytes(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn reads_tar_entries() {
+        let fs = ArchiveFs::from_tar(sample_tar()).unwrap();
+        assert_eq!(read(&fs, "/index.html").unwrap(), b"<h1>hi</h1>");
+        assert_eq!(read(&fs, "/assets/app.js").unwrap(), vec![b'x'; 700]);
+        let long = format!("/{}/deep.txt", "d".repeat(120));
+        assert_eq!(read(&fs, &long).unwrap(), b"long");
+        assert_eq!(read(&fs, "/pax/name.txt").unwrap(), b"pax");
+        assert_eq!(read(&fs, "/truncated-name"), None);
+        assert_eq!(fs.as_memory_fs().len(), 4);
+    }
+
+    #[test]
+    fn tar_without_end_marker_is_accepted() {
+        let data = tar_entry("a.txt", b'0', b"a");
+        let fs = ArchiveFs::from_tar(data).unwrap();
+        assert_eq!(read(&fs, "/a.txt").unwrap(), b"a");
+        assert!(
+            ArchiveFs::from_tar(Vec::new())
+                .unwrap()
+                .as_memory_fs()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_tar() {
+        // ボディがヘッダのサイズより短い
+        let mut data = tar_entry("a.txt", b'0', &[b'a'; 600]);
+        data.truncate(700);
+        assert!(ArchiveFs::from_tar(data).is_err());
+
+        // サイズが8進数でない
+        let mut data = tar(&[tar_entry("a.txt", b'0', b"a")]);
+        data[124..128].copy_from_slice(b"12x4");
+        assert!(ArchiveFs::from_tar(data).is_err());
+
+        // サイズが桁あふれする
+        let mut data = tar(&[tar_entry("a.txt", b'0', b"a")]);
+        data[124..136].copy_from_slice(b"777777777777");
+        assert!(ArchiveFs::from_tar(data).is_err());
+
+        // pax レコードの長さが壊れている
+        let data = tar(&[tar_entry("PaxHeader", b'x', b"99 path=a\n"), tar_entry("a", b'0', b"a")]);
+        assert!(ArchiveFs::from_tar(data).is_err());
+        let data = tar(&[tar_entry("PaxHeader", b'x', b"path=a\n"), tar_entry("a", b'0', b"a")]);
+        assert!(ArchiveFs::from_tar(data).is_err());
+    }
+
+    #[test]
+    fn reads_zip_entries() {
+        let fs = ArchiveFs::from_zip(sample_zip()).unwrap();
+        assert_eq!(read(&fs, "/index.html").unwrap(), b"<h1>hi</h1>");
+        assert_eq!(
+            read(&fs, "/assets/app.js").unwrap(),
+            "console.log(1);\n".repeat(50).as_bytes()
+        );
+        assert_eq!(fs.as_memory_fs().len(), 2);
+    }
+
+    #[test]
+    fn zip_with_comment_is_found() {
+        let mut data = sample_zip();
+        let len = data.len();
+        data[len - 2..].copy_from_slice(&5u16.to_le_bytes());
+        data.extend_from_slice(b"hello");
+        let fs = ArchiveFs::from_zip(data).unwrap();
+        assert_eq!(read(&fs, "/index.html").unwrap(), b"<h1>hi</h1>");
+    }
+
+    #[test]
+    fn rejects_corrupt_zip() {
+        assert!(ArchiveFs::from_zip(Vec::new()).is_err());
+        assert!(ArchiveFs::from_zip(b"PK\x05\x06".to_vec()).is_err());
+        assert!(ArchiveFs::from_zip(sample_tar()).is_err());
+
+        // セントラルディレクトリの位置がデータの外
+        let mut data = sample_zip();
+        let len = data.len();
+        data[len - 6..len - 2].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(ArchiveFs::from_zip(data).is_err());
+
+        // エントリ数が実際より多い
+        let mut data = sample_zip();
+        let len = data.len();
+        data[len - 12..len - 10].copy_from_slice(&100u16.to_le_bytes());
+        assert!(ArchiveFs::from_zip(data).is_err());
+
+        // 圧縮後のサイズがデータの外まで続く
+        for method in [0, 8] {
+            let mut data = zip(&[("a.txt", method, b"aaaa")]);
+            let cd = data.len() - 22 - 51;
+            data[cd + 20..cd + 24].copy_from_slice(&0x00ff_ffffu32.to_le_bytes());
+            assert!(ArchiveFs::from_zip(data).is_err());
+        }
+
+        // 壊れた deflate
+        let mut data = zip(&[(
+            "a.txt",
+            8,
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+        )]);
+        data[35..41].fill(0xff);
+        assert!(ArchiveFs::from_zip(data).is_err());
+
+        // 展開後のサイズが申告より大きい
+        let mut data = zip(&[("a.txt", 8, &[b'a'; 1000])]);
+        let cd = data.len() - 22 - 51;
+        data[cd + 24..cd + 28].copy_from_slice(&10u32.to_le_bytes());
+        assert!(ArchiveFs::from_zip(data).is_err());
+
+        // 未対応の圧縮方式と暗号化
+        assert!(ArchiveFs::from_zip(zip(&[("a.txt", 12, b"a")])).is_err());
+        let mut data = zip(&[("a.txt", 0, b"a")]);
+        let cd = data.len() - 22 - 51;
+        data[cd + 8] = 1;
+        assert!(ArchiveFs::from_zip(data).is_err());
+    }
+
+    #[test]
+    fn truncated_archives_never_panic() {
+        let tar = sample_tar();
+        for len in 0..tar.len() {
+            let _ = ArchiveFs::from_tar(tar[..len].to_vec());
+        }
+        let zip = sample_zip();
+        for len in 0..zip.len() {
+            let _ = ArchiveFs::from_zip(zip[..len].to_vec());
+        }
+    }
+
+    #[test]
+    fn corrupted_bytes_never_panic() {
+        let zip = sample_zip();
+        for i in 0..zip.len() {
+            for b in [0x00, 0x7f, 0xff] {
+                let mut data = zip.clone();
+                data[i] = b;
+                let _ = ArchiveFs::from_zip(data);
+            }
+        }
+        let tar = sample_tar();
+        // ヘッダだけを壊す (ボディはどんな値でもよい)
+        for block in (0..tar.len()).step_by(512).take(8) {
+            for i in block..block + 512 {
+                let mut data = tar.clone();
+                data[i] = 0xff;
+                let _ = ArchiveFs::from_tar(data);
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use futures_util::{
+    AsyncReadExt,
+    io::{Cursor, Take},
+};
+
+use crate::connection::file::{
+    DirEntryInfo, FileKind,
+    vfs::{Vfs, VfsFile, VfsMetadata},
+};
+
+/// Arc で共有されるバイト列の一部
+/// clone してもデータはコピーされません
+#[derive(Clone)]
+pub struct SharedBytes {
+    data: Arc<[u8]>,
+    range: Range<usize>,
+}
+
+impl SharedBytes {
+    pub fn new<D>(data: D) -> Self
+    where
+        D: Into<Arc<[u8]>>,
+    {
+        let data = data.into();
+        let range = 0..data.len();
+        SharedBytes { data, range }
+    }
+
+    /// data の一部を指すバイト列を作る
+    /// range が data の外を指す場合は None
+    pub fn slice(data: Arc<[u8]>, range: Range<usize>) -> Option<Self> {
+        if range.start > range.end || range.end > data.len() {
+            return None;
+        }
+        Some(SharedBytes { data, range })
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+}
+
+impl AsRef<[u8]> for SharedBytes {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &self.data[self.range.clone()]
+    }
+}
+
+impl From<Vec<u8>> for SharedBytes {
+    fn from(v: Vec<u8>) -> Self {
+        SharedBytes::new(v)
+    }
+}
+
+impl From<&'static [u8]> for SharedBytes {
+    fn from(v: &'static [u8]) -> Self {
+        SharedBytes::new(v)
+    }
+}
+
+impl From<&'static str> for SharedBytes {
+    fn from(v: &'static str) -> Self {
+        SharedBytes::new(v.as_bytes())
+    }
+}
+
+impl From<String> for SharedBytes {
+    fn from(v: String) -> Self {
+        SharedBytes::new(v.into_bytes())
+    }
+}
+
+impl From<Arc<[u8]>> for SharedBytes {
+    fn from(v: Arc<[u8]>) -> Self {
+        SharedBytes::new(v)
+    }
+}
+
+/// メモリ上のファイルシステム
+/// パスは `/` 区切りで管理され、ディレクトリはファイルのパスから暗黙に決まります
+/// clone は安価です
+#[derive(Clone, Default)]
+pub struct MemoryFs {
+    files: Arc<BTreeMap<String, SharedBytes>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        MemoryFs::default()
+    }
+
+    /// ファイルを追加して自身を返す
+    pub fn file<P, D>(mut self, path: P, data: D) -> Self
+    where
+        P: AsRef<Path>,
+        D: Into<SharedBytes>,
+    {
+        self.insert(path, data);
+        self
+    }
+
+    /// ファイルを追加する
+    /// パスが正規化できない (ルートより上を指す, 非UTF-8) 場合は false
+    pub fn insert<P, D>(&mut self, path: P, data: D) -> bool
+    where
+        P: AsRef<Path>,
+        D: Into<SharedBytes>,
+    {
+        match key_of(path.as_ref()) {
+            Some(key) if !key.is_empty() => {
+                Arc::make_mut(&mut self.files).insert(key, data.into());
+                true
+            },
+            _ => false,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// 登録されているファイルのパス一覧
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|k| k.as_str())
+    }
+
+    fn is_dir_key(&self, key: &str) -> bool {
+        if key.is_empty() {
+            return true;
+        }
+        let prefix = format!("{}/", key);
+        self.files
+            .range(prefix.clone()..)
+            .next()
+            .is_some_and(|(k, _)| k.starts_with(&prefix))
+    }
+}
+
+/// パスを MemoryFs のキーに正規化する
+/// ルート/`.` は無視し `..` は一つ上に戻る
+fn key_of(path: &Path) -> Option<String> {
+    let mut segs: Vec<&str> = Vec::new();
+    for comp in path.components() {
+        match comp {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {},
+            Component::ParentDir => {
+                segs.pop()?;
+            },
+            Component::Normal(seg) => segs.push(seg.to_str()?),
+        }
+    }
+    Some(segs.join("/"))
+}
+
+fn not_found() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "no such file in memory fs")
+}
+
+impl Vfs for MemoryFs {
+    type File = MemoryFile;
+
+    async fn open(&self, path: &Path) -> std::io::Result<MemoryFile> {
+        let key = key_of(path).ok_or_else(not_found)?;
+        match self.files.get(&key) {
+            Some(bytes) => Ok(MemoryFile { bytes: bytes.clone() }),
+            None => Err(not_found()),
+        }
+    }
+
+    async fn metadata(&self, path: &Path) -> std::io::Result<VfsMetadata> {
+        let key = key_of(path).ok_or_else(not_found)?;
+        if let Some(bytes) = self.files.get(&key) {
+            return Ok(VfsMetadata {
+                kind: FileKind::File,
+                len: bytes.len() as u64,
+            });
+        }
+        if self.is_dir_key(&key) {
+            return Ok(VfsMetadata { kind: FileKind::Dir, len: 0 });
+        }
+        Err(not_found())
+    }
+
+    async fn read_dir(&self, path: &Path) -> std::io::Result<Vec<DirEntryInfo>> {
+        let key = key_of(path).ok_or_else(not_found)?;
+        if !self.is_dir_key(&key) {
+            return Err(not_found());
+        }
+        let prefix = if key.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", key)
+        };
+        let mut entries: Vec<DirEntryInfo> = Vec::new();
+        let mut last: Option<&str> = None;
+        for (k, _) in self.files.range(prefix.clone()..) {
+            let Some(rest) = k.strip_prefix(&prefix) else {
+                break;
+            };
+            let (child, kind) = match rest.split_once('/') {
+                Some((dir, _)) => (dir, FileKind::Dir),
+                None => (rest, FileKind::File),
+            };
+            // BTreeMap の並びなので同じ子ディレクトリは連続する
+            if last == Some(child) {
+                continue;
+            }
+            last = Some(child);
+            entries.push(DirEntryInfo { path: path.join(child), kind });
+        }
+        Ok(entries)
+    }
+}
+
+/// MemoryFs で開いたファイル
+pub struct MemoryFile {
+    bytes: SharedBytes,
+}
+
+impl VfsFile for MemoryFile {
+    type RangeReader = Take<Cursor<SharedBytes>>;
+
+    #[inline]
+    async fn size(&self) -> std::io::Result<u64> {
+        Ok(self.bytes.len() as u64)
+    }
+
+    async fn read_head(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.bytes.as_ref();
+        let n = buf.len().min(data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    async fn range_reader(self, start: u64, len: u64) -> std::io::Result<Self::RangeReader> {
+        let mut cursor = Cursor::new(self.bytes);
+        cursor.set_position(start);
+        Ok(cursor.take(len))
+    }
+}
@@ -0,0 +1,155 @@
+#[cfg(feature = "archive")]
+mod archive;
+mod memory;
+
+#[cfg(feature = "archive")]
+pub use archive::ArchiveFs;
+pub use memory::{MemoryFile, MemoryFs, SharedBytes};
+
+use std::{
+    marker::PhantomData,
+    path::{Component, Path, PathBuf},
+};
+
+use futures_io::AsyncRead;
+
+use crate::connection::file::{AsyncFile, DefaultFile, DirEntryInfo, FileKind};
+
+/// 開いたファイルへの操作
+pub trait VfsFile: Sized + 'static {
+    /// 範囲読み出し用のリーダ
+    type RangeReader: AsyncRead + Unpin + 'static;
+
+    /// ファイルサイズ
+    fn size(&self) -> impl Future<Output = std::io::Result<u64>>;
+
+    /// ファイル先頭から buf に読む (文字コード推定用)
+    fn read_head(&mut self, buf: &mut [u8]) -> impl Future<Output = std::io::Result<usize>>;
+
+    /// start から len バイトを読むリーダに変換する
+    fn range_reader(self, start: u64, len: u64) -> impl Future<Output = std::io::Result<Self::RangeReader>>;
+
+    /// 条件付きリクエスト用の ETag
+    /// None なら ETag を付けません
+    fn etag(&self) -> impl Future<Output = std::io::Result<Option<String>>> {
+        async { Ok(None) }
+    }
+}
+
+/// `FileContentBuilder` が読み出し元にする仮想ファイルシステム
+pub trait Vfs: 'static {
+    type File: VfsFile;
+
+    fn open(&self, path: &Path) -> impl Future<Output = std::io::Result<Self::File>>;
+
+    fn metadata(&self, path: &Path) -> impl Future<Output = std::io::Result<VfsMetadata>>;
+
+    fn read_dir(&self, path: &Path) -> impl Future<Output = std::io::Result<Vec<DirEntryInfo>>>;
+
+    /// base 配下に path を安全に連結する
+    /// デフォルトは字句的な正規化のみで `..` による脱出と絶対パスを拒否します
+    fn resolve(&self, base: &Path, path: &Path) -> std::io::Result<PathBuf> {
+        if path.is_absolute() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "absolute path not allowed",
+            ));
+        }
+        let mut resolved = base.to_path_buf();
+        let mut depth = 0usize;
+        for comp in path.components() {
+            match comp {
+                Component::Normal(seg) => {
+                    resolved.push(seg);
+                    depth += 1;
+                },
+                Component::CurDir => {},
+                Component::ParentDir if depth > 0 => {
+                    resolved.pop();
+                    depth -= 1;
+                },
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        "path traversal detected",
+                    ));
+                },
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VfsMetadata {
+    pub kind: FileKind,
+    pub len: u64,
+}
+
+/// OS のファイルシステム
+/// F でランタイムのファイル実装を選びます
+pub struct OsFs<F = DefaultFile> {
+    phantom: PhantomData<fn() -> F>,
+}
+
+impl<F> OsFs<F> {
+    pub fn new() -> Self {
+        OsFs { phantom: PhantomData }
+    }
+}
+
+impl<F> Default for OsFs<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F> Clone for OsFs<F> {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl<F: AsyncFile> Vfs for OsFs<F> {
+    type File = F;
+
+    #[inline]
+    async fn open(&self, path: &Path) -> std::io::Result<F> {
+        F::open(path).await
+    }
+
+    #[inline]
+    async fn metadata(&self, path: &Path) -> std::io::Result<VfsMetadata> {
+        F::metadata(path).await
+    }
+
+    #[inline]
+    async fn read_dir(&self, path: &Path) -> std::io::Result<Vec<DirEntryInfo>> {
+        F::read_dir(path).await
+    }
+
+    fn resolve(&self, base: &Path, path: &Path) -> std::io::Result<PathBuf> {
+        // base を実体パス化（相対のままだと starts_with が壊れる）
+        let base = base.canonicalize()?;
+
+        // 絶対パス拒否
+        if path.is_absolute() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "absolute path not allowed",
+            ));
+        }
+
+        let joined = base.join(path);
+        let canon = joined.canonicalize()?;
+
+        // base 配下チェック（symlink脱出も防ぐ）
+        if !canon.starts_with(&base) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "path traversal detected",
+            ));
+        }
+        Ok(canon)
+    }
+}
@@ -1,18 +1,31 @@
 #[cfg(feature = "file")]
-#[cfg(not(feature = "tokio-server"))]
-compile_error!("feature `file` requires `tokio-server`");
+#[cfg(not(any(feature = "tokio-server", feature = "compio-server")))]
+compile_error!("feature `file` requires `tokio-server` or `compio-server`");
+#[cfg(feature = "embed")]
+pub mod embed;
 #[cfg(feature = "file")]
-#[cfg(feature = "tokio-server")]
+#[cfg(any(feature = "tokio-server", feature = "compio-server"))]
 pub mod file;
 
-use std::borrow::Borrow;
+use std::{
+    borrow::Borrow,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use futures_io::{AsyncRead, AsyncWrite};
 use futures_util::{AsyncReadExt, AsyncWriteExt, future::join};
 
 use crate::{
     error::{ConnectionResult, ErrorPare, RouterError},
-    http::{code::HttpStatusCode, request::HttpRequest, response::HttpResponse},
+    http::{
+        code::HttpStatusCode,
+        extensions::Extensions,
+        info::{ConnectionInfo, PeerCertificate, TlsInfo},
+        request::{HttpRequest, RequestHandle},
+        response::{HttpResponse, WriterHandle},
+    },
+    response::{HandlerResponse, IntoResponse},
     utils::{write_all_vectored3, write_hex_crlf},
 };
 
@@ -44,15 +57,167 @@ impl ConnectionState for CompletedResponse {}
 pub const STREAM_CHUNK_SIZE: usize = 1024 * 32; // 32KB
 
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, S: ConnectionState> Connection<C, R, W, S> {
+    /// パスをセグメントごとに分割する
+    /// マウントのプレフィックスは除きますが、クエリは最後のセグメントに含まれたままです
+    /// (`/hello?lang=ja` は `["hello?lang=ja"]`)
     #[inline(always)]
     pub fn path_seg_iter<'a>(&'a self) -> std::str::Split<'a, char> {
-        self.req.path_full()[1..].split('/')
+        let path = self.req.path_full_relative();
+        path.strip_prefix('/').unwrap_or(path).split('/')
     }
 
     #[inline(always)]
-    pub fn path_segs<'a>(&'a self) -> Box<[&'a str]> {
+    pub fn path_segs(&self) -> Box<[&str]> {
         self.path_seg_iter().collect::<Box<[_]>>()
     }
+
+    /// ルーターがキャプチャしたパスパラメータを取得する
+    /// `/users/:id` なら `conn.param("id")`
+    #[inline(always)]
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.req.params().get(name)
+    }
+
+    /// リクエストごとの拡張データ
+    /// ミドルウェアで入れた値をハンドラで取り出すのに使います
+    #[inline(always)]
+    pub fn extensions(&self) -> &Extensions {
+        self.req.extensions()
+    }
+
+    #[inline(always)]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.req.extensions_mut()
+    }
+
+    /// 拡張データを型で取り出す
+    #[inline(always)]
+    pub fn extension<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.req.extensions().get()
+    }
+
+    /// 拡張データを入れる
+    /// ミドルウェアでメソッドチェーンしやすいように自身を返します
+    #[inline]
+    pub fn with_extension<T>(mut self, value: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.req.extensions_mut().insert(value);
+        self
+    }
+
+    /// 相手のアドレス
+    /// プロキシの後ろでは、プロキシのアドレスになります
+    #[inline(always)]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.req.connection_info().peer_addr()
+    }
+
+    /// 本当のクライアントのアドレス
+    /// 信頼するプロキシからの転送ヘッダがあればそこから、なければ相手のアドレスです
+    /// 転送ヘッダが `unknown` などアドレスを隠していれば None
+    #[inline]
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match self.req.forwarded() {
+            Some(forwarded) => forwarded.ip(),
+            None => self.peer_addr().map(|addr| addr.ip()),
+        }
+    }
+
+    /// クライアントが使ったスキーム
+    /// 信頼するプロキシからの転送ヘッダになければ、この接続のもの (`http` か `https`) です
+    #[inline]
+    pub fn client_scheme(&self) -> &str {
+        match self
+            .req
+            .forwarded()
+            .and_then(|forwarded| forwarded.scheme())
+        {
+            Some(scheme) => scheme,
+            None if self.tls().is_some() => "https",
+            None => "http",
+        }
+    }
+
+    /// クライアントが送った Host
+    /// 信頼するプロキシからの転送ヘッダになければ、このリクエストの Host ヘッダです
+    #[inline]
+    pub fn client_host(&self) -> Option<&str> {
+        self.req
+            .forwarded()
+            .and_then(|forwarded| forwarded.host())
+            .or_else(|| self.req.header_value("Host"))
+    }
+
+    /// この接続を受けたこちら側のアドレス
+    #[inline(always)]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.req.connection_info().local_addr()
+    }
+
+    /// 相手やリスナーのアドレスなど、接続そのものの情報
+    #[inline(always)]
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        self.req.connection_info()
+    }
+
+    /// TLS で受けた接続なら、ALPN や SNI など交渉の結果
+    #[inline(always)]
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.req.connection_info().tls()
+    }
+
+    /// mTLS で検証済みのクライアント証明書
+    /// Subject や SAN、フィンガープリントを見て認可に使えます
+    #[inline]
+    pub fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.tls().and_then(|tls| tls.peer_certificate())
+    }
+
+    /// このリクエストだけハンドラのタイムアウトを変える
+    /// サーバービルダーの `handler_timeout` より優先され、None ならタイムアウトしません
+    #[inline]
+    pub fn with_handler_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.req.set_handler_timeout(timeout);
+        self
+    }
+
+    /// 接続ごとの状態を型で取り出す
+    /// サーバービルダーの `connection_state` で作った値で、keep-alive の間引き継がれます
+    #[inline(always)]
+    pub fn connection_state<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.req.connection_state().get()
+    }
+
+    #[inline(always)]
+    pub fn connection_state_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.req.connection_state_mut().get_mut()
+    }
+
+    /// コンテキストを差し替える
+    /// 元のコンテキストと差し替えたコネクションを返します
+    #[inline]
+    pub fn replace_context<C2>(self, c: C2) -> (C, Connection<C2, R, W, S>) {
+        (
+            self.c,
+            Connection {
+                c,
+                req: self.req,
+                res: self.res,
+                phantom: std::marker::PhantomData,
+            },
+        )
+    }
 }
 
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, NoneBody> {
@@ -64,6 +229,75 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
             phantom: std::marker::PhantomData,
         }
     }
+
+    /// ハンドラに渡す前に、手放されても取り戻せるようにしておく
+    #[inline]
+    pub(crate) fn recovery(&mut self) -> ConnectionRecovery<C, R, W>
+    where
+        C: Clone,
+    {
+        let (req, owns_req) = self.req.request_handle();
+        let (res, owns_res) = self.res.shared_writer_handle();
+        ConnectionRecovery {
+            c: self.c.clone(),
+            req,
+            res,
+            owns_req,
+            owns_res,
+            omit_body: self.res.is_body_omitted(),
+            write_timeout: self.res.write_timeout(),
+        }
+    }
+}
+
+/// ハンドラが Connection を手放して値だけを返したときに、Connection を組み立て直すためのもの
+pub(crate) struct ConnectionRecovery<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> {
+    c: C,
+    req: RequestHandle<R>,
+    res: WriterHandle<W>,
+    /// ここでハンドルを付けたか (付けたなら終わったら外す)
+    owns_req: bool,
+    owns_res: bool,
+    omit_body: bool,
+    write_timeout: Option<Duration>,
+}
+
+impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> ConnectionRecovery<C, R, W> {
+    /// ハンドラの戻り値からレスポンスを作る
+    ///
+    /// # Panics
+    /// Connection が drop されずにどこかへ持ち出されていたり、既にレスポンスを書き込み始めていたら、
+    /// レスポンスを作れないのでパニックします (ルーターが捕まえて接続を閉じます)
+    pub(crate) fn finish(self, responded: HandlerResponse<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        let mut conn = match responded {
+            HandlerResponse::Ready(conn) => conn,
+            HandlerResponse::Deferred(respond) => {
+                let req = self
+                    .req
+                    .recover()
+                    .expect("handler returned a value without dropping its Connection");
+                let writer = self
+                    .res
+                    .recover_untouched()
+                    .expect("handler dropped its Connection after writing part of the response");
+                let mut res = HttpResponse::new(writer);
+                res.set_omit_body(self.omit_body);
+                res.set_write_timeout(self.write_timeout);
+                let mut conn = Connection::new(self.c, req, res);
+                // 外側でも取り戻せるように付け直す
+                conn.req.attach_request_handle(&self.req);
+                conn.res.attach_writer_handle(&self.res);
+                respond(conn)
+            },
+        };
+        if self.owns_req {
+            conn.req.release_request_handle();
+        }
+        if self.owns_res {
+            conn.res.release_writer_handle();
+        }
+        conn
+    }
 }
 
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, NoneBody> {
@@ -240,6 +474,16 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
         self.set_status_code(HttpStatusCode::Found)
             .redirect(location.borrow())
     }
+
+    /// `IntoResponse` な値でレスポンスを作る
+    /// `PathRouter` のルートなら値をそのまま返せるので、`router_and_build` のクロージャなどで使います
+    #[inline]
+    pub fn respond<T>(self, value: T) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        T: IntoResponse<C, R, W>,
+    {
+        value.into_response(self)
+    }
 }
 
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, StatusSetNoneBody> {
@@ -514,6 +758,20 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
         }
     }
 
+    /// Content-Length を付けずにヘッダだけで終える
+    /// 304 のようにボディを持たないレスポンス用
+    #[inline]
+    #[cfg(any(feature = "file", feature = "embed"))]
+    pub(crate) fn headers_only(mut self) -> Connection<C, R, W, ResponseReadyToSend> {
+        self.res.start_content();
+        Connection {
+            c: self.c,
+            req: self.req,
+            res: self.res,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
     #[inline]
     pub fn redirect<T>(self, location: T) -> Connection<C, R, W, ResponseReadyToSend>
     where
@@ -562,12 +820,21 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
                 connection: response_ready_conn,
             });
         }
+        self.res.flag_flushed_buf();
+        if self.res.is_body_omitted() {
+            return Ok(Connection {
+                c: self.c,
+                req: self.req,
+                res: self.res,
+                phantom: std::marker::PhantomData,
+            });
+        }
 
         let mut buf0 = [0u8; STREAM_CHUNK_SIZE];
         let mut buf1 = [0u8; STREAM_CHUNK_SIZE];
 
         let stream_res: std::io::Result<()> = 'brk: {
-            let writer = self.res.writer();
+            let mut writer = self.res.timed_writer();
 
             let mut cur_n = match reader.read(&mut buf0).await {
                 Ok(n) => n,
@@ -645,13 +912,22 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
                 connection: response_ready_conn,
             });
         }
+        self.res.flag_flushed_buf();
+        if self.res.is_body_omitted() {
+            return Ok(Connection {
+                c: self.c,
+                req: self.req,
+                res: self.res,
+                phantom: std::marker::PhantomData,
+            });
+        }
 
         let mut buf0 = [0u8; STREAM_CHUNK_SIZE];
         let mut buf1 = [0u8; STREAM_CHUNK_SIZE];
         let mut hexline = [0u8; 32];
 
         let stream_res: std::io::Result<()> = 'brk: {
-            let mut writer = self.res.writer();
+            let mut writer = self.res.timed_writer();
 
             let mut cur_n = match reader.read(&mut buf0).await {
                 Ok(n) => n,
@@ -740,18 +1016,22 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
 impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connection<C, R, W, ChunkedResponse> {
     #[inline]
     pub async fn send_chunk(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        if self.res.is_body_omitted() {
+            return Ok(());
+        }
         let chunk_size_hex = format!("{:X}\r\n", chunk.len());
-        self.res
-            .writer()
-            .write_all(chunk_size_hex.as_bytes())
-            .await?;
-        self.res.writer().write_all(chunk).await?;
-        self.res.writer().write_all(b"\r\n").await
+        let mut writer = self.res.timed_writer();
+        writer.write_all(chunk_size_hex.as_bytes()).await?;
+        writer.write_all(chunk).await?;
+        writer.write_all(b"\r\n").await
     }
 
     #[inline]
     pub async fn send_last_chunk(&mut self) -> std::io::Result<()> {
-        self.res.writer().write_all(b"0\r\n\r\n").await
+        if self.res.is_body_omitted() {
+            return Ok(());
+        }
+        self.res.timed_writer().write_all(b"0\r\n\r\n").await
     }
 
     #[inline]
@@ -802,13 +1082,65 @@ impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static> Connect
         })
     }
 
+    /// レスポンスが既に送信済みか (ストリーミングなど)
+    /// 送信済みならヘッダやステータスの変更は反映されません
+    #[inline]
+    pub fn is_sent(&self) -> bool {
+        self.res.is_flushed()
+    }
+
+    #[inline]
+    pub fn status_code(&self) -> HttpStatusCode {
+        self.res.status_code()
+    }
+
+    /// ミドルウェアの after などでステータスコードを差し替える
+    #[inline]
+    pub fn set_status_code<T>(mut self, status_code: T) -> Self
+    where
+        T: Into<u16>,
+    {
+        if !self.is_sent() {
+            self.res.set_status_code(status_code);
+        }
+        self
+    }
+
+    /// ミドルウェアの after などでヘッダを追加する
+    /// ボディの後ろに挿入するのでバッファのシフトが発生します
+    #[inline]
+    pub fn add_header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        if !self.is_sent() {
+            self.res.header_add(key, value);
+        }
+        self
+    }
+
+    #[inline]
+    pub fn remove_header<S>(mut self, key: S) -> Self
+    where
+        S: std::borrow::Borrow<str>,
+    {
+        if !self.is_sent() {
+            self.res.header_remove(key);
+        }
+        self
+    }
+
     /// 構築したレスポンスを破棄して初期化
     /// レスポンスを再設計したいときに
     pub fn cancel(self) -> Connection<C, R, W, NoneBody> {
+        let omit_body = self.res.is_body_omitted();
+        let mut res = self.res.reset();
+        res.set_omit_body(omit_body);
         Connection {
             c: self.c,
             req: self.req,
-            res: self.res.reset(),
+            res,
             phantom: std::marker::PhantomData,
         }
     }
@@ -12,6 +12,12 @@ pub enum RouterError {
     IoError(std::io::Error),
     Timeout,
     KeepAliveTimeout,
+    HandlerPanicked,
+    HandlerTimeout,
+    /// リクエストを読み切れないまま応答したので、接続を閉じた
+    RequestNotConsumed,
+    /// サーバーを止めるので、接続を閉じた
+    Shutdown,
 }
 
 impl fmt::Display for RouterError {
@@ -39,6 +45,18 @@ impl fmt::Display for RouterError {
             RouterError::KeepAliveTimeout => {
                 write!(f, "Keep-Alive Timeout Error")
             },
+            RouterError::HandlerPanicked => {
+                write!(f, "Handler Panicked")
+            },
+            RouterError::HandlerTimeout => {
+                write!(f, "Handler Timeout")
+            },
+            RouterError::RequestNotConsumed => {
+                write!(f, "Request Not Consumed")
+            },
+            RouterError::Shutdown => {
+                write!(f, "Server Shutdown")
+            },
         }
     }
 }
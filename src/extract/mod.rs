@@ -0,0 +1,736 @@
+//! ハンドラの引数をリクエストから型付きで取り出す
+//!
+//! `extract` でくるむと、第2引数以降を `FromConnection` で取り出してからハンドラを呼びます
+//! 取り出しに失敗したら 400 / 415 / 422 などのレスポンスを自動で返します
+//!
+//! ```ignore
+//! async fn show(conn: ConnReq, Path(id): Path<u32>, Query(q): Query<Search>) -> ConnRes {
+//!     conn.text_body(format!("{} {}", id, q.word))
+//! }
+//!
+//! let router = TokioPathRouter::builder()
+//!     .get("/items/:id", extract(show))
+//!     .build()?;
+//! ```
+
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::future::{BoxFuture, LocalBoxFuture};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    http::{ConnectionInfo, HttpStatusCode},
+    response::IntoResponse,
+    router::PathParams,
+};
+
+/// 取り出しに失敗したときのレスポンス
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    status: HttpStatusCode,
+    message: Cow<'static, str>,
+}
+
+impl Rejection {
+    pub fn new<M>(status: HttpStatusCode, message: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Rejection { status, message: message.into() }
+    }
+
+    /// 400 Bad Request
+    pub fn bad_request<M>(message: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(HttpStatusCode::BadRequest, message)
+    }
+
+    /// 415 Unsupported Media Type
+    pub fn unsupported_media_type<M>(message: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(HttpStatusCode::UnsupportedMediaType, message)
+    }
+
+    /// 422 Unprocessable Entity
+    pub fn unprocessable<M>(message: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(HttpStatusCode::UnprocessableEntity, message)
+    }
+
+    #[inline]
+    pub fn status(&self) -> HttpStatusCode {
+        self.status
+    }
+
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", u16::from(self.status), self.message)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Connection から取り出せる型
+///
+/// 取り出しは同期的に行われます
+/// `READS_BODY` が true の型が引数にあれば、先にボディを読み込んで `body` に渡します
+pub trait FromConnection<C, R, W>: Sized
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    const READS_BODY: bool = false;
+
+    fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection>;
+}
+
+/// 取り出しに失敗しても None で続行する
+impl<C, R, W, T> FromConnection<C, R, W> for Option<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: FromConnection<C, R, W>,
+{
+    const READS_BODY: bool = T::READS_BODY;
+
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+        Ok(T::from_connection(conn, body).ok())
+    }
+}
+
+/// 取り出しの失敗をハンドラで扱う
+impl<C, R, W, T> FromConnection<C, R, W> for Result<T, Rejection>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: FromConnection<C, R, W>,
+{
+    const READS_BODY: bool = T::READS_BODY;
+
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+        Ok(T::from_connection(conn, body))
+    }
+}
+
+macro_rules! impl_from_connection_tuple {
+    ($($t:ident),*) => {
+        impl<C, R, W, $($t),*> FromConnection<C, R, W> for ($($t,)*)
+        where
+            R: AsyncRead + Unpin + 'static,
+            W: AsyncWrite + Unpin + 'static,
+            $($t: FromConnection<C, R, W>,)*
+        {
+            const READS_BODY: bool = false $(|| $t::READS_BODY)*;
+
+            #[inline]
+            #[allow(unused_variables)]
+            fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+                Ok(($($t::from_connection(conn, body)?,)*))
+            }
+        }
+    };
+}
+
+impl_from_connection_tuple!();
+impl_from_connection_tuple!(T1);
+impl_from_connection_tuple!(T1, T2);
+impl_from_connection_tuple!(T1, T2, T3);
+impl_from_connection_tuple!(T1, T2, T3, T4);
+impl_from_connection_tuple!(T1, T2, T3, T4, T5);
+impl_from_connection_tuple!(T1, T2, T3, T4, T5, T6);
+
+/// ルーターがキャプチャしたパスパラメータ
+///
+/// パラメータが1つなら `Path<u32>`、複数なら `Path<(u32, String)>` のように
+/// ルートパターンに現れる順で受け取ります
+/// `nest` の下では親のパラメータも前に並ぶので、受け取るのは後ろ (内側のルート) から必要な数だけです
+#[derive(Debug, Clone)]
+pub struct Path<T>(pub T);
+
+/// パスパラメータ列から作れる型
+pub trait FromPathParams: Sized {
+    fn from_params(params: &PathParams) -> Result<Self, Rejection>;
+}
+
+impl FromPathParams for PathParams {
+    #[inline]
+    fn from_params(params: &PathParams) -> Result<Self, Rejection> {
+        Ok(params.clone())
+    }
+}
+
+/// パラメータがルートより多く要るのはハンドラ側の書き間違いなので 500
+fn param_count_mismatch(expected: usize, actual: usize) -> Rejection {
+    Rejection::new(
+        HttpStatusCode::InternalServerError,
+        format!(
+            "handler expects {} path parameters but the route captured {}",
+            expected, actual
+        ),
+    )
+}
+
+/// 後ろから `count` 個を受け取るときの先頭の位置
+fn params_offset(params: &PathParams, count: usize) -> Result<usize, Rejection> {
+    params
+        .len()
+        .checked_sub(count)
+        .ok_or_else(|| param_count_mismatch(count, params.len()))
+}
+
+fn parse_param<T: FromStr>(params: &PathParams, index: usize) -> Result<T, Rejection> {
+    let (name, value) = params
+        .iter()
+        .nth(index)
+        .ok_or_else(|| param_count_mismatch(index + 1, params.len()))?;
+    value
+        .parse()
+        .map_err(|_| Rejection::bad_request(format!("invalid path parameter `{}`: {}", name, value)))
+}
+
+macro_rules! impl_from_params_single {
+    ($($t:ty),*) => {
+        $(
+            impl FromPathParams for $t {
+                #[inline]
+                fn from_params(params: &PathParams) -> Result<Self, Rejection> {
+                    parse_param(params, params_offset(params, 1)?)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_params_single!(
+    String, bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
+
+macro_rules! impl_from_params_tuple {
+    ($len:expr; $($t:ident => $i:tt),*) => {
+        impl<$($t: FromStr),*> FromPathParams for ($($t,)*) {
+            #[inline]
+            fn from_params(params: &PathParams) -> Result<Self, Rejection> {
+                let offset = params_offset(params, $len)?;
+                Ok(($(parse_param::<$t>(params, offset + $i)?,)*))
+            }
+        }
+    };
+}
+
+impl_from_params_tuple!(1; T1 => 0);
+impl_from_params_tuple!(2; T1 => 0, T2 => 1);
+impl_from_params_tuple!(3; T1 => 0, T2 => 1, T3 => 2);
+impl_from_params_tuple!(4; T1 => 0, T2 => 1, T3 => 2, T4 => 3);
+
+impl<C, R, W, T> FromConnection<C, R, W> for Path<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: FromPathParams,
+{
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        T::from_params(conn.req.params()).map(Path)
+    }
+}
+
+/// ヘッダ名と値の変換を持つ型
+pub trait TypedHeader: Sized {
+    const NAME: &'static str;
+
+    fn decode(value: &str) -> Option<Self>;
+}
+
+/// リクエストヘッダ
+/// ヘッダがない、または変換できなければ 400
+#[derive(Debug, Clone)]
+pub struct Header<T>(pub T);
+
+impl<C, R, W, T> FromConnection<C, R, W> for Header<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: TypedHeader,
+{
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        match conn.req.header_value(T::NAME) {
+            Some(value) => T::decode(value)
+                .map(Header)
+                .ok_or_else(|| Rejection::bad_request(format!("invalid {} header", T::NAME))),
+            None => Err(Rejection::bad_request(format!(
+                "missing {} header",
+                T::NAME
+            ))),
+        }
+    }
+}
+
+macro_rules! string_header {
+    ($($(#[$meta:meta])* $name:ident => $header:expr),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, PartialEq, Eq)]
+            pub struct $name(pub String);
+
+            impl TypedHeader for $name {
+                const NAME: &'static str = $header;
+
+                #[inline]
+                fn decode(value: &str) -> Option<Self> {
+                    Some($name(value.to_string()))
+                }
+            }
+        )*
+    };
+}
+
+string_header! {
+    Host => "Host",
+    UserAgent => "User-Agent",
+    Accept => "Accept",
+    ContentType => "Content-Type",
+    /// `Bearer xxx` などスキームごとそのまま
+    Authorization => "Authorization",
+}
+
+/// Content-Length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLength(pub u64);
+
+impl TypedHeader for ContentLength {
+    const NAME: &'static str = "Content-Length";
+
+    #[inline]
+    fn decode(value: &str) -> Option<Self> {
+        value.trim().parse().ok().map(ContentLength)
+    }
+}
+
+/// ユーザーコンテキストのクローン
+#[derive(Debug, Clone)]
+pub struct Context<C>(pub C);
+
+impl<C, R, W> FromConnection<C, R, W> for Context<C>
+where
+    C: Clone,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        Ok(Context(conn.c.clone()))
+    }
+}
+
+/// ミドルウェアが入れた拡張データのクローン
+/// 入っていなければ設定ミスなので 500 を返します
+#[derive(Debug, Clone)]
+pub struct Extension<T>(pub T);
+
+impl<C, R, W, T> FromConnection<C, R, W> for Extension<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        conn.extension::<T>()
+            .cloned()
+            .map(Extension)
+            .ok_or_else(|| {
+                Rejection::new(
+                    HttpStatusCode::InternalServerError,
+                    format!("missing extension: {}", std::any::type_name::<T>()),
+                )
+            })
+    }
+}
+
+/// 相手やリスナーのアドレスなど、接続そのものの情報
+impl<C, R, W> FromConnection<C, R, W> for ConnectionInfo
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        Ok(conn.connection_info().clone())
+    }
+}
+
+/// 生のリクエストボディ
+#[derive(Debug, Clone)]
+pub struct Body(pub Vec<u8>);
+
+impl<C, R, W> FromConnection<C, R, W> for Body
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    const READS_BODY: bool = true;
+
+    #[inline]
+    fn from_connection(_conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+        Ok(Body(body.to_vec()))
+    }
+}
+
+/// Content-Type の `;` より前がいずれかに一致するか
+#[cfg(any(feature = "json", feature = "form"))]
+fn content_type_is<C, R, W>(conn: &Connection<C, R, W>, accept: impl Fn(&str) -> bool) -> bool
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    match conn.req.header_value("Content-Type") {
+        Some(value) => {
+            let essence = value
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase();
+            accept(&essence)
+        },
+        None => false,
+    }
+}
+
+/// JSON ボディ
+///
+/// - Content-Type が `application/json` (`+json` 含む) でなければ 415
+/// - 構文エラーは 400、型が合わなければ 422
+#[cfg(feature = "json")]
+#[derive(Debug, Clone)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<C, R, W, T> FromConnection<C, R, W> for Json<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: serde::de::DeserializeOwned,
+{
+    const READS_BODY: bool = true;
+
+    fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+        if !content_type_is(conn, |ct| ct == "application/json" || ct.ends_with("+json")) {
+            return Err(Rejection::unsupported_media_type(
+                "expected Content-Type: application/json",
+            ));
+        }
+        serde_json::from_slice(body)
+            .map(Json)
+            .map_err(|e| match e.classify() {
+                serde_json::error::Category::Data => Rejection::unprocessable(e.to_string()),
+                _ => Rejection::bad_request(e.to_string()),
+            })
+    }
+}
+
+/// クエリ文字列
+/// 変換できなければ 400
+#[cfg(feature = "form")]
+#[derive(Debug, Clone)]
+pub struct Query<T>(pub T);
+
+#[cfg(feature = "form")]
+impl<C, R, W, T> FromConnection<C, R, W> for Query<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: serde::de::DeserializeOwned,
+{
+    fn from_connection(conn: &Connection<C, R, W>, _body: &[u8]) -> Result<Self, Rejection> {
+        serde_urlencoded::from_str(conn.req.query().unwrap_or(""))
+            .map(Query)
+            .map_err(|e| Rejection::bad_request(format!("invalid query string: {}", e)))
+    }
+}
+
+/// `application/x-www-form-urlencoded` のボディ
+///
+/// - Content-Type が違えば 415
+/// - 変換できなければ 422
+#[cfg(feature = "form")]
+#[derive(Debug, Clone)]
+pub struct Form<T>(pub T);
+
+#[cfg(feature = "form")]
+impl<C, R, W, T> FromConnection<C, R, W> for Form<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: serde::de::DeserializeOwned,
+{
+    const READS_BODY: bool = true;
+
+    fn from_connection(conn: &Connection<C, R, W>, body: &[u8]) -> Result<Self, Rejection> {
+        if !content_type_is(conn, |ct| ct == "application/x-www-form-urlencoded") {
+            return Err(Rejection::unsupported_media_type(
+                "expected Content-Type: application/x-www-form-urlencoded",
+            ));
+        }
+        serde_urlencoded::from_bytes(body)
+            .map(Form)
+            .map_err(|e| Rejection::unprocessable(format!("invalid form body: {}", e)))
+    }
+}
+
+/// Connection と取り出した引数で呼べるハンドラ
+/// `Fn(Connection, T1, T2, ...)` に実装されています
+pub trait ExtractHandler<C, R, W, Args>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    type Fut: Future<Output = Connection<C, R, W, ResponseReadyToSend>>;
+
+    fn call(&self, conn: Connection<C, R, W>, args: Args) -> Self::Fut;
+}
+
+macro_rules! impl_extract_handler {
+    ($($t:ident),*) => {
+        impl<C, R, W, F, Fut, $($t),*> ExtractHandler<C, R, W, ($($t,)*)> for F
+        where
+            R: AsyncRead + Unpin + 'static,
+            W: AsyncWrite + Unpin + 'static,
+            F: Fn(Connection<C, R, W>, $($t),*) -> Fut,
+            Fut: Future<Output = Connection<C, R, W, ResponseReadyToSend>>,
+        {
+            type Fut = Fut;
+
+            #[inline(always)]
+            #[allow(non_snake_case)]
+            fn call(&self, conn: Connection<C, R, W>, ($($t,)*): ($($t,)*)) -> Self::Fut {
+                (self)(conn, $($t),*)
+            }
+        }
+    };
+}
+
+impl_extract_handler!(T1);
+impl_extract_handler!(T1, T2);
+impl_extract_handler!(T1, T2, T3);
+impl_extract_handler!(T1, T2, T3, T4);
+impl_extract_handler!(T1, T2, T3, T4, T5);
+impl_extract_handler!(T1, T2, T3, T4, T5, T6);
+
+/// `extract` が返すハンドラの Future
+pub type ExtractFuture<C, R, W> = BoxFuture<'static, Connection<C, R, W, ResponseReadyToSend>>;
+
+/// `extract_local` が返すハンドラの Future
+pub type LocalExtractFuture<C, R, W> = LocalBoxFuture<'static, Connection<C, R, W, ResponseReadyToSend>>;
+
+/// 必要ならボディを読んでから引数を取り出す
+async fn extract_args<C, R, W, Args>(conn: &mut Connection<C, R, W>) -> Result<Args, Rejection>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    Args: FromConnection<C, R, W>,
+{
+    let body = if Args::READS_BODY {
+        let res = conn.req.read_body_bytes().await;
+        res.map_err(|e| match e.kind() {
+            _ if conn.req.is_body_too_large() => {
+                Rejection::new(HttpStatusCode::PayloadTooLarge, "request body too large")
+            },
+            std::io::ErrorKind::TimedOut => Rejection::new(HttpStatusCode::RequestTimeout, "request body timed out"),
+            _ => Rejection::bad_request(format!("failed to read request body: {}", e)),
+        })?
+    } else {
+        Vec::new()
+    };
+    Args::from_connection(conn, &body)
+}
+
+/// 型付き引数のハンドラを通常のハンドラにする (tokio など Send が必要なとき)
+pub fn extract<C, R, W, Args, H>(
+    handler: H,
+) -> impl Fn(Connection<C, R, W>) -> ExtractFuture<C, R, W> + Clone + Send + Sync + 'static
+where
+    C: Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    Args: FromConnection<C, R, W> + Send + 'static,
+    H: ExtractHandler<C, R, W, Args> + Clone + Send + Sync + 'static,
+    H::Fut: Send + 'static,
+{
+    move |mut conn| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            match extract_args::<C, R, W, Args>(&mut conn).await {
+                Ok(args) => handler.call(conn, args).await,
+                Err(rejection) => rejection.into_response(conn),
+            }
+        })
+    }
+}
+
+/// 型付き引数のハンドラを通常のハンドラにする (compio など Send が不要なとき)
+/// `CompioPathRouter` のルートに渡せます
+pub fn extract_local<C, R, W, Args, H>(
+    handler: H,
+) -> impl Fn(Connection<C, R, W>) -> LocalExtractFuture<C, R, W> + Clone + Sync + 'static
+where
+    C: 'static,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    Args: FromConnection<C, R, W> + 'static,
+    H: ExtractHandler<C, R, W, Args> + Clone + Sync + 'static,
+    H::Fut: 'static,
+{
+    move |mut conn| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            match extract_args::<C, R, W, Args>(&mut conn).await {
+                Ok(args) => handler.call(conn, args).await,
+                Err(rejection) => rejection.into_response(conn),
+            }
+        })
+    }
+}
+
+/// 取り出した引数だけで呼べて、`IntoResponse` を返すハンドラ
+/// `Fn(T1, T2, ...)` に実装されています
+pub trait RespondHandler<Args> {
+    type Output;
+    type Fut: Future<Output = Self::Output>;
+
+    fn call(&self, args: Args) -> Self::Fut;
+}
+
+macro_rules! impl_respond_handler {
+    ($($t:ident),*) => {
+        impl<F, Fut, $($t),*> RespondHandler<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> Fut,
+            Fut: Future,
+        {
+            type Output = Fut::Output;
+            type Fut = Fut;
+
+            #[inline(always)]
+            #[allow(non_snake_case)]
+            fn call(&self, ($($t,)*): ($($t,)*)) -> Self::Fut {
+                (self)($($t),*)
+            }
+        }
+    };
+}
+
+impl_respond_handler!();
+impl_respond_handler!(T1);
+impl_respond_handler!(T1, T2);
+impl_respond_handler!(T1, T2, T3);
+impl_respond_handler!(T1, T2, T3, T4);
+impl_respond_handler!(T1, T2, T3, T4, T5);
+impl_respond_handler!(T1, T2, T3, T4, T5, T6);
+
+/// 値を返すハンドラを通常のハンドラにする (tokio など Send が必要なとき)
+///
+/// Connection はハンドラに渡さず、返した値を `IntoResponse` でレスポンスにします
+pub fn respond<C, R, W, Args, H>(
+    handler: H,
+) -> impl Fn(Connection<C, R, W>) -> ExtractFuture<C, R, W> + Clone + Send + Sync + 'static
+where
+    C: Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+    Args: FromConnection<C, R, W> + Send + 'static,
+    H: RespondHandler<Args> + Clone + Send + Sync + 'static,
+    H::Output: IntoResponse<C, R, W>,
+    H::Fut: Send + 'static,
+{
+    move |mut conn| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            match extract_args::<C, R, W, Args>(&mut conn).await {
+                Ok(args) => handler.call(args).await.into_response(conn),
+                Err(rejection) => rejection.into_response(conn),
+            }
+        })
+    }
+}
+
+/// 値を返すハンドラを通常のハンドラにする (compio など Send が不要なとき)
+/// `CompioPathRouter` のルートに渡せます
+pub fn respond_local<C, R, W, Args, H>(
+    handler: H,
+) -> impl Fn(Connection<C, R, W>) -> LocalExtractFuture<C, R, W> + Clone + Sync + 'static
+where
+    C: 'static,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    Args: FromConnection<C, R, W> + 'static,
+    H: RespondHandler<Args> + Clone + Sync + 'static,
+    H::Output: IntoResponse<C, R, W>,
+    H::Fut: 'static,
+{
+    move |mut conn| {
+        let handler = handler.clone();
+        Box::pin(async move {
+            match extract_args::<C, R, W, Args>(&mut conn).await {
+                Ok(args) => handler.call(args).await.into_response(conn),
+                Err(rejection) => rejection.into_response(conn),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, io::Cursor};
+
+    use super::*;
+    use crate::http::{HttpLimits, HttpRequest, HttpResponse};
+
+    fn body_rejection(raw: &str, max_body: usize) -> Option<HttpStatusCode> {
+        let mut req = HttpRequest::new(Cursor::new(raw.as_bytes().to_vec()));
+        req.set_limits(HttpLimits { max_body, ..HttpLimits::new() });
+        let req = async { req.parse_request_line().await?.parse_request().await }
+            .now_or_never()
+            .unwrap()
+            .ok()
+            .unwrap();
+        let mut conn = Connection::new((), req, HttpResponse::new(Vec::new()));
+        extract_args::<_, _, _, Body>(&mut conn)
+            .now_or_never()
+            .unwrap()
+            .err()
+            .map(|rejection| rejection.status())
+    }
+
+    #[test]
+    fn body_over_the_limit_is_413() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nabcde";
+        assert_eq!(body_rejection(raw, 5), None);
+        assert_eq!(
+            body_rejection(raw, 4),
+            Some(HttpStatusCode::PayloadTooLarge)
+        );
+    }
+
+    #[test]
+    fn truncated_body_is_400() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nabc";
+        assert_eq!(body_rejection(raw, 1024), Some(HttpStatusCode::BadRequest));
+    }
+}
@@ -193,33 +193,36 @@ impl HttpStatusCode {
             HttpStatusCode::Unused => HttpStatusInfo { code: 306, message: "(Unused)" },
             HttpStatusCode::TemporaryRedirect => HttpStatusInfo { code: 307, message: "Temporary Redirect" },
             HttpStatusCode::PermanentRedirect => HttpStatusInfo { code: 308, message: "Permanent Redirect" },
-            HttpStatusCode::BadRequest => HttpStatusInfo { code: 400, message: "BadRequest" },
+            HttpStatusCode::BadRequest => HttpStatusInfo { code: 400, message: "Bad Request" },
             HttpStatusCode::Unauthorized => HttpStatusInfo { code: 401, message: "Unauthorized" },
             HttpStatusCode::PaymentRequired => HttpStatusInfo { code: 402, message: "Payment Required" },
             HttpStatusCode::Forbidden => HttpStatusInfo { code: 403, message: "Forbidden" },
-            HttpStatusCode::NotFound => HttpStatusInfo { code: 404, message: "NotFound" },
-            HttpStatusCode::MethodNotAllowed => HttpStatusInfo { code: 405, message: "MethodNotAllowed" },
-            HttpStatusCode::NotAcceptable => HttpStatusInfo { code: 406, message: "NotAcceptable" },
+            HttpStatusCode::NotFound => HttpStatusInfo { code: 404, message: "Not Found" },
+            HttpStatusCode::MethodNotAllowed => HttpStatusInfo { code: 405, message: "Method Not Allowed" },
+            HttpStatusCode::NotAcceptable => HttpStatusInfo { code: 406, message: "Not Acceptable" },
             HttpStatusCode::ProxyAuthenticationRequired => HttpStatusInfo {
                 code: 407,
-                message: "ProxyAuthenticationRequired",
+                message: "Proxy Authentication Required",
             },
-            HttpStatusCode::RequestTimeout => HttpStatusInfo { code: 408, message: "RequestTimeout" },
+            HttpStatusCode::RequestTimeout => HttpStatusInfo { code: 408, message: "Request Timeout" },
             HttpStatusCode::Conflict => HttpStatusInfo { code: 409, message: "Conflict" },
             HttpStatusCode::Gone => HttpStatusInfo { code: 410, message: "Gone" },
-            HttpStatusCode::LengthRequired => HttpStatusInfo { code: 411, message: "LengthRequired" },
-            HttpStatusCode::PreconditionFailed => HttpStatusInfo { code: 412, message: "PreconditionFailed" },
-            HttpStatusCode::PayloadTooLarge => HttpStatusInfo { code: 413, message: "PayloadTooLarge" },
-            HttpStatusCode::URITooLong => HttpStatusInfo { code: 414, message: "URITooLong" },
+            HttpStatusCode::LengthRequired => HttpStatusInfo { code: 411, message: "Length Required" },
+            HttpStatusCode::PreconditionFailed => HttpStatusInfo {
+                code: 412,
+                message: "Precondition Failed",
+            },
+            HttpStatusCode::PayloadTooLarge => HttpStatusInfo { code: 413, message: "Payload Too Large" },
+            HttpStatusCode::URITooLong => HttpStatusInfo { code: 414, message: "URI Too Long" },
             HttpStatusCode::UnsupportedMediaType => HttpStatusInfo {
                 code: 415,
-                message: "UnsupportedMediaType",
+                message: "Unsupported Media Type",
             },
             HttpStatusCode::RangeNotSatisfiable => HttpStatusInfo {
                 code: 416,
-                message: "RangeNotSatisfiable",
+                message: "Range Not Satisfiable",
             },
-            HttpStatusCode::ExpectationFailed => HttpStatusInfo { code: 417, message: "ExpectationFailed" },
+            HttpStatusCode::ExpectationFailed => HttpStatusInfo { code: 417, message: "Expectation Failed" },
             HttpStatusCode::ImATeapot => HttpStatusInfo { code: 418, message: "I'm a Teapot" },
             HttpStatusCode::MisdirectedRequest => HttpStatusInfo {
                 code: 421,
@@ -227,59 +230,62 @@ impl HttpStatusCode {
             },
             HttpStatusCode::UnprocessableEntity => HttpStatusInfo {
                 code: 422,
-                message: "UnprocessableEntity",
+                message: "Unprocessable Entity",
             },
             HttpStatusCode::Locked => HttpStatusInfo { code: 423, message: "Locked" },
-            HttpStatusCode::FailedDependency => HttpStatusInfo { code: 424, message: "FailedDependency" },
-            HttpStatusCode::TooEarly => HttpStatusInfo { code: 425, message: "TooEarly" },
-            HttpStatusCode::UpgradeRequired => HttpStatusInfo { code: 426, message: "UpgradeRequired" },
+            HttpStatusCode::FailedDependency => HttpStatusInfo { code: 424, message: "Failed Dependency" },
+            HttpStatusCode::TooEarly => HttpStatusInfo { code: 425, message: "Too Early" },
+            HttpStatusCode::UpgradeRequired => HttpStatusInfo { code: 426, message: "Upgrade Required" },
             HttpStatusCode::PreconditionRequired => HttpStatusInfo {
                 code: 428,
-                message: "PreconditionRequired",
+                message: "Precondition Required",
             },
-            HttpStatusCode::TooManyRequests => HttpStatusInfo { code: 429, message: "TooManyRequests" },
+            HttpStatusCode::TooManyRequests => HttpStatusInfo { code: 429, message: "Too Many Requests" },
             HttpStatusCode::RequestHeaderFieldsTooLarge => HttpStatusInfo {
                 code: 431,
-                message: "RequestHeaderFieldsTooLarge",
+                message: "Request Header Fields Too Large",
             },
             HttpStatusCode::UnavailableForLegalReasons => HttpStatusInfo {
                 code: 451,
-                message: "UnavailableForLegalReasons",
+                message: "Unavailable For Legal Reasons",
             },
             HttpStatusCode::InternalServerError => HttpStatusInfo {
                 code: 500,
-                message: "InternalServerError",
+                message: "Internal Server Error",
+            },
+            HttpStatusCode::NotImplemented => HttpStatusInfo { code: 501, message: "Not Implemented" },
+            HttpStatusCode::BadGateway => HttpStatusInfo { code: 502, message: "Bad Gateway" },
+            HttpStatusCode::ServiceUnavailable => HttpStatusInfo {
+                code: 503,
+                message: "Service Unavailable",
             },
-            HttpStatusCode::NotImplemented => HttpStatusInfo { code: 501, message: "NotImplemented" },
-            HttpStatusCode::BadGateway => HttpStatusInfo { code: 502, message: "BadGateway" },
-            HttpStatusCode::ServiceUnavailable => HttpStatusInfo { code: 503, message: "ServiceUnavailable" },
-            HttpStatusCode::GatewayTimeout => HttpStatusInfo { code: 504, message: "GatewayTimeout" },
+            HttpStatusCode::GatewayTimeout => HttpStatusInfo { code: 504, message: "Gateway Timeout" },
             HttpStatusCode::HTTPVersionNotSupported => HttpStatusInfo {
                 code: 505,
-                message: "HTTPVersionNotSupported",
+                message: "HTTP Version Not Supported",
             },
             HttpStatusCode::VariantAlsoNegotiates => HttpStatusInfo {
                 code: 506,
-                message: "VariantAlsoNegotiates",
+                message: "Variant Also Negotiates",
             },
             HttpStatusCode::InsufficientStorage => HttpStatusInfo {
                 code: 507,
-                message: "InsufficientStorage",
+                message: "Insufficient Storage",
             },
-            HttpStatusCode::LoopDetected => HttpStatusInfo { code: 508, message: "LoopDetected" },
-            HttpStatusCode::NotExtended => HttpStatusInfo { code: 510, message: "NotExtended" },
+            HttpStatusCode::LoopDetected => HttpStatusInfo { code: 508, message: "Loop Detected" },
+            HttpStatusCode::NotExtended => HttpStatusInfo { code: 510, message: "Not Extended" },
             HttpStatusCode::NetworkAuthenticationRequired => HttpStatusInfo {
                 code: 511,
-                message: "NetworkAuthenticationRequired",
+                message: "Network Authentication Required",
             },
             HttpStatusCode::GoodLuck => HttpStatusInfo { code: 777, message: "Good Luck" },
         }
     }
 }
 
-impl Into<u16> for HttpStatusCode {
-    fn into(self) -> u16 {
-        self as u16
+impl From<HttpStatusCode> for u16 {
+    fn from(val: HttpStatusCode) -> Self {
+        val as u16
     }
 }
 
@@ -0,0 +1,106 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+};
+
+type AnyMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// 型をキーにして値を持たせるマップ
+///
+/// 同じ型を2か所で使っていて、どこから取ったかで寿命が違います
+/// - `req.extensions()`: そのリクエストの間だけ
+///   ミドルウェアからハンドラへ、認証済みユーザーやリクエストIDなどを渡すのに使います
+/// - `req.connection_state()`: 接続が閉じるまで
+///   keep-alive の次のリクエストにも引き継がれるので、接続単位のセッションやカウンタに使います
+///
+/// 何も入れなければメモリを確保しません
+#[derive(Default)]
+pub struct Extensions {
+    map: Option<AnyMap>,
+}
+
+impl Extensions {
+    pub const fn new() -> Self {
+        Extensions { map: None }
+    }
+
+    /// 値を入れる
+    /// 同じ型の値が既にあれば置き換えて、前の値を返します
+    pub fn insert<T>(&mut self, value: T) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .get_or_insert_with(Default::default)
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok().map(|b| *b))
+    }
+
+    #[inline]
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map.as_ref()?.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    #[inline]
+    pub fn get_mut<T>(&mut self) -> Option<&mut T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .as_mut()?
+            .get_mut(&TypeId::of::<T>())?
+            .downcast_mut()
+    }
+
+    #[inline]
+    pub fn remove<T>(&mut self) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .as_mut()?
+            .remove(&TypeId::of::<T>())?
+            .downcast()
+            .ok()
+            .map(|b| *b)
+    }
+
+    #[inline]
+    pub fn contains<T>(&self) -> bool
+    where
+        T: Send + Sync + 'static,
+    {
+        self.map
+            .as_ref()
+            .is_some_and(|m| m.contains_key(&TypeId::of::<T>()))
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        if let Some(map) = self.map.as_mut() {
+            map.clear();
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |m| m.len())
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// CIDR 表記のアドレス範囲 (`10.0.0.0/8`, `fd00::/8`, `127.0.0.1` など)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// プレフィックス長がアドレスの長さを超えていれば None
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        (prefix <= max).then_some(IpNet { addr, prefix })
+    }
+
+    /// アドレス1つだけの範囲
+    pub fn host(addr: IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        IpNet { addr, prefix }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// 範囲に含まれるか (IPv4 射影の IPv6 アドレスは IPv4 として比べます)
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(net), ip) => {
+                let ip = match ip {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            },
+            (IpAddr::V4(_), IpAddr::V6(_)) => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidIpNet(s.to_string());
+        match s.trim().split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+                let prefix = prefix.parse::<u8>().map_err(|_| invalid())?;
+                IpNet::new(addr, prefix).ok_or_else(invalid)
+            },
+            None => s
+                .trim()
+                .parse::<IpAddr>()
+                .map(IpNet::host)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// CIDR 表記として読めなかった
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidIpNet(pub String);
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InvalidIpNet: `{}` is not an address or CIDR", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+/// 信頼するプロキシのアドレス範囲
+///
+/// 直接つないできた相手がこの範囲にあるときだけ `Forwarded` (RFC 7239) か
+/// `X-Forwarded-For` / `X-Forwarded-Proto` / `X-Forwarded-Host` を信じて、本当のクライアントを求めます
+/// 経路は右 (近いプロキシ) から辿り、信頼するプロキシを読み飛ばして最初に出てきたアドレスをクライアントとします
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> Self {
+        TrustedProxies { nets: Vec::new() }
+    }
+
+    /// CIDR 表記の文字列からまとめて作る
+    pub fn parse<I, S>(nets: I) -> Result<Self, InvalidIpNet>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let nets = nets
+            .into_iter()
+            .map(|s| s.as_ref().parse())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies { nets })
+    }
+
+    /// 信頼するアドレス範囲を追加する
+    pub fn trust(mut self, net: IpNet) -> Self {
+        self.nets.push(net);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// 相手のアドレスと転送ヘッダからクライアントを求める
+    /// 相手が信頼するプロキシでないか、転送ヘッダがなければ None
+    ///
+    /// `header` はそのヘッダの値をすべて (出てきた順に) 返すものです
+    pub fn resolve<'a, F, I>(&self, peer: IpAddr, header: F) -> Option<ForwardedInfo>
+    where
+        F: Fn(&'static str) -> I,
+        I: Iterator<Item = &'a str>,
+    {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+        let elements: Vec<ForwardedElement> = header("Forwarded")
+            .flat_map(split_list)
+            .map(ForwardedElement::parse)
+            .collect();
+        if !elements.is_empty() {
+            let chosen = self.choose(elements.iter().map(|e| e.for_ip));
+            let element = &elements[chosen];
+            return Some(ForwardedInfo {
+                ip: element.for_ip,
+                scheme: element.proto.and_then(normalize_scheme),
+                host: element.host.and_then(normalize_host),
+            });
+        }
+
+        let chain: Vec<Option<IpAddr>> = header("X-Forwarded-For")
+            .flat_map(split_list)
+            .map(parse_node)
+            .collect();
+        let protos: Vec<&str> = header("X-Forwarded-Proto").flat_map(split_list).collect();
+        let hosts: Vec<&str> = header("X-Forwarded-Host").flat_map(split_list).collect();
+        if chain.is_empty() && protos.is_empty() && hosts.is_empty() {
+            return None;
+        }
+        let (ip, chosen) = if chain.is_empty() {
+            (Some(peer.to_canonical()), 0)
+        } else {
+            let chosen = self.choose(chain.iter().copied());
+            (chain[chosen], chosen)
+        };
+        // Proto / Host が For と同じ数だけあれば同じ位置のものを、そうでなければいちばん近いプロキシが付けたものを使う
+        let pick = |values: &[&'a str]| {
+            if values.len() == chain.len() {
+                values.get(chosen).copied()
+            } else {
+                values.last().copied()
+            }
+        };
+        Some(ForwardedInfo {
+            ip,
+            scheme: pick(&protos).and_then(normalize_scheme),
+            host: pick(&hosts).and_then(normalize_host),
+        })
+    }
+
+    /// 右から辿って、信頼するプロキシでない最初の位置 (すべて信頼できれば左端)
+    fn choose<I>(&self, chain: I) -> usize
+    where
+        I: DoubleEndedIterator<Item = Option<IpAddr>> + ExactSizeIterator,
+    {
+        let len = chain.len();
+        chain
+            .rev()
+            .position(|ip| !ip.is_some_and(|ip| self.is_trusted(ip)))
+            .map_or(0, |from_right| len - 1 - from_right)
+    }
+}
+
+/// 転送ヘッダから求めたクライアントの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedInfo {
+    ip: Option<IpAddr>,
+    scheme: Option<Box<str>>,
+    host: Option<Box<str>>,
+}
+
+impl ForwardedInfo {
+    /// クライアントのアドレス (`unknown` や難読化された識別子なら None)
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// クライアントが使ったスキーム (小文字)
+    pub fn scheme(&self) -> Option<&str> {
+        self.scheme.as_deref()
+    }
+
+    /// クライアントが送った Host
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+}
+
+struct ForwardedElement<'a> {
+    for_ip: Option<IpAddr>,
+    proto: Option<&'a str>,
+    host: Option<&'a str>,
+}
+
+impl<'a> ForwardedElement<'a> {
+    fn parse(element: &'a str) -> Self {
+        let mut parsed = ForwardedElement { for_ip: None, proto: None, host: None };
+        for pair in element.split(';') {
+            let Some((key, value)) = pair.split_once('=') else {
+                continue;
+            };
+            let value = unquote(value.trim());
+            match key.trim() {
+                k if k.eq_ignore_ascii_case("for") => parsed.for_ip = parse_node(value),
+                k if k.eq_ignore_ascii_case("proto") => parsed.proto = Some(value),
+                k if k.eq_ignore_ascii_case("host") => parsed.host = Some(value),
+                _ => {},
+            }
+        }
+        parsed
+    }
+}
+
+/// `,` 区切りのリスト (引用符の中の `,` は区切りにしない)
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    let mut in_quote = false;
+    value
+        .split(move |c: char| {
+            if c == '"' {
+                in_quote = !in_quote;
+            }
+            c == ',' && !in_quote
+        })
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// `192.0.2.1`, `192.0.2.1:4711`, `[2001:db8::1]:4711`, `2001:db8::1` を読む
+/// `unknown` や `_hidden` のような識別子は None
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = unquote(node.trim());
+    if let Some(rest) = node.strip_prefix('[') {
+        let (addr, _) = rest.split_once(']')?;
+        return addr.parse::<IpAddr>().ok().map(|ip| ip.to_canonical());
+    }
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+fn normalize_scheme(scheme: &str) -> Option<Box<str>> {
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    valid.then(|| scheme.to_ascii_lowercase().into_boxed_str())
+}
+
+fn normalize_host(host: &str) -> Option<Box<str>> {
+    let valid = !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !matches!(b, b'/' | b'\\' | b'@' | b'?' | b'#' | b'"'));
+    valid.then(|| host.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn trusted(nets: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(nets).unwrap()
+    }
+
+    #[test]
+    fn ipnet_parse() {
+        assert_eq!(net("10.0.0.0/8"), IpNet::new(ip("10.0.0.0"), 8).unwrap());
+        assert_eq!(net(" 127.0.0.1 "), IpNet::host(ip("127.0.0.1")));
+        assert_eq!(net("::1").prefix(), 128);
+        assert_eq!(net("fd00::/8").to_string(), "fd00::/8");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("::/129".parse::<IpNet>().is_err());
+        assert!("10.0.0.0/".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn ipnet_contains_v4() {
+        let n = net("10.1.0.0/16");
+        assert!(n.contains(ip("10.1.0.0")));
+        assert!(n.contains(ip("10.1.255.255")));
+        assert!(!n.contains(ip("10.2.0.0")));
+        assert!(!n.contains(ip("::a01:0")));
+        // IPv4 射影アドレスは IPv4 として比べる
+        assert!(n.contains(ip("::ffff:10.1.2.3")));
+
+        assert!(net("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!net("192.0.2.1").contains(ip("192.0.2.2")));
+        assert!(net("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(!net("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipnet_contains_v6() {
+        let n = net("fd00::/8");
+        assert!(n.contains(ip("fd12:3456::1")));
+        assert!(!n.contains(ip("fe80::1")));
+        assert!(!n.contains(ip("10.0.0.1")));
+
+        assert!(net("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!net("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert!(net("::/0").contains(ip("2001:db8::1")));
+        assert!(net("::/0").contains(ip("192.0.2.1")));
+        assert!(net("::ffff:0:0/96").contains(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn choose_from_right() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let chain = |ips: &[Option<&str>]| -> Vec<Option<IpAddr>> { ips.iter().map(|s| s.map(ip)).collect() };
+        // いちばん右の信頼できないアドレス
+        let c = chain(&[Some("198.51.100.1"), Some("203.0.113.7"), Some("10.0.0.2"), Some("10.0.0.1")]);
+        assert_eq!(proxies.choose(c.into_iter()), 1);
+        // 右端が信頼できなければ右端
+        let c = chain(&[Some("10.0.0.2"), Some("203.0.113.7")]);
+        assert_eq!(proxies.choose(c.into_iter()), 1);
+        // 読めないアドレスは信頼しない
+        let c = chain(&[Some("203.0.113.7"), None, Some("10.0.0.1")]);
+        assert_eq!(proxies.choose(c.into_iter()), 1);
+        // すべて信頼できれば左端
+        let c = chain(&[Some("10.0.0.3"), Some("10.0.0.2")]);
+        assert_eq!(proxies.choose(c.into_iter()), 0);
+        assert_eq!(proxies.choose(Vec::new().into_iter()), 0);
+    }
+
+    fn resolve(
+        proxies: &TrustedProxies,
+        peer: &str,
+        headers: &[(&'static str, &'static str)],
+    ) -> Option<ForwardedInfo> {
+        proxies.resolve(ip(peer), |name| {
+            headers
+                .iter()
+                .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| *v)
+        })
+    }
+
+    #[test]
+    fn resolve_untrusted_peer() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = [("X-Forwarded-For", "198.51.100.1")];
+        assert_eq!(resolve(&proxies, "203.0.113.7", &headers), None);
+        assert_eq!(resolve(&proxies, "10.0.0.1", &[]), None);
+    }
+
+    #[test]
+    fn resolve_forwarded() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = [
+            (
+                "Forwarded",
+                r#"for=198.51.100.1;proto=HTTPS;host=example.com"#,
+            ),
+            (
+                "Forwarded",
+                r#"for="[2001:db8::1]:4711";proto=http, for=10.0.0.2"#,
+            ),
+            ("X-Forwarded-For", "192.0.2.99"),
+        ];
+        let info = resolve(&proxies, "10.0.0.1", &headers).unwrap();
+        assert_eq!(info.ip(), Some(ip("2001:db8::1")));
+        assert_eq!(info.scheme(), Some("http"));
+        assert_eq!(info.host(), None);
+
+        let info = resolve(
+            &trusted(&["10.0.0.0/8", "2001:db8::/32"]),
+            "10.0.0.1",
+            &headers,
+        )
+        .unwrap();
+        assert_eq!(info.ip(), Some(ip("198.51.100.1")));
+        assert_eq!(info.scheme(), Some("https"));
+        assert_eq!(info.host(), Some("example.com"));
+
+        let info = resolve(&proxies, "10.0.0.1", &[("Forwarded", "for=_hidden")]).unwrap();
+        assert_eq!(info.ip(), None);
+    }
+
+    #[test]
+    fn resolve_x_forwarded() {
+        let proxies = trusted(&["10.0.0.0/8"]);
+        let headers = [
+            ("X-Forwarded-For", "198.51.100.1, 203.0.113.7"),
+            ("X-Forwarded-For", "10.0.0.2"),
+            ("X-Forwarded-Proto", "https, http, http"),
+            ("X-Forwarded-Host", "a.example, b.example"),
+        ];
+        let info = resolve(&proxies, "10.0.0.1", &headers).unwrap();
+        assert_eq!(info.ip(), Some(ip("203.0.113.7")));
+        // For と同じ数なら同じ位置、違えば右端
+        assert_eq!(info.scheme(), Some("http"));
+        assert_eq!(info.host(), Some("b.example"));
+
+        let info = resolve(
+            &proxies,
+            "::ffff:10.0.0.1",
+            &[("X-Forwarded-Proto", "https")],
+        )
+        .unwrap();
+        assert_eq!(info.ip(), Some(ip("10.0.0.1")));
+        assert_eq!(info.scheme(), Some("https"));
+
+        let headers = [("X-Forwarded-For", "unknown"), ("X-Forwarded-Host", "evil/host")];
+        let info = resolve(&proxies, "10.0.0.1", &headers).unwrap();
+        assert_eq!(info.ip(), None);
+        assert_eq!(info.host(), None);
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(parse_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("192.0.2.1:4711"), Some(ip("192.0.2.1")));
+        assert_eq!(
+            parse_node("\"[2001:db8::1]:4711\""),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("::ffff:192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("[2001:db8::1"), None);
+        assert_eq!(
+            split_list(r#"for="a,b", for=c"#).collect::<Vec<_>>(),
+            [r#"for="a,b""#, "for=c"]
+        );
+    }
+}
@@ -6,6 +6,9 @@ use std::string::String;
 use futures_io::AsyncRead;
 use futures_util::AsyncReadExt;
 
+use crate::http::{code::HttpStatusCode, limits::HttpLimits};
+
+/// `HttpLimits` の既定値
 pub const MAX_HEADER_BYTES: usize = 32 * 1024;
 pub const MAX_HEADERS: usize = 128;
 
@@ -63,6 +66,12 @@ struct HeaderEntry {
     line: Range<usize>,
 }
 
+impl Default for HttpHeader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpHeader {
     pub fn new() -> Self {
         HttpHeader { headers: Vec::with_capacity(32) }
@@ -104,6 +113,36 @@ impl HttpHeader {
         });
     }
 
+    /// buf の `at` の位置にヘッダ行を挿入する
+    /// `at` より後ろにヘッダ行がない (ボディのみ) 前提です
+    pub fn insert_at<K>(&mut self, key: K, value: String, buf: &mut Vec<u8>, at: usize)
+    where
+        K: Into<String>,
+    {
+        // 後ろのヘッダ行の位置はずらさないので、ずれるものがあってはいけない
+        debug_assert!(
+            self.headers.iter().all(|h| h.line.end <= at),
+            "insert_at: a header line follows `at`"
+        );
+        let key_str = key.into();
+        let mut line = Vec::with_capacity(key_str.len() + value.len() + 4);
+        line.extend_from_slice(key_str.as_bytes());
+        line.push(b':');
+        line.push(b' ');
+        line.extend_from_slice(value.as_bytes());
+        line.push(b'\r');
+        line.push(b'\n');
+
+        let line_len = line.len();
+        buf.splice(at..at, line);
+
+        self.headers.push(HeaderEntry {
+            key: at..at + key_str.len(),
+            value: at + key_str.len() + 1..at + line_len - 2,
+            line: at..at + line_len,
+        });
+    }
+
     #[inline(always)]
     pub fn get<'a, S>(&self, key: S, buf: &'a [u8]) -> Option<&'a [u8]>
     where
@@ -118,6 +157,15 @@ impl HttpHeader {
         None
     }
 
+    /// 同じ名前のヘッダの値をすべて、出てきた順に返す
+    #[inline(always)]
+    pub fn get_all<'a, 'k>(&'a self, key: &'k str, buf: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + use<'a, 'k> {
+        self.headers
+            .iter()
+            .filter(move |h| slice_by_range(buf, &h.key).eq_ignore_ascii_case(key.as_bytes()))
+            .map(move |h| slice_by_range(buf, &h.value))
+    }
+
     #[inline(always)]
     pub fn remove<S>(&mut self, key: S, buf: &mut Vec<u8>)
     where
@@ -176,24 +224,44 @@ impl HttpHeader {
     where
         R: AsyncRead + Unpin,
     {
+        Self::parse_async_with_limits(reader, buf, start, &HttpLimits::new())
+            .await
+            .ok()
+    }
+
+    /// 上限を超えたら 431、壊れていたら 400 を返します
+    pub async fn parse_async_with_limits<R>(
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+        start: usize,
+        limits: &HttpLimits,
+    ) -> Result<(HttpHeader, usize), HttpStatusCode>
+    where
+        R: AsyncRead + Unpin,
+    {
+        const BAD: HttpStatusCode = HttpStatusCode::BadRequest;
+        const TOO_LARGE: HttpStatusCode = HttpStatusCode::RequestHeaderFieldsTooLarge;
         // まずはヘッダ終端を探しつつ buf に追記
         let header_end = loop {
             // 既存の追記済み領域で終端が見つかるかチェック
             if let Some(end) = find_header_end(buf, start) {
+                if end - start > limits.max_header_bytes {
+                    return Err(TOO_LARGE);
+                }
                 break end;
             }
 
             // まだなら追加で読む
             let mut tmp = [0u8; 4096];
-            let n = reader.read(&mut tmp).await.ok()?;
+            let n = reader.read(&mut tmp).await.map_err(|_| BAD)?;
             if n == 0 {
-                return None; // EOF
+                return Err(BAD); // EOF
             }
             buf.extend_from_slice(&tmp[..n]);
 
             // サイズ制限（start以降の増分だけをカウント）
-            if buf.len() - start > MAX_HEADER_BYTES {
-                return None;
+            if buf.len() - start > limits.max_header_bytes {
+                return Err(TOO_LARGE);
             }
         };
 
@@ -214,7 +282,7 @@ impl HttpHeader {
             }
             if line_end >= header_end {
                 // ヘッダ終端は見つかっているので、ここに来るなら壊れてる
-                return None;
+                return Err(BAD);
             }
             line_end += 1; // include '\n'
 
@@ -228,8 +296,8 @@ impl HttpHeader {
             }
 
             header_lines += 1;
-            if header_lines > MAX_HEADERS {
-                return None;
+            if header_lines > limits.max_headers {
+                return Err(TOO_LARGE);
             }
 
             // 末尾の LF/CRLF を除いた content 範囲
@@ -245,7 +313,7 @@ impl HttpHeader {
                 colon += 1;
             }
             if colon == content.end {
-                return None;
+                return Err(BAD);
             }
 
             let key_range = trim_ascii_range(buf, content.start..colon);
@@ -258,6 +326,115 @@ impl HttpHeader {
             });
         }
 
-        Some((header, body_start))
+        Ok((header, body_start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn parse(raw: &[u8], limits: &HttpLimits) -> Result<(HttpHeader, Vec<u8>, usize), HttpStatusCode> {
+        let mut reader = raw;
+        let mut buf = Vec::new();
+        let (header, body_start) = HttpHeader::parse_async_with_limits(&mut reader, &mut buf, 0, limits)
+            .now_or_never()
+            .unwrap()?;
+        Ok((header, buf, body_start))
+    }
+
+    fn limits(max_header_bytes: usize, max_headers: usize) -> HttpLimits {
+        HttpLimits {
+            max_header_bytes,
+            max_headers,
+            ..HttpLimits::new()
+        }
+    }
+
+    #[test]
+    fn parses_headers_and_finds_the_body() {
+        let raw = b"Host: example.com\r\nX-Empty:\r\nAccept:  text/html \r\n\r\nbody";
+        let (header, buf, body_start) = parse(raw, &HttpLimits::new()).unwrap();
+        assert_eq!(header.get("host", &buf), Some(&b"example.com"[..]));
+        assert_eq!(header.get("X-Empty", &buf), Some(&b""[..]));
+        assert_eq!(header.get("Accept", &buf), Some(&b"text/html"[..]));
+        assert_eq!(&buf[body_start..], b"body");
+    }
+
+    #[test]
+    fn header_bytes_over_the_limit_is_431() {
+        let raw = b"X-Long: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+        assert!(parse(raw, &limits(raw.len(), 128)).is_ok());
+        assert_eq!(
+            parse(raw, &limits(raw.len() - 1, 128)).err(),
+            Some(HttpStatusCode::RequestHeaderFieldsTooLarge)
+        );
+
+        // 終端が来ないまま上限を超えても 431
+        let endless = vec![b'a'; 10_000];
+        assert_eq!(
+            parse(&endless, &limits(4096, 128)).err(),
+            Some(HttpStatusCode::RequestHeaderFieldsTooLarge)
+        );
+    }
+
+    #[test]
+    fn header_count_over_the_limit_is_431() {
+        let raw: Vec<u8> = (0..4)
+            .flat_map(|i| format!("X-{}: {}\r\n", i, i).into_bytes())
+            .chain(*b"\r\n")
+            .collect();
+        assert!(parse(&raw, &limits(1024, 4)).is_ok());
+        assert_eq!(
+            parse(&raw, &limits(1024, 3)).err(),
+            Some(HttpStatusCode::RequestHeaderFieldsTooLarge)
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_400() {
+        assert_eq!(
+            parse(b"no colon here\r\n\r\n", &HttpLimits::new()).err(),
+            Some(HttpStatusCode::BadRequest)
+        );
+        // 終端の前に接続が閉じた
+        assert_eq!(
+            parse(b"Host: example.com\r\n", &HttpLimits::new()).err(),
+            Some(HttpStatusCode::BadRequest)
+        );
+    }
+
+    #[test]
+    fn insert_at_before_the_body() {
+        let mut buf = Vec::new();
+        let mut header = HttpHeader::new();
+        header.insert("Content-Type", "text/plain".to_string(), &mut buf);
+        buf.extend_from_slice(b"\r\nhello");
+        let at = buf.len() - b"\r\nhello".len();
+        header.insert_at("Content-Length", "5".to_string(), &mut buf, at);
+        assert_eq!(
+            buf,
+            b"Content-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello"
+        );
+        assert_eq!(
+            header.get("content-length", &buf).map(<[u8]>::trim_ascii),
+            Some(&b"5"[..])
+        );
+        assert_eq!(
+            header.get("content-type", &buf).map(<[u8]>::trim_ascii),
+            Some(&b"text/plain"[..])
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a header line follows `at`")]
+    fn insert_at_before_a_header_line_panics() {
+        let mut buf = Vec::new();
+        let mut header = HttpHeader::new();
+        header.insert("Content-Type", "text/plain".to_string(), &mut buf);
+        header.insert_at("Content-Length", "5".to_string(), &mut buf, 0);
     }
 }
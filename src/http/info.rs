@@ -0,0 +1,290 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::http::proxy_protocol::ProxyHeader;
+
+/// 接続そのものの情報
+///
+/// 接続を受け付けたときにサーバー (や独自のトランスポート) が作り、keep-alive の間ずっと引き継ぎます
+/// ハンドラからは `conn.peer_addr()` や `conn.connection_info()` で見えます
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    listener_addr: Option<SocketAddr>,
+    listener_name: Option<Arc<str>>,
+    proxy_header: Option<Arc<ProxyHeader>>,
+    tls: Option<Arc<TlsInfo>>,
+}
+
+impl ConnectionInfo {
+    pub const fn new() -> Self {
+        ConnectionInfo {
+            peer_addr: None,
+            local_addr: None,
+            listener_addr: None,
+            listener_name: None,
+            proxy_header: None,
+            tls: None,
+        }
+    }
+
+    /// 相手のアドレス
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = Some(addr);
+        self
+    }
+
+    /// この接続を受けたこちら側のアドレス
+    pub fn with_local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// 受け付けたリスナーがバインドしているアドレス
+    /// `0.0.0.0` でバインドしていればそのままで、実際に受けたアドレスは `local_addr` です
+    pub fn with_listener_addr(mut self, addr: SocketAddr) -> Self {
+        self.listener_addr = Some(addr);
+        self
+    }
+
+    /// 受け付けたリスナーの名前
+    /// 複数のリスナーで同じルーターを使うときに、どこから来たか見分けるのに使います
+    pub fn with_listener_name<S>(mut self, name: S) -> Self
+    where
+        S: Into<Arc<str>>,
+    {
+        self.listener_name = Some(name.into());
+        self
+    }
+
+    /// PROXY protocol のヘッダ
+    /// クライアントのアドレスが書かれていれば、相手のアドレスとこちら側のアドレスもそれに置き換えます
+    pub fn with_proxy_header(mut self, header: ProxyHeader) -> Self {
+        if let Some(source) = header.source() {
+            self.peer_addr = Some(source);
+            self.local_addr = header.destination();
+        }
+        self.proxy_header = Some(Arc::new(header));
+        self
+    }
+
+    /// TLS で受けた接続なら、交渉の結果
+    pub fn with_tls(mut self, tls: TlsInfo) -> Self {
+        self.tls = Some(Arc::new(tls));
+        self
+    }
+
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    #[inline]
+    pub fn listener_addr(&self) -> Option<SocketAddr> {
+        self.listener_addr
+    }
+
+    #[inline]
+    pub fn listener_name(&self) -> Option<&str> {
+        self.listener_name.as_deref()
+    }
+
+    /// PROXY protocol で受け取ったヘッダ (TLV もここから見えます)
+    #[inline]
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.as_deref()
+    }
+
+    /// TLS で受けた接続なら、交渉の結果
+    #[inline]
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_deref()
+    }
+}
+
+/// TLS の交渉の結果
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    alpn_protocol: Option<Box<[u8]>>,
+    server_name: Option<Box<str>>,
+    protocol_version: Option<&'static str>,
+    cipher_suite: Option<&'static str>,
+    peer_certificates: Vec<PeerCertificate>,
+}
+
+impl TlsInfo {
+    pub const fn new() -> Self {
+        TlsInfo {
+            alpn_protocol: None,
+            server_name: None,
+            protocol_version: None,
+            cipher_suite: None,
+            peer_certificates: Vec::new(),
+        }
+    }
+
+    pub fn with_alpn_protocol(mut self, protocol: &[u8]) -> Self {
+        self.alpn_protocol = Some(protocol.into());
+        self
+    }
+
+    pub fn with_server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    pub fn with_protocol_version(mut self, version: &'static str) -> Self {
+        self.protocol_version = Some(version);
+        self
+    }
+
+    pub fn with_cipher_suite(mut self, suite: &'static str) -> Self {
+        self.cipher_suite = Some(suite);
+        self
+    }
+
+    /// 検証済みのクライアント証明書のチェーン (先頭がクライアント自身)
+    pub fn with_peer_certificates(mut self, chain: Vec<PeerCertificate>) -> Self {
+        self.peer_certificates = chain;
+        self
+    }
+
+    /// ALPN で決まったプロトコル (`http/1.1` など)
+    #[inline]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    /// クライアントが SNI で指定したホスト名
+    #[inline]
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// `TLSv1_3` など
+    #[inline]
+    pub fn protocol_version(&self) -> Option<&'static str> {
+        self.protocol_version
+    }
+
+    /// `TLS13_AES_128_GCM_SHA256` など
+    #[inline]
+    pub fn cipher_suite(&self) -> Option<&'static str> {
+        self.cipher_suite
+    }
+
+    /// 検証済みのクライアント証明書のチェーン (先頭がクライアント自身)
+    /// クライアント認証をしていないか、証明書が送られてこなければ空です
+    #[inline]
+    pub fn peer_certificates(&self) -> &[PeerCertificate] {
+        &self.peer_certificates
+    }
+
+    /// 検証済みのクライアント証明書
+    #[inline]
+    pub fn peer_certificate(&self) -> Option<&PeerCertificate> {
+        self.peer_certificates.first()
+    }
+}
+
+/// クライアント証明書
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    der: Box<[u8]>,
+    fingerprint: [u8; 32],
+    subject: Box<str>,
+    common_name: Option<Box<str>>,
+    subject_alt_names: Vec<SubjectAltName>,
+}
+
+impl PeerCertificate {
+    /// DER と、その SHA-256 のハッシュから作る
+    pub fn new(der: &[u8], fingerprint: [u8; 32]) -> Self {
+        PeerCertificate {
+            der: der.into(),
+            fingerprint,
+            subject: "".into(),
+            common_name: None,
+            subject_alt_names: Vec::new(),
+        }
+    }
+
+    pub fn with_subject(mut self, subject: &str) -> Self {
+        self.subject = subject.into();
+        self
+    }
+
+    pub fn with_common_name(mut self, name: &str) -> Self {
+        self.common_name = Some(name.into());
+        self
+    }
+
+    pub fn with_subject_alt_names(mut self, names: Vec<SubjectAltName>) -> Self {
+        self.subject_alt_names = names;
+        self
+    }
+
+    /// 証明書そのもの (DER)
+    #[inline]
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// DER の SHA-256
+    #[inline]
+    pub fn fingerprint_sha256(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    /// DER の SHA-256 (小文字の16進、区切りなし)
+    /// 証明書を固定で許可するときの比較に使えます
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// RFC 4514 の形の Subject (`CN=client,O=Example` など)
+    #[inline]
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Subject の CN (いくつかあれば最後のもの)
+    #[inline]
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// Subject Alternative Name
+    #[inline]
+    pub fn subject_alt_names(&self) -> &[SubjectAltName] {
+        &self.subject_alt_names
+    }
+
+    /// SAN の DNS 名
+    pub fn dns_names(&self) -> impl Iterator<Item = &str> {
+        self.subject_alt_names.iter().filter_map(|name| match name {
+            SubjectAltName::Dns(dns) => Some(&**dns),
+            _ => None,
+        })
+    }
+}
+
+/// Subject Alternative Name のうち、kurosabi が読むもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(Box<str>),
+    Email(Box<str>),
+    Uri(Box<str>),
+    Ip(IpAddr),
+}
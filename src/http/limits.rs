@@ -0,0 +1,43 @@
+use crate::http::header::{MAX_HEADER_BYTES, MAX_HEADERS};
+
+/// リクエストの大きさの上限
+///
+/// 超えたリクエストには 400 ではなく、それぞれに合ったステータスコードを返します
+/// - リクエストライン、URI のセグメント数: 414
+/// - ヘッダのバイト数、ヘッダの数: 431
+/// - ボディ (読み込むとき): 413
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpLimits {
+    /// リクエストラインのバイト数 (改行を含む)
+    pub max_request_line: usize,
+    /// ヘッダ全体のバイト数
+    pub max_header_bytes: usize,
+    /// ヘッダの数
+    pub max_headers: usize,
+    /// `read_body_bytes` などでメモリに読み込むボディのバイト数
+    pub max_body: usize,
+    /// パスの `/` 区切りのセグメント数
+    pub max_uri_segments: usize,
+}
+
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+pub const DEFAULT_MAX_BODY: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_URI_SEGMENTS: usize = 64;
+
+impl HttpLimits {
+    pub const fn new() -> Self {
+        HttpLimits {
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            max_header_bytes: MAX_HEADER_BYTES,
+            max_headers: MAX_HEADERS,
+            max_body: DEFAULT_MAX_BODY,
+            max_uri_segments: DEFAULT_MAX_URI_SEGMENTS,
+        }
+    }
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,14 +1,24 @@
 // mod http では http 関連の定義、機能が実装されます
 pub mod code;
+pub mod extensions;
+pub mod forwarded;
 pub mod header;
+pub mod info;
+pub mod limits;
 pub mod method;
+pub mod proxy_protocol;
 pub mod request;
 pub mod response;
 pub mod version;
 
 pub use code::HttpStatusCode;
+pub use extensions::Extensions;
+pub use forwarded::{ForwardedInfo, IpNet, TrustedProxies};
 pub use header::HttpHeader;
+pub use info::{ConnectionInfo, PeerCertificate, SubjectAltName, TlsInfo};
+pub use limits::HttpLimits;
 pub use method::HttpMethod;
+pub use proxy_protocol::{ProxyHeader, ProxyProtocol};
 pub use request::HttpRequest;
 pub use response::HttpResponse;
 pub use version::HttpVersion;
@@ -0,0 +1,541 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use futures_io::AsyncRead;
+use futures_util::AsyncReadExt;
+
+/// PROXY protocol v2 の先頭12バイト
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// v1 のヘッダの最大長 (CRLF を含む)
+const V1_MAX_LEN: usize = 107;
+
+/// PROXY ヘッダを待つかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyProtocol {
+    /// 読まない (既定)
+    #[default]
+    Disabled,
+    /// あれば読み、なければそのまま HTTP として扱う
+    /// 仕様では推奨されないので、移行中だけ使ってください
+    Optional,
+    /// なければ接続を閉じる
+    Required,
+}
+
+/// v2 のコマンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyCommand {
+    /// プロキシ自身からの接続 (ヘルスチェックなど)。アドレスは使いません
+    Local,
+    /// 中継された接続
+    Proxy,
+}
+
+/// v2 の TLV
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyTlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+impl ProxyTlv {
+    pub const ALPN: u8 = 0x01;
+    pub const AUTHORITY: u8 = 0x02;
+    pub const CRC32C: u8 = 0x03;
+    pub const NOOP: u8 = 0x04;
+    pub const UNIQUE_ID: u8 = 0x05;
+    pub const SSL: u8 = 0x20;
+    pub const NETNS: u8 = 0x30;
+}
+
+/// 読み取った PROXY ヘッダ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    version: u8,
+    command: ProxyCommand,
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+    tlvs: Vec<ProxyTlv>,
+}
+
+impl ProxyHeader {
+    /// 1 か 2
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// v1 は常に `Proxy`
+    pub fn command(&self) -> ProxyCommand {
+        self.command
+    }
+
+    /// 本当のクライアントのアドレス (`UNKNOWN` や `LOCAL`、UNIX ソケットなら None)
+    pub fn source(&self) -> Option<SocketAddr> {
+        self.source
+    }
+
+    /// クライアントがつないだ先 (プロキシ側) のアドレス
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.destination
+    }
+
+    /// v2 の TLV (v1 では空)
+    pub fn tlvs(&self) -> &[ProxyTlv] {
+        &self.tlvs
+    }
+
+    /// 種類が一致する最初の TLV の値
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|tlv| tlv.kind == kind)
+            .map(|tlv| tlv.value.as_slice())
+    }
+
+    /// クライアントが TLS の SNI などで指定したホスト名 (`PP2_TYPE_AUTHORITY`)
+    pub fn authority(&self) -> Option<&str> {
+        self.tlv(ProxyTlv::AUTHORITY)
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// 接続を読み進めて PROXY ヘッダを読む
+    ///
+    /// ヘッダの後ろまで読んでしまったバイト列も一緒に返すので、HTTP のパースに渡してください
+    /// `Optional` でヘッダがなかったときは None と、読んだバイト列をそのまま返します
+    pub async fn read<R>(reader: &mut R, mode: ProxyProtocol) -> io::Result<(Option<ProxyHeader>, Vec<u8>)>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = Vec::with_capacity(256);
+        if mode == ProxyProtocol::Disabled {
+            return Ok((None, buf));
+        }
+        let mut chunk = [0u8; 512];
+        loop {
+            match parse(&buf)? {
+                Parsed::Header(header, len) => {
+                    buf.drain(..len);
+                    return Ok((Some(header), buf));
+                },
+                Parsed::NotProxy if mode == ProxyProtocol::Optional => return Ok((None, buf)),
+                Parsed::NotProxy => return Err(invalid("missing PROXY protocol header")),
+                Parsed::Incomplete => {},
+            }
+            let n = reader.read(&mut chunk).await?;
+            if n == 0 {
+                if mode == ProxyProtocol::Optional && !buf.is_empty() {
+                    return Ok((None, buf));
+                }
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before PROXY protocol header",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+enum Parsed {
+    Header(ProxyHeader, usize),
+    NotProxy,
+    Incomplete,
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse(buf: &[u8]) -> io::Result<Parsed> {
+    let head = &buf[..buf.len().min(V2_SIGNATURE.len())];
+    if V2_SIGNATURE.starts_with(head) {
+        if head.len() < V2_SIGNATURE.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        return parse_v2(buf);
+    }
+    let head = &buf[..buf.len().min(V1_PREFIX.len())];
+    if V1_PREFIX.starts_with(head) {
+        if head.len() < V1_PREFIX.len() {
+            return Ok(Parsed::Incomplete);
+        }
+        return parse_v1(buf);
+    }
+    Ok(Parsed::NotProxy)
+}
+
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`
+fn parse_v1(buf: &[u8]) -> io::Result<Parsed> {
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searched.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        return Ok(Parsed::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut parts = line.split(' ');
+    let (source, destination) = match parts.next() {
+        Some("UNKNOWN") => (None, None),
+        Some(family @ ("TCP4" | "TCP6")) => {
+            let mut next = || {
+                parts
+                    .next()
+                    .ok_or_else(|| invalid("PROXY v1 header too short"))
+            };
+            let (src, dst, sport, dport) = (next()?, next()?, next()?, next()?);
+            let parse_ip = |s: &str| -> io::Result<IpAddr> {
+                let ip = match family {
+                    "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => s.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| invalid("PROXY v1 header has an invalid address"))
+            };
+            let parse_port = |s: &str| {
+                s.parse::<u16>()
+                    .map_err(|_| invalid("PROXY v1 header has an invalid port"))
+            };
+            if parts.next().is_some() {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            (
+                Some(SocketAddr::new(parse_ip(src)?, parse_port(sport)?)),
+                Some(SocketAddr::new(parse_ip(dst)?, parse_port(dport)?)),
+            )
+        },
+        _ => return Err(invalid("PROXY v1 header has an unknown protocol")),
+    };
+    let header = ProxyHeader {
+        version: 1,
+        command: ProxyCommand::Proxy,
+        source,
+        destination,
+        tlvs: Vec::new(),
+    };
+    Ok(Parsed::Header(header, end + 2))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.len() < 16 {
+        return Ok(Parsed::Incomplete);
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(invalid("PROXY v2 header has an unknown version"));
+    }
+    let command = match buf[12] & 0x0f {
+        0 => ProxyCommand::Local,
+        1 => ProxyCommand::Proxy,
+        _ => return Err(invalid("PROXY v2 header has an unknown command")),
+    };
+    let body = &buf[16..len];
+    let (addrs, rest) = match buf[13] >> 4 {
+        // AF_UNSPEC
+        0 => (None, body),
+        // AF_INET
+        1 => {
+            let (a, rest) = body
+                .split_at_checked(12)
+                .ok_or_else(|| invalid("PROXY v2 header too short"))?;
+            let src = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let dst = Ipv4Addr::new(a[4], a[5], a[6], a[7]);
+            let ports = (
+                u16::from_be_bytes([a[8], a[9]]),
+                u16::from_be_bytes([a[10], a[11]]),
+            );
+            (Some((IpAddr::V4(src), IpAddr::V4(dst), ports)), rest)
+        },
+        // AF_INET6
+        2 => {
+            let (a, rest) = body
+                .split_at_checked(36)
+                .ok_or_else(|| invalid("PROXY v2 header too short"))?;
+            let src: [u8; 16] = a[..16].try_into().unwrap_or_default();
+            let dst: [u8; 16] = a[16..32].try_into().unwrap_or_default();
+            let ports = (
+                u16::from_be_bytes([a[32], a[33]]),
+                u16::from_be_bytes([a[34], a[35]]),
+            );
+            (Some((IpAddr::from(src), IpAddr::from(dst), ports)), rest)
+        },
+        // AF_UNIX はアドレスとして扱わない
+        3 => {
+            let (_, rest) = body
+                .split_at_checked(216)
+                .ok_or_else(|| invalid("PROXY v2 header too short"))?;
+            (None, rest)
+        },
+        _ => return Err(invalid("PROXY v2 header has an unknown address family")),
+    };
+    let (source, destination) = match (command, addrs) {
+        (ProxyCommand::Proxy, Some((src, dst, (sport, dport)))) => (
+            Some(SocketAddr::new(src, sport)),
+            Some(SocketAddr::new(dst, dport)),
+        ),
+        _ => (None, None),
+    };
+    let header = ProxyHeader {
+        version: 2,
+        command,
+        source,
+        destination,
+        tlvs: parse_tlvs(rest)?,
+    };
+    Ok(Parsed::Header(header, len))
+}
+
+fn parse_tlvs(mut rest: &[u8]) -> io::Result<Vec<ProxyTlv>> {
+    let mut tlvs = Vec::new();
+    while !rest.is_empty() {
+        let [kind, hi, lo, ..] = *rest else {
+            return Err(invalid("PROXY v2 TLV is truncated"));
+        };
+        let len = u16::from_be_bytes([hi, lo]) as usize;
+        let value = rest
+            .get(3..3 + len)
+            .ok_or_else(|| invalid("PROXY v2 TLV is truncated"))?;
+        tlvs.push(ProxyTlv { kind, value: value.to_vec() });
+        rest = &rest[3 + len..];
+    }
+    Ok(tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn header(buf: &[u8]) -> (ProxyHeader, usize) {
+        match parse(buf).unwrap() {
+            Parsed::Header(header, len) => (header, len),
+            Parsed::NotProxy => panic!("not proxy"),
+            Parsed::Incomplete => panic!("incomplete"),
+        }
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | command);
+        buf.push(family << 4 | 1);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buf = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (h, len) = header(buf);
+        assert_eq!(len, 42);
+        assert_eq!(h.version(), 1);
+        assert_eq!(h.command(), ProxyCommand::Proxy);
+        assert_eq!(h.source(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(h.destination(), Some("192.0.2.2:443".parse().unwrap()));
+        assert!(h.tlvs().is_empty());
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let (h, _) = header(b"PROXY TCP6 2001:db8::1 ::1 56324 443\r\n");
+        assert_eq!(h.source(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(h.destination(), Some("[::1]:443".parse().unwrap()));
+        // TCP6 に v4 のアドレスは通さない
+        assert!(parse(b"PROXY TCP6 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let (h, len) = header(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n");
+        assert_eq!(len, 35);
+        assert_eq!(h.source(), None);
+        assert_eq!(h.destination(), None);
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert!(parse(b"PROXY UDP4 192.0.2.1 192.0.2.2 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1 2 3\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 192.0.2.2 1 65536\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let mut buf = b"PROXY UNKNOWN ".to_vec();
+        buf.resize(V1_MAX_LEN, b'a');
+        assert!(parse(&buf[..V1_MAX_LEN - 1]).is_ok_and(|p| matches!(p, Parsed::Incomplete)));
+        assert!(parse(&buf).is_err());
+        // 107 バイトちょうどで CRLF が終わるなら通す
+        buf.truncate(V1_MAX_LEN - 2);
+        buf.extend_from_slice(b"\r\n");
+        assert_eq!(header(&buf).1, V1_MAX_LEN);
+    }
+
+    #[test]
+    fn incomplete_prefix() {
+        assert!(matches!(parse(b"").unwrap(), Parsed::Incomplete));
+        assert!(matches!(parse(b"PRO").unwrap(), Parsed::Incomplete));
+        assert!(matches!(
+            parse(&V2_SIGNATURE[..5]).unwrap(),
+            Parsed::Incomplete
+        ));
+        assert!(matches!(
+            parse(b"PROXY TCP4 192.0.2.1").unwrap(),
+            Parsed::Incomplete
+        ));
+        assert!(matches!(
+            parse(b"GET / HTTP/1.1\r\n").unwrap(),
+            Parsed::NotProxy
+        ));
+    }
+
+    #[test]
+    fn v2_local() {
+        let buf = v2(0, 1, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 1, 187]);
+        let (h, len) = header(&buf);
+        assert_eq!(len, buf.len());
+        assert_eq!(h.version(), 2);
+        assert_eq!(h.command(), ProxyCommand::Local);
+        assert_eq!(h.source(), None);
+        assert_eq!(h.destination(), None);
+    }
+
+    #[test]
+    fn v2_inet() {
+        let mut body = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        body.extend_from_slice(&[ProxyTlv::AUTHORITY, 0, 11]);
+        body.extend_from_slice(b"example.com");
+        body.extend_from_slice(&[ProxyTlv::NOOP, 0, 0]);
+        let mut buf = v2(1, 1, &body);
+        buf.extend_from_slice(b"GET /");
+        let (h, len) = header(&buf);
+        assert_eq!(len, buf.len() - 5);
+        assert_eq!(h.command(), ProxyCommand::Proxy);
+        assert_eq!(h.source(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(h.destination(), Some("192.0.2.2:443".parse().unwrap()));
+        assert_eq!(h.tlvs().len(), 2);
+        assert_eq!(h.authority(), Some("example.com"));
+        assert_eq!(h.tlv(ProxyTlv::NOOP), Some(&[][..]));
+        assert_eq!(h.tlv(ProxyTlv::ALPN), None);
+    }
+
+    #[test]
+    fn v2_inet6() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut body = src.octets().to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let (h, _) = header(&v2(1, 2, &body));
+        assert_eq!(h.source(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(h.destination(), Some("[::1]:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_short_address() {
+        assert!(parse(&v2(1, 1, &[192, 0, 2, 1])).is_err());
+        assert!(parse(&v2(1, 2, &[0; 35])).is_err());
+        assert!(parse(&v2(1, 3, &[0; 100])).is_err());
+        assert!(parse(&v2(1, 4, &[])).is_err());
+    }
+
+    #[test]
+    fn v2_incomplete() {
+        let buf = v2(1, 1, &[192, 0, 2, 1, 192, 0, 2, 2, 0, 80, 1, 187]);
+        assert!(matches!(parse(&buf[..15]).unwrap(), Parsed::Incomplete));
+        assert!(matches!(
+            parse(&buf[..buf.len() - 1]).unwrap(),
+            Parsed::Incomplete
+        ));
+    }
+
+    #[test]
+    fn v2_bad_version_and_command() {
+        let mut buf = v2(1, 0, &[]);
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err());
+        buf[12] = 0x22;
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn tlvs() {
+        assert_eq!(parse_tlvs(&[]).unwrap(), Vec::new());
+        let tlvs = parse_tlvs(&[0x05, 0, 2, b'i', b'd', 0x01, 0, 0]).unwrap();
+        assert_eq!(
+            tlvs,
+            vec![
+                ProxyTlv {
+                    kind: ProxyTlv::UNIQUE_ID,
+                    value: b"id".to_vec()
+                },
+                ProxyTlv { kind: ProxyTlv::ALPN, value: Vec::new() },
+            ]
+        );
+    }
+
+    #[test]
+    fn tlvs_truncated() {
+        // 型と長さの途中で切れている
+        assert!(parse_tlvs(&[0x01]).is_err());
+        assert!(parse_tlvs(&[0x01, 0]).is_err());
+        // 値が長さに足りない
+        assert!(parse_tlvs(&[0x01, 0, 3, b'h', b'2']).is_err());
+        assert!(parse_tlvs(&[0x01, 0, 0, 0x02, 0, 1]).is_err());
+        assert!(parse(&v2(1, 0, &[0x02, 0, 5, b'a'])).is_err());
+    }
+
+    fn read(input: &[u8], mode: ProxyProtocol) -> io::Result<(Option<ProxyHeader>, Vec<u8>)> {
+        let mut reader = input;
+        ProxyHeader::read(&mut reader, mode)
+            .now_or_never()
+            .expect("slice reader never pends")
+    }
+
+    #[test]
+    fn read_modes() {
+        let http = b"GET / HTTP/1.1\r\n\r\n";
+        let (h, rest) = read(http, ProxyProtocol::Optional).unwrap();
+        assert!(h.is_none());
+        assert_eq!(rest, http);
+        assert!(read(http, ProxyProtocol::Required).is_err());
+        let (h, rest) = read(http, ProxyProtocol::Disabled).unwrap();
+        assert!(h.is_none());
+        assert!(rest.is_empty());
+
+        let mut input = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n".to_vec();
+        input.extend_from_slice(http);
+        for mode in [ProxyProtocol::Optional, ProxyProtocol::Required] {
+            let (h, rest) = read(&input, mode).unwrap();
+            assert_eq!(
+                h.unwrap().source(),
+                Some("192.0.2.1:56324".parse().unwrap())
+            );
+            assert_eq!(rest, http);
+        }
+    }
+
+    #[test]
+    fn read_eof() {
+        assert_eq!(
+            read(b"", ProxyProtocol::Optional).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        // Optional で途中まで PROXY に見えたまま切れたら、そのまま返す
+        let (h, rest) = read(b"PRO", ProxyProtocol::Optional).unwrap();
+        assert!(h.is_none());
+        assert_eq!(rest, b"PRO");
+        assert_eq!(
+            read(b"PROXY TCP4", ProxyProtocol::Required)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
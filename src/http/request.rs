@@ -1,15 +1,34 @@
-use std::ops::Range;
+use std::{
+    borrow::Cow,
+    ops::Range,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
 
 use futures_io::AsyncRead;
 use futures_util::AsyncReadExt;
 
 use crate::{
     error::RouterError,
-    http::{header::HttpHeader, method::HttpMethod, version::HttpVersion},
+    http::{
+        code::HttpStatusCode,
+        extensions::Extensions,
+        forwarded::{ForwardedInfo, TrustedProxies},
+        header::HttpHeader,
+        info::ConnectionInfo,
+        limits::HttpLimits,
+        method::HttpMethod,
+        version::HttpVersion,
+    },
+    router::PathParams,
+    utils::IdleTimeout,
 };
 
 pub struct HttpRequest<R: AsyncRead + Unpin + 'static> {
-    io_reader: R,
+    /// drop するときだけ取り出すので、それまでは常に Some
+    io_reader: Option<R>,
+    /// ハンドラが Connection を手放したときに、drop でリクエストを戻す先
+    recovery: Option<RequestSlot<R>>,
     buf: Vec<u8>,
     // 0 => no readed body yet
     body_start: usize,
@@ -18,6 +37,31 @@ pub struct HttpRequest<R: AsyncRead + Unpin + 'static> {
     /// つまりbufと同じ
     headers: HttpHeader,
     request_line: HttpRequestLine,
+    /// ルーターがキャプチャしたパスパラメータ
+    params: PathParams,
+    /// マウントで取り除いたパスのバイト数
+    path_offset: usize,
+    /// リクエストごとの拡張データ
+    extensions: Extensions,
+    /// 接続ごとの状態 (keep-alive の間ずっと引き継ぐ)
+    connection_state: Extensions,
+    /// ハンドラが上書きしたタイムアウト
+    handler_timeout: HandlerTimeout,
+    /// 相手やリスナーのアドレス (keep-alive の間ずっと引き継ぐ)
+    connection_info: Arc<ConnectionInfo>,
+    /// ボディの読み込みが進まないまま待つ上限
+    body_read_timeout: Option<Duration>,
+    /// ボディの読み込みがタイムアウトした (続きが読めないので接続を閉じる)
+    body_timed_out: bool,
+    /// ボディが上限を超えていた (読まずに残っているので接続を閉じる)
+    body_too_large: bool,
+    limits: HttpLimits,
+    /// パースに失敗したときに返すステータスとその理由
+    parse_error: Option<(HttpStatusCode, Cow<'static, str>)>,
+    /// 信頼するプロキシの転送ヘッダから求めたクライアント
+    forwarded: Option<ForwardedInfo>,
+    /// まだパースしていない、先に読んでしまったバイト列 (次のリクエストの頭になる)
+    buffered: Vec<u8>,
 }
 
 impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
@@ -30,6 +74,15 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
         std::str::from_utf8(self.headers.get(key, &self.buf)?).ok()
     }
 
+    /// header_get の同期版
+    #[inline(always)]
+    pub(crate) fn header_value<S>(&self, key: S) -> Option<&str>
+    where
+        S: std::borrow::Borrow<str>,
+    {
+        std::str::from_utf8(self.headers.get(key, &self.buf)?).ok()
+    }
+
     #[inline(always)]
     pub fn request_line(&self) -> &HttpRequestLine {
         &self.request_line
@@ -42,6 +95,84 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
         std::str::from_utf8(&self.buf[path_range.clone()]).expect("Invalid UTF-8 in request path")
     }
 
+    /// get request path without query
+    /// マウントされている場合はプレフィックスを除いたパス
+    #[inline(always)]
+    pub fn path(&self) -> &str {
+        let full = self.path_full();
+        let path = match full.find('?') {
+            Some(i) => &full[..i],
+            None => full,
+        };
+        match path.get(self.path_offset..) {
+            Some("") | None => "/",
+            Some(rel) => rel,
+        }
+    }
+
+    /// マウントのプレフィックスだけを除いたパス (クエリを含む)
+    #[inline(always)]
+    pub(crate) fn path_full_relative(&self) -> &str {
+        self.path_full().get(self.path_offset..).unwrap_or("")
+    }
+
+    /// get query string (without `?`)
+    #[inline(always)]
+    pub fn query(&self) -> Option<&str> {
+        let full = self.path_full();
+        full.find('?').map(|i| &full[i + 1..])
+    }
+
+    /// ルーターがキャプチャしたパスパラメータ
+    #[inline(always)]
+    pub fn params(&self) -> &PathParams {
+        &self.params
+    }
+
+    #[inline(always)]
+    pub(crate) fn params_mut(&mut self) -> &mut PathParams {
+        &mut self.params
+    }
+
+    /// リクエストごとの拡張データ
+    #[inline(always)]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    #[inline(always)]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// 接続ごとの状態
+    /// 同じ接続の次のリクエストにも引き継がれます
+    #[inline(always)]
+    pub fn connection_state(&self) -> &Extensions {
+        &self.connection_state
+    }
+
+    #[inline(always)]
+    pub fn connection_state_mut(&mut self) -> &mut Extensions {
+        &mut self.connection_state
+    }
+
+    /// マウントで取り除かれたパスのプレフィックス
+    #[inline(always)]
+    pub fn mount_prefix(&self) -> &str {
+        &self.path_full()[..self.path_offset]
+    }
+
+    #[inline(always)]
+    pub(crate) fn path_offset(&self) -> usize {
+        self.path_offset
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_path_offset(&mut self, offset: usize) {
+        self.path_offset = offset;
+    }
+
     /// get request http method
     #[inline(always)]
     pub fn method(&self) -> &HttpMethod {
@@ -54,11 +185,188 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
         &self.request_line.version
     }
 
+    /// 同じ名前のヘッダの値をすべて、出てきた順に返す
+    #[inline(always)]
+    pub fn header_get_all<'a, 'k>(&'a self, key: &'k str) -> impl Iterator<Item = &'a str> + use<'a, 'k, R> {
+        self.headers
+            .get_all(key, &self.buf)
+            .filter_map(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// 信頼するプロキシの転送ヘッダから求めたクライアント
+    /// 信頼するプロキシを設定していないか、相手がプロキシでなければ None
+    #[inline(always)]
+    pub fn forwarded(&self) -> Option<&ForwardedInfo> {
+        self.forwarded.as_ref()
+    }
+
+    /// 転送ヘッダからクライアントを求めて控えておく
+    pub(crate) fn resolve_forwarded(&mut self, proxies: &TrustedProxies) {
+        let Some(peer) = self.connection_info.peer_addr() else {
+            return;
+        };
+        self.forwarded = proxies.resolve(peer.ip(), |name| self.header_get_all(name));
+    }
+
+    /// 相手やリスナーのアドレスなど、接続そのものの情報
+    #[inline(always)]
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+
     #[inline(always)]
-    pub(crate) fn into_reader(self) -> R {
-        self.io_reader
+    pub(crate) fn set_connection_info(&mut self, info: ConnectionInfo) {
+        self.connection_info = Arc::new(info);
     }
 
+    /// 先に読んでしまったバイト列を、次のリクエストの頭として渡す
+    #[inline(always)]
+    pub(crate) fn set_buffered(&mut self, buffered: Vec<u8>) {
+        self.buffered = buffered;
+    }
+
+    #[inline(always)]
+    pub(crate) fn into_reader_and_scope(mut self) -> (R, ConnectionScope) {
+        let scope = ConnectionScope {
+            state: std::mem::take(&mut self.connection_state),
+            info: self.connection_info.clone(),
+            buffered: std::mem::take(&mut self.buffered),
+        };
+        let reader = self.io_reader.take().expect("reader is taken only on drop");
+        (reader, scope)
+    }
+
+    /// ハンドラが Connection を手放したときにリクエストを取り戻すためのハンドル
+    /// 既にハンドルがあればそれを共有し、このハンドルで作ったかどうかも返します
+    #[inline]
+    pub(crate) fn request_handle(&mut self) -> (RequestHandle<R>, bool) {
+        match &self.recovery {
+            Some(slot) => (RequestHandle(slot.clone()), false),
+            None => {
+                let slot: RequestSlot<R> = Arc::new(Mutex::new(None));
+                self.recovery = Some(slot.clone());
+                (RequestHandle(slot), true)
+            },
+        }
+    }
+
+    /// 取り戻したリクエストにハンドルを付け直す
+    #[inline]
+    pub(crate) fn attach_request_handle(&mut self, handle: &RequestHandle<R>) {
+        self.recovery = Some(handle.0.clone());
+    }
+
+    /// ハンドラが Connection を返したので、drop してもリクエストを戻さない
+    #[inline]
+    pub(crate) fn release_request_handle(&mut self) {
+        self.recovery = None;
+    }
+
+    /// 前のリクエストから接続ごとの状態と情報を引き継ぐ
+    #[inline(always)]
+    pub(crate) fn with_connection_scope(mut self, scope: ConnectionScope) -> Self {
+        self.connection_state = scope.state;
+        self.connection_info = scope.info;
+        if !scope.buffered.is_empty() {
+            self.buf = scope.buffered;
+        }
+        self
+    }
+
+    /// このリクエストだけハンドラのタイムアウトを変える
+    /// None ならタイムアウトしません
+    #[inline]
+    pub fn set_handler_timeout(&mut self, timeout: Option<Duration>) {
+        self.handler_timeout.set(Some(timeout));
+    }
+
+    /// ハンドラを動かしている間も上書きを見られるように共有する
+    #[inline]
+    pub(crate) fn handler_timeout_handle(&self) -> HandlerTimeout {
+        self.handler_timeout.clone()
+    }
+
+    /// ボディの読み込みのタイムアウト
+    /// 相手が送ってこないまま、この時間読み込みが進まなければ `TimedOut` で打ち切ります
+    #[inline]
+    pub fn set_body_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.body_read_timeout = timeout;
+    }
+
+    #[inline]
+    pub fn body_read_timeout(&self) -> Option<Duration> {
+        self.body_read_timeout
+    }
+
+    /// ボディの読み込みがタイムアウトしたか
+    /// タイムアウトした接続はレスポンスを送った後に閉じられます
+    #[inline]
+    pub fn is_body_timed_out(&self) -> bool {
+        self.body_timed_out
+    }
+
+    /// ボディが `HttpLimits::max_body` を超えていたか
+    /// 超えた接続はレスポンスを送った後に閉じられます
+    #[inline]
+    pub fn is_body_too_large(&self) -> bool {
+        self.body_too_large
+    }
+
+    /// リクエストを読み切れていないので、レスポンスの後に接続を閉じる必要があるか
+    /// (ボディのタイムアウトや上限超え、上限を超えたリクエストラインやヘッダ)
+    #[inline]
+    pub(crate) fn must_close(&self) -> bool {
+        self.body_timed_out
+            || self.body_too_large
+            || self
+                .parse_error
+                .as_ref()
+                .is_some_and(|(status, _)| *status != HttpStatusCode::BadRequest)
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &HttpLimits {
+        &self.limits
+    }
+
+    #[inline]
+    pub fn set_limits(&mut self, limits: HttpLimits) {
+        self.limits = limits;
+    }
+
+    /// パースに失敗したときのステータスとその理由
+    /// `Router::invalid_http` でレスポンスを作るのに使います
+    #[inline]
+    pub fn parse_error(&self) -> Option<(HttpStatusCode, &str)> {
+        self.parse_error
+            .as_ref()
+            .map(|(status, msg)| (*status, msg.as_ref()))
+    }
+
+    #[inline]
+    fn check_body_size(&mut self, size: usize) -> std::io::Result<()> {
+        if size > self.limits.max_body {
+            self.body_too_large = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request body exceeds the size limit",
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn check_timed_out<T>(&mut self, res: std::io::Result<T>) -> std::io::Result<T> {
+        if let Err(e) = &res
+            && e.kind() == std::io::ErrorKind::TimedOut
+        {
+            self.body_timed_out = true;
+        }
+        res
+    }
+
+    /// Content-Length 分のボディを読む
+    /// `HttpLimits::max_body` (既定は 2MiB) を超えていたら読まずに `InvalidData` を返します
     #[inline(always)]
     pub async fn read_body_bytes(&mut self) -> std::io::Result<Vec<u8>> {
         let content_length = if let Some(cl_val) = self.header_get("Content-Length").await {
@@ -66,6 +374,7 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
         } else {
             0
         };
+        self.check_body_size(content_length)?;
         let have = self.buf.len().saturating_sub(self.body_start);
         if have < content_length {
             let need = content_length - have;
@@ -74,15 +383,17 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
             let old_len = self.buf.len();
             self.buf.resize(old_len + need, 0);
 
-            self.io_reader
+            let res = IdleTimeout::new(reader(&mut self.io_reader), self.body_read_timeout)
                 .read_exact(&mut self.buf[old_len..old_len + need])
-                .await?;
+                .await;
+            self.check_timed_out(res)?;
         }
         Ok(self.buf.split_off(self.body_start))
     }
 
     #[inline(always)]
     pub async fn read_body_bytes_size(&mut self, size: usize) -> std::io::Result<Vec<u8>> {
+        self.check_body_size(size)?;
         let have = self.buf.len().saturating_sub(self.body_start);
         if have < size {
             let need = size - have;
@@ -91,17 +402,32 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
             let old_len = self.buf.len();
             self.buf.resize(old_len + need, 0);
 
-            self.io_reader
+            let res = IdleTimeout::new(reader(&mut self.io_reader), self.body_read_timeout)
                 .read_exact(&mut self.buf[old_len..old_len + need])
-                .await?;
+                .await;
+            self.check_timed_out(res)?;
         }
         Ok(self.buf.split_off(self.body_start))
     }
 
+    /// 接続が閉じるまでボディを読む
+    /// `HttpLimits::max_body` (既定は 2MiB) を超えたら `InvalidData` を返します
     #[inline(always)]
     pub async fn read_body_to_end(&mut self) -> std::io::Result<Vec<u8>> {
         let mut body = self.buf.split_off(self.body_start);
-        self.io_reader.read_to_end(&mut body).await?;
+        self.check_body_size(body.len())?;
+        let mut reader = IdleTimeout::new(reader(&mut self.io_reader), self.body_read_timeout);
+        // 上限を1バイトでも超えたらわかるように、上限 + 1 まで読む
+        // 上限が usize::MAX のように + 1 できなければ、上限なしで読む
+        let rest = (self.limits.max_body - body.len())
+            .checked_add(1)
+            .and_then(|rest| u64::try_from(rest).ok());
+        let res = match rest {
+            Some(rest) => reader.take(rest).read_to_end(&mut body).await,
+            None => reader.read_to_end(&mut body).await,
+        };
+        self.check_timed_out(res)?;
+        self.check_body_size(body.len())?;
         Ok(body)
     }
 
@@ -122,71 +448,161 @@ impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
 impl<R: AsyncRead + Unpin + 'static> HttpRequest<R> {
     pub fn new(io_reader: R) -> Self {
         HttpRequest {
-            io_reader,
-            buf: Vec::with_capacity(1024 * 1),
+            io_reader: Some(io_reader),
+            recovery: None,
+            buf: Vec::with_capacity(1024),
             body_start: 0,
             headers_start: 0,
             headers: HttpHeader::new(),
             request_line: HttpRequestLine::new(),
+            params: PathParams::new(),
+            path_offset: 0,
+            extensions: Extensions::new(),
+            connection_state: Extensions::new(),
+            handler_timeout: HandlerTimeout::default(),
+            connection_info: Arc::new(ConnectionInfo::new()),
+            body_read_timeout: None,
+            body_timed_out: false,
+            body_too_large: false,
+            limits: HttpLimits::new(),
+            parse_error: None,
+            forwarded: None,
+            buffered: Vec::new(),
         }
     }
 
     #[inline(always)]
     pub async fn parse_request_line(mut self) -> Result<HttpRequest<R>, HttpRequest<R>> {
-        let (request_line, headers_start) = match HttpRequestLine::parse_async(&mut self.io_reader, &mut self.buf).await
-        {
-            Ok(line) => line,
+        let parsed = HttpRequestLine::parse_buffered(reader(&mut self.io_reader), &mut self.buf, 0, &self.limits).await;
+        self.body_start = 0;
+        self.body_timed_out = false;
+        self.body_too_large = false;
+        self.forwarded = None;
+        self.buffered = Vec::new();
+        match parsed {
+            Ok((request_line, headers_start)) => {
+                self.headers_start = headers_start;
+                self.request_line = request_line;
+                self.parse_error = None;
+                Ok(self)
+            },
             Err(e) => {
-                let line = HttpRequestLine {
+                let (path, parse_error) = match e {
+                    RouterError::InvalidHttpRequest(range, msg) => (range, (HttpStatusCode::BadRequest, msg.into())),
+                    RouterError::HttpErrorCodeWithMessage(code, msg) => (0..0, (code, msg.into())),
+                    e => (0..0, (HttpStatusCode::BadRequest, e.to_string().into())),
+                };
+                self.headers_start = 0;
+                self.headers = HttpHeader::new();
+                self.request_line = HttpRequestLine {
                     method: HttpMethod::ERR,
-                    path: if let RouterError::InvalidHttpRequest(range, _) = e {
-                        range
-                    } else {
-                        0..0
-                    },
+                    path,
                     version: HttpVersion::ERR,
                 };
-                return Err(HttpRequest {
-                    io_reader: self.io_reader,
-                    buf: self.buf,
-                    body_start: 0,
-                    headers_start: 0,
-                    headers: HttpHeader::new(),
-                    request_line: line,
-                });
+                self.params = PathParams::new();
+                self.path_offset = 0;
+                self.extensions = Extensions::new();
+                self.parse_error = Some(parse_error);
+                Err(self)
             },
-        };
-        Ok(HttpRequest {
-            io_reader: self.io_reader,
-            buf: self.buf,
-            body_start: 0,
-            headers_start: headers_start,
-            headers: self.headers,
-            request_line,
-        })
+        }
     }
 
     #[inline(always)]
     pub async fn parse_request(mut self) -> Result<HttpRequest<R>, HttpRequest<R>> {
-        let (headers, body_start) =
-            match HttpHeader::parse_async(&mut self.io_reader, &mut self.buf, self.headers_start).await {
-                Some(headers) => headers,
-                None => {
-                    return Err(self);
-                },
-            };
+        let (headers, body_start) = match HttpHeader::parse_async_with_limits(
+            reader(&mut self.io_reader),
+            &mut self.buf,
+            self.headers_start,
+            &self.limits,
+        )
+        .await
+        {
+            Ok(headers) => headers,
+            Err(status) => {
+                let msg = match status {
+                    HttpStatusCode::RequestHeaderFieldsTooLarge => "Request header fields too large",
+                    _ => "Invalid request headers",
+                };
+                self.parse_error = Some((status, Cow::Borrowed(msg)));
+                return Err(self);
+            },
+        };
         self.body_start = body_start;
         self.headers = headers;
         Ok(self)
     }
 }
 
+#[inline(always)]
+fn reader<R>(io_reader: &mut Option<R>) -> &mut R {
+    io_reader.as_mut().expect("reader is taken only on drop")
+}
+
+impl<R: AsyncRead + Unpin + 'static> Drop for HttpRequest<R> {
+    fn drop(&mut self) {
+        if let Some(recovery) = self.recovery.take()
+            && let Some(io_reader) = self.io_reader.take()
+        {
+            // 中身を新しいリクエストに移して、それを戻す
+            let mut req = HttpRequest::new(io_reader);
+            std::mem::swap(self, &mut req);
+            req.io_reader = self.io_reader.take();
+            *recovery.lock().unwrap_or_else(PoisonError::into_inner) = Some(req);
+        }
+    }
+}
+
+type RequestSlot<R> = Arc<Mutex<Option<HttpRequest<R>>>>;
+
+/// ハンドラに渡した HttpRequest を取り戻すハンドル
+/// HttpRequest が drop されると、ここに戻ってきます
+pub(crate) struct RequestHandle<R: AsyncRead + Unpin + 'static>(RequestSlot<R>);
+
+impl<R: AsyncRead + Unpin + 'static> RequestHandle<R> {
+    /// HttpRequest が破棄されていれば取り出す
+    pub(crate) fn recover(&self) -> Option<HttpRequest<R>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+/// ハンドラが上書きしたタイムアウト
+/// ハンドラが Connection を持っている間もルーターから見えるように共有しています
+#[derive(Clone, Default)]
+pub(crate) struct HandlerTimeout(Arc<Mutex<Option<Option<Duration>>>>);
+
+impl HandlerTimeout {
+    /// 上書きしていなければ None、タイムアウトしないなら Some(None)
+    #[inline]
+    pub(crate) fn get(&self) -> Option<Option<Duration>> {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn set(&self, timeout: Option<Option<Duration>>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = timeout;
+    }
+}
+
+/// keep-alive の次のリクエストに引き継ぐもの
+pub(crate) struct ConnectionScope {
+    state: Extensions,
+    info: Arc<ConnectionInfo>,
+    buffered: Vec<u8>,
+}
+
 pub struct HttpRequestLine {
     method: HttpMethod,
     path: Range<usize>,
     version: HttpVersion,
 }
 
+impl Default for HttpRequestLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpRequestLine {
     pub fn new() -> Self {
         HttpRequestLine {
@@ -200,23 +616,50 @@ impl HttpRequestLine {
     pub async fn parse_async<R: AsyncRead + Unpin + 'static>(
         reader: &mut R,
         buf: &mut Vec<u8>,
+    ) -> Result<(HttpRequestLine, usize), RouterError> {
+        Self::parse_async_with_limits(reader, buf, &HttpLimits::new()).await
+    }
+
+    /// 上限を超えたら 414 (`HttpErrorCodeWithMessage`) を返します
+    pub async fn parse_async_with_limits<R: AsyncRead + Unpin + 'static>(
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+        limits: &HttpLimits,
     ) -> Result<(HttpRequestLine, usize), RouterError> {
         let start = buf.len();
+        Self::parse_buffered(reader, buf, start, limits).await
+    }
+
+    /// `buf[start..]` にすでに読んであるバイト列から続けてパースする
+    pub(crate) async fn parse_buffered<R: AsyncRead + Unpin + 'static>(
+        reader: &mut R,
+        buf: &mut Vec<u8>,
+        start: usize,
+        limits: &HttpLimits,
+    ) -> Result<(HttpRequestLine, usize), RouterError> {
         // Read bytes into buf until we find a newline or EOF
         let mut temp_buf = [0u8; 1024];
         let mut n = 0;
+        let mut searched = start;
         loop {
+            if let Some(pos) = buf[searched..].iter().position(|&b| b == b'\n') {
+                n = searched - start + pos + 1;
+                break;
+            }
+            if buf.len() - start > limits.max_request_line {
+                return Err(request_line_too_long());
+            }
             let read_bytes = reader.read(&mut temp_buf).await.map_err(|_| {
                 RouterError::InvalidHttpRequest(start..buf.len(), "Failed to read request line".to_string())
             })?;
             if read_bytes == 0 {
                 break;
             }
+            searched = buf.len();
             buf.extend_from_slice(&temp_buf[..read_bytes]);
-            if let Some(pos) = buf[start..].iter().position(|&b| b == b'\n') {
-                n = pos + 1;
-                break;
-            }
+        }
+        if n > limits.max_request_line {
+            return Err(request_line_too_long());
         }
 
         if n == 0 {
@@ -293,6 +736,17 @@ impl HttpRequestLine {
             },
         };
 
+        let path_end = raw_path
+            .iter()
+            .position(|&b| b == b'?')
+            .unwrap_or(raw_path.len());
+        if raw_path[..path_end].iter().filter(|&&b| b == b'/').count() > limits.max_uri_segments {
+            return Err(RouterError::HttpErrorCodeWithMessage(
+                HttpStatusCode::URITooLong,
+                "Too many path segments".to_string(),
+            ));
+        }
+
         let headers_start = start + n;
         Ok((
             HttpRequestLine { method, path: path_range, version },
@@ -301,6 +755,14 @@ impl HttpRequestLine {
     }
 }
 
+#[inline]
+fn request_line_too_long() -> RouterError {
+    RouterError::HttpErrorCodeWithMessage(
+        HttpStatusCode::URITooLong,
+        "Request line too long".to_string(),
+    )
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RangeSpec {
     FromToInclusive { start: u64, end: u64 }, // bytes=START-END (end inclusive)
@@ -370,3 +832,361 @@ pub fn parse_range_header_value(range_value: &str) -> RangeParse {
         },
     }
 }
+
+/// Range ヘッダの値から実際に送る範囲を決める
+/// - default_range: Range ヘッダがない / 不正な場合の範囲 (この外には出ない)
+/// - max_size: 1回で送る最大バイト数
+///
+/// 満たせない範囲なら None (416)
+#[inline]
+pub fn select_byte_range(range_value: Option<&str>, default_range: Range<u64>, max_size: u64) -> Option<Range<u64>> {
+    let range = default_range;
+    let spec = match range_value.map(parse_range_header_value) {
+        Some(RangeParse::Valid(spec)) => spec,
+        // 不正な Range は無視して全体を返す
+        Some(RangeParse::Invalid) | None => return Some(range),
+    };
+    let (start, end) = match spec {
+        RangeSpec::FromToInclusive { start, end } => {
+            let start = start.max(range.start);
+            // HTTPのRangeヘッダはendがinclusiveなので+1する
+            let end = (end.saturating_add(1))
+                .min(range.end)
+                .min(start.saturating_add(max_size));
+            (start, end)
+        },
+        RangeSpec::From { start } => {
+            let start = start.max(range.start);
+            let end = range.end.min(start.saturating_add(max_size));
+            (start, end)
+        },
+        RangeSpec::Suffix { len } => {
+            let end = range.end;
+            let start = end.saturating_sub(len.min(max_size)).max(range.start);
+            (start, end)
+        },
+    };
+    if start < end { Some(start..end) } else { None }
+}
+
+/// If-None-Match の値が etag に一致するか (弱い比較)
+#[inline]
+pub fn etag_matches_weak(header_value: &str, etag: &str) -> bool {
+    let header_value = header_value.trim();
+    if header_value == "*" {
+        return true;
+    }
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    header_value
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// If-Range の値が etag に一致するか (強い比較)
+/// 一致しなければ Range を無視して全体を返す
+#[inline]
+pub fn etag_matches_strong(header_value: &str, etag: &str) -> bool {
+    let header_value = header_value.trim();
+    !etag.starts_with("W/") && !header_value.starts_with("W/") && header_value == etag
+}
+
+/// Accept-Encoding の値が coding を許可しているか
+/// q=0 は拒否として扱います
+#[inline]
+pub fn accept_encoding_allows(header_value: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for item in header_value.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let rejected = parts.any(|p| {
+            let p = p.trim();
+            p.strip_prefix("q=")
+                .or_else(|| p.strip_prefix("Q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        if name.eq_ignore_ascii_case(coding) {
+            return !rejected;
+        }
+        if name == "*" {
+            wildcard = !rejected;
+        }
+    }
+    wildcard
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, io::Cursor};
+
+    use super::*;
+
+    type Req = HttpRequest<Cursor<Vec<u8>>>;
+
+    fn parse(raw: &str, limits: HttpLimits) -> Result<Req, Box<Req>> {
+        let mut req = HttpRequest::new(Cursor::new(raw.as_bytes().to_vec()));
+        req.set_limits(limits);
+        async { req.parse_request_line().await?.parse_request().await }
+            .now_or_never()
+            .unwrap()
+            .map_err(Box::new)
+    }
+
+    fn rejected(raw: &str, limits: HttpLimits) -> HttpStatusCode {
+        let req = parse(raw, limits)
+            .err()
+            .expect("request should be rejected");
+        assert!(req.must_close());
+        req.parse_error().unwrap().0
+    }
+
+    #[test]
+    fn request_line_over_the_limit_is_414() {
+        let raw = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(100));
+        let line_len = raw.find('\n').unwrap() + 1;
+        let limits = |max_request_line| HttpLimits { max_request_line, ..HttpLimits::new() };
+        assert!(parse(&raw, limits(line_len)).is_ok());
+        assert_eq!(
+            rejected(&raw, limits(line_len - 1)),
+            HttpStatusCode::URITooLong
+        );
+        // 改行が来ないまま上限を超えても 414
+        let endless = format!("GET /{}", "a".repeat(5000));
+        assert_eq!(rejected(&endless, limits(1024)), HttpStatusCode::URITooLong);
+    }
+
+    #[test]
+    fn too_many_path_segments_is_414() {
+        let limits = HttpLimits { max_uri_segments: 3, ..HttpLimits::new() };
+        assert!(parse("GET /a/b/c HTTP/1.1\r\nHost: a\r\n\r\n", limits).is_ok());
+        // クエリの中の `/` は数えない
+        assert!(parse("GET /a/b/c?d=/e/f HTTP/1.1\r\nHost: a\r\n\r\n", limits).is_ok());
+        assert_eq!(
+            rejected("GET /a/b/c/d HTTP/1.1\r\nHost: a\r\n\r\n", limits),
+            HttpStatusCode::URITooLong
+        );
+    }
+
+    #[test]
+    fn header_limits_are_431() {
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\nX-One: 1\r\nX-Two: 2\r\n\r\n";
+        let headers_len = raw.len() - raw.find('\n').unwrap() - 1;
+
+        let bytes = |max_header_bytes| HttpLimits { max_header_bytes, ..HttpLimits::new() };
+        assert!(parse(raw, bytes(headers_len)).is_ok());
+        assert_eq!(
+            rejected(raw, bytes(headers_len - 1)),
+            HttpStatusCode::RequestHeaderFieldsTooLarge
+        );
+
+        let count = |max_headers| HttpLimits { max_headers, ..HttpLimits::new() };
+        assert!(parse(raw, count(3)).is_ok());
+        assert_eq!(
+            rejected(raw, count(2)),
+            HttpStatusCode::RequestHeaderFieldsTooLarge
+        );
+    }
+
+    #[test]
+    fn malformed_requests_are_400_and_keep_the_connection() {
+        for raw in ["BREW / HTTP/1.1\r\nHost: a\r\n\r\n", "GET /\r\nHost: a\r\n\r\n", "GET / HTTP/9\r\nHost: a\r\n\r\n"]
+        {
+            let req = parse(raw, HttpLimits::new()).err().unwrap();
+            assert_eq!(
+                req.parse_error().unwrap().0,
+                HttpStatusCode::BadRequest,
+                "{}",
+                raw
+            );
+            assert!(!req.must_close(), "{}", raw);
+        }
+    }
+
+    #[test]
+    fn body_over_the_limit_is_flagged() {
+        let limits = HttpLimits { max_body: 4, ..HttpLimits::new() };
+        let ok = "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd";
+        let mut req = parse(ok, limits).ok().unwrap();
+        assert_eq!(
+            req.read_body_bytes().now_or_never().unwrap().unwrap(),
+            b"abcd"
+        );
+        assert!(!req.is_body_too_large());
+
+        let large = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde";
+        let mut req = parse(large, limits).ok().unwrap();
+        let err = req.read_body_bytes().now_or_never().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(req.is_body_too_large() && req.must_close());
+
+        let mut req = parse(large, limits).ok().unwrap();
+        assert!(req.read_body_bytes_size(5).now_or_never().unwrap().is_err());
+        assert!(req.is_body_too_large());
+
+        let mut req = parse("POST / HTTP/1.0\r\nHost: a\r\n\r\nabcd", limits)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            req.read_body_to_end().now_or_never().unwrap().unwrap(),
+            b"abcd"
+        );
+        let mut req = parse("POST / HTTP/1.0\r\nHost: a\r\n\r\nabcde", limits)
+            .ok()
+            .unwrap();
+        assert!(req.read_body_to_end().now_or_never().unwrap().is_err());
+        assert!(req.is_body_too_large());
+    }
+
+    const FULL: Range<u64> = 0..1000;
+
+    #[test]
+    fn parses_range_specs() {
+        assert_eq!(
+            parse_range_header_value("bytes=0-99"),
+            RangeParse::Valid(RangeSpec::FromToInclusive { start: 0, end: 99 })
+        );
+        assert_eq!(
+            parse_range_header_value(" bytes=500- "),
+            RangeParse::Valid(RangeSpec::From { start: 500 })
+        );
+        assert_eq!(
+            parse_range_header_value("bytes=-200"),
+            RangeParse::Valid(RangeSpec::Suffix { len: 200 })
+        );
+        assert_eq!(parse_range_header_value("bytes=-0"), RangeParse::Invalid);
+        assert_eq!(parse_range_header_value("bytes=9-3"), RangeParse::Invalid);
+        assert_eq!(parse_range_header_value("bytes=-"), RangeParse::Invalid);
+        assert_eq!(parse_range_header_value("bytes=a-b"), RangeParse::Invalid);
+        assert_eq!(parse_range_header_value("items=0-1"), RangeParse::Invalid);
+    }
+
+    #[test]
+    fn selects_closed_open_ended_and_suffix_ranges() {
+        assert_eq!(select_byte_range(None, FULL, u64::MAX), Some(0..1000));
+        assert_eq!(
+            select_byte_range(Some("bytes=0-99"), FULL, u64::MAX),
+            Some(0..100)
+        );
+        // 末尾を超える end は末尾までに切り詰める
+        assert_eq!(
+            select_byte_range(Some("bytes=900-5000"), FULL, u64::MAX),
+            Some(900..1000)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=990-"), FULL, u64::MAX),
+            Some(990..1000)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=-10"), FULL, u64::MAX),
+            Some(990..1000)
+        );
+        // ファイルより長い suffix は全体
+        assert_eq!(
+            select_byte_range(Some("bytes=-5000"), FULL, u64::MAX),
+            Some(0..1000)
+        );
+    }
+
+    #[test]
+    fn caps_ranges_at_max_size() {
+        assert_eq!(select_byte_range(Some("bytes=0-"), FULL, 64), Some(0..64));
+        assert_eq!(
+            select_byte_range(Some("bytes=100-899"), FULL, 64),
+            Some(100..164)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=-500"), FULL, 64),
+            Some(936..1000)
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_none() {
+        assert_eq!(select_byte_range(Some("bytes=1000-"), FULL, u64::MAX), None);
+        assert_eq!(
+            select_byte_range(Some("bytes=1000-1999"), FULL, u64::MAX),
+            None
+        );
+        assert_eq!(select_byte_range(Some("bytes=0-"), 0..0, u64::MAX), None);
+        assert_eq!(select_byte_range(Some("bytes=-10"), 0..0, u64::MAX), None);
+    }
+
+    #[test]
+    fn invalid_and_multiple_ranges_fall_back_to_the_whole_body() {
+        assert_eq!(
+            select_byte_range(Some("bytes=0-9,20-29"), FULL, u64::MAX),
+            Some(0..1000)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=5-1"), FULL, u64::MAX),
+            Some(0..1000)
+        );
+        assert_eq!(
+            select_byte_range(Some("lines=1-2"), FULL, u64::MAX),
+            Some(0..1000)
+        );
+    }
+
+    #[test]
+    fn ranges_stay_inside_the_default_range() {
+        // 位置は絶対値なので、default_range より前だけを指す範囲は満たせない
+        assert_eq!(
+            select_byte_range(Some("bytes=0-9"), 100..200, u64::MAX),
+            None
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=50-109"), 100..200, u64::MAX),
+            Some(100..110)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=150-"), 100..200, u64::MAX),
+            Some(150..200)
+        );
+        assert_eq!(
+            select_byte_range(Some("bytes=-10"), 100..200, u64::MAX),
+            Some(190..200)
+        );
+    }
+
+    #[test]
+    fn weak_etag_comparison() {
+        assert!(etag_matches_weak("\"abc\"", "\"abc\""));
+        assert!(etag_matches_weak("W/\"abc\"", "\"abc\""));
+        assert!(etag_matches_weak("\"abc\"", "W/\"abc\""));
+        assert!(etag_matches_weak("\"x\", W/\"abc\" ", "\"abc\""));
+        assert!(etag_matches_weak(" * ", "\"abc\""));
+        assert!(!etag_matches_weak("\"abcd\"", "\"abc\""));
+        assert!(!etag_matches_weak("", "\"abc\""));
+    }
+
+    #[test]
+    fn if_range_uses_strong_comparison() {
+        assert!(etag_matches_strong("\"abc\"", "\"abc\""));
+        assert!(etag_matches_strong(" \"abc\" ", "\"abc\""));
+        // どちらかが弱い ETag なら一致しない
+        assert!(!etag_matches_strong("W/\"abc\"", "\"abc\""));
+        assert!(!etag_matches_strong("\"abc\"", "W/\"abc\""));
+        assert!(!etag_matches_strong("W/\"abc\"", "W/\"abc\""));
+        assert!(!etag_matches_strong("\"other\"", "\"abc\""));
+    }
+
+    #[test]
+    fn accept_encoding_honours_q_values() {
+        assert!(accept_encoding_allows("gzip, deflate, br", "gzip"));
+        assert!(accept_encoding_allows("GZIP;q=0.5", "gzip"));
+        assert!(!accept_encoding_allows("gzip;q=0", "gzip"));
+        assert!(!accept_encoding_allows("gzip; q=0.0, br", "gzip"));
+        assert!(!accept_encoding_allows("deflate, br", "gzip"));
+        assert!(!accept_encoding_allows("", "gzip"));
+    }
+
+    #[test]
+    fn accept_encoding_wildcard() {
+        assert!(accept_encoding_allows("*", "gzip"));
+        assert!(!accept_encoding_allows("*;q=0", "gzip"));
+        // 名前で書かれた方が優先される
+        assert!(!accept_encoding_allows("*, gzip;q=0", "gzip"));
+        assert!(accept_encoding_allows("*;q=0, gzip", "gzip"));
+    }
+}
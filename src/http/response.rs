@@ -1,25 +1,49 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
 use futures_io::AsyncWrite;
 use futures_util::AsyncWriteExt;
 
-use crate::http::{code::HttpStatusCode, header::HttpHeader, version::HttpVersion};
+use crate::{
+    http::{code::HttpStatusCode, header::HttpHeader, version::HttpVersion},
+    utils::IdleTimeout,
+};
 
 pub struct HttpResponse<W: AsyncWrite + Unpin + 'static> {
-    io_writer: W,
+    /// drop するときだけ取り出すので、それまでは常に Some
+    io_writer: Option<W>,
+    /// ハンドラがパニックしたりタイムアウトしたときに、drop で書き込み側を戻す先
+    recovery: Option<Arc<Mutex<WriterSlot<W>>>>,
+    /// writer_handle の後に書き込んだか
+    touched: bool,
     /// 先頭14byteはレスポンスライン用に予約
     buf: Vec<u8>,
     headers: Option<HttpHeader>,
     response_line: HttpResponseLine,
+    /// HEAD リクエスト用にボディを送らない
+    omit_body: bool,
+    /// ボディの開始位置 (start_content 済みなら Some)
+    body_start: Option<usize>,
+    /// 書き込みが進まないまま待つ上限 (keep-alive の間引き継ぐ)
+    write_timeout: Option<Duration>,
 }
 
 impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
     pub fn new(io_writer: W) -> Self {
         let mut buf = vec![0; 14];
-        buf.reserve(1024 * 1 - 14);
+        buf.reserve(1024 - 14);
         HttpResponse {
-            io_writer,
+            io_writer: Some(io_writer),
+            recovery: None,
+            touched: false,
             buf,
             headers: None,
             response_line: HttpResponseLine::new(),
+            omit_body: false,
+            body_start: None,
+            write_timeout: None,
         }
     }
 
@@ -29,18 +53,45 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
         self.buf.resize(14, 0);
         self.headers = None;
         self.response_line = HttpResponseLine::new();
+        self.omit_body = false;
+        self.body_start = None;
         self
     }
 
+    /// ボディを送らずヘッダだけ送るようにする (HEAD 用)
+    /// Content-Length などのヘッダはそのまま送られます
+    #[inline]
+    pub fn set_omit_body(&mut self, omit: bool) {
+        self.omit_body = omit;
+    }
+
+    /// 書き込みのタイムアウト
+    /// 相手が受け取らないまま、この時間書き込みが進まなければ `TimedOut` で打ち切ります
+    #[inline]
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    #[inline]
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    #[inline]
+    pub fn is_body_omitted(&self) -> bool {
+        self.omit_body
+    }
+
     /// バッファを空にしてフラッシュ済みとマークする
     #[inline]
     pub fn flag_flushed_buf(&mut self) {
         self.buf.truncate(0);
+        self.body_start = None;
     }
 
     #[inline]
     pub(crate) fn is_flushed(&self) -> bool {
-        self.buf.len() == 0
+        self.buf.is_empty()
     }
 
     /// # please use `Connection::add_header` instead
@@ -58,7 +109,15 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
         }
 
         if let Some(headers) = &mut self.headers {
-            headers.insert(key, value.into(), &mut self.buf);
+            match self.body_start {
+                // ボディの後なら空行の前に挿入する
+                Some(body_start) => {
+                    let before = self.buf.len();
+                    headers.insert_at(key, value.into(), &mut self.buf, body_start - 2);
+                    self.body_start = Some(body_start + self.buf.len() - before);
+                },
+                None => headers.insert(key, value.into(), &mut self.buf),
+            }
         }
         self
     }
@@ -73,7 +132,11 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
         S: std::borrow::Borrow<str>,
     {
         if let Some(headers) = &mut self.headers {
+            let before = self.buf.len();
             headers.remove(key, &mut self.buf);
+            if let Some(body_start) = self.body_start {
+                self.body_start = Some(body_start - (before - self.buf.len()));
+            }
         }
         self
     }
@@ -84,10 +147,10 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
     where
         S: std::borrow::Borrow<str>,
     {
-        if let Some(headers) = &self.headers {
-            if let Some(value_bytes) = headers.get(key, &self.buf) {
-                return std::str::from_utf8(value_bytes).ok();
-            }
+        if let Some(headers) = &self.headers
+            && let Some(value_bytes) = headers.get(key, &self.buf)
+        {
+            return std::str::from_utf8(value_bytes).ok();
         }
         None
     }
@@ -114,7 +177,50 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
     /// HTTPレスポンスの送信の責任はこれであなたのもの
     #[inline]
     pub fn writer(&mut self) -> &mut W {
-        &mut self.io_writer
+        self.touched = true;
+        self.io_writer
+            .as_mut()
+            .expect("writer is taken only on drop")
+    }
+
+    /// 書き込みのタイムアウト付きのライター
+    /// ストリーミングなどフレームワーク内の書き込みはこれを通します
+    #[inline]
+    pub(crate) fn timed_writer(&mut self) -> IdleTimeout<'_, W> {
+        let timeout = self.write_timeout;
+        IdleTimeout::new(self.writer(), timeout)
+    }
+
+    /// パニックやタイムアウトのときに書き込み側を取り戻すためのハンドル
+    /// このリクエストでまだ何も書き込んでいない状態から数え直します
+    #[inline]
+    pub(crate) fn writer_handle(&mut self) -> WriterHandle<W> {
+        self.touched = false;
+        let slot = Arc::new(Mutex::new(WriterSlot { writer: None, touched: false }));
+        self.recovery = Some(slot.clone());
+        WriterHandle(slot)
+    }
+
+    /// `writer_handle` と同じだが、既にハンドルがあればそれを共有する
+    /// このハンドルで作ったかどうかも返します
+    #[inline]
+    pub(crate) fn shared_writer_handle(&mut self) -> (WriterHandle<W>, bool) {
+        match &self.recovery {
+            Some(slot) => (WriterHandle(slot.clone()), false),
+            None => (self.writer_handle(), true),
+        }
+    }
+
+    /// 取り戻した書き込み側にハンドルを付け直す
+    #[inline]
+    pub(crate) fn attach_writer_handle(&mut self, handle: &WriterHandle<W>) {
+        self.recovery = Some(handle.0.clone());
+    }
+
+    /// ハンドラが最後まで動いたので、drop しても書き込み側を戻さない
+    #[inline]
+    pub(crate) fn release_writer_handle(&mut self) {
+        self.recovery = None;
     }
 
     #[inline(always)]
@@ -244,17 +350,49 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
         self.buf.extend_from_slice(body);
     }
 
+    /// 組み立て済みのボディ (start_content 前なら None)
+    #[inline]
+    pub(crate) fn body(&self) -> Option<&[u8]> {
+        self.body_start.and_then(|start| self.buf.get(start..))
+    }
+
+    /// 組み立て済みのボディを差し替える
+    /// Content-Length と Content-Type は付け直し、それ以外のヘッダは残します
+    pub(crate) fn replace_body(&mut self, content_type: &str, body: &[u8]) {
+        let Some(body_start) = self.body_start else {
+            return;
+        };
+        self.buf.truncate(body_start);
+        self.header_remove("Content-Length");
+        self.header_remove("Content-Type");
+        self.header_add("Content-Length", body.len().to_string());
+        self.header_add("Content-Type", content_type);
+        self.buf.extend_from_slice(body);
+    }
+
     #[inline(always)]
     pub fn start_content(&mut self) {
         self.buf.push(b'\r');
         self.buf.push(b'\n');
+        self.body_start = Some(self.buf.len());
     }
 
     /// 自動でよばれるのでrouter側で呼び出す必要性はほぼないです
     #[inline(always)]
     pub async fn send(&mut self) -> std::io::Result<()> {
-        self.io_writer.write_all(&self.buf).await?;
-        self.io_writer.flush().await
+        let end = match self.body_start {
+            // ヘッダの終わり (ボディの開始位置) までだけ送る
+            Some(body_start) if self.omit_body => body_start,
+            _ => self.buf.len(),
+        };
+        self.touched = true;
+        let io_writer = self
+            .io_writer
+            .as_mut()
+            .expect("writer is taken only on drop");
+        let mut writer = IdleTimeout::new(io_writer, self.write_timeout);
+        writer.write_all(&self.buf[..end]).await?;
+        writer.flush().await
     }
 
     /// HTTPレスポンスラインを書き込む
@@ -279,12 +417,59 @@ impl<W: AsyncWrite + Unpin + 'static> HttpResponse<W> {
     }
 }
 
+impl<W: AsyncWrite + Unpin + 'static> Drop for HttpResponse<W> {
+    fn drop(&mut self) {
+        if let Some(recovery) = self.recovery.take()
+            && let Some(writer) = self.io_writer.take()
+        {
+            let mut slot = recovery.lock().unwrap_or_else(PoisonError::into_inner);
+            slot.writer = Some(writer);
+            slot.touched = self.touched;
+        }
+    }
+}
+
+struct WriterSlot<W> {
+    writer: Option<W>,
+    touched: bool,
+}
+
+/// ハンドラに渡した HttpResponse の書き込み側を取り戻すハンドル
+/// HttpResponse が drop されると、書き込み側がここに戻ってきます
+pub(crate) struct WriterHandle<W>(Arc<Mutex<WriterSlot<W>>>);
+
+impl<W> WriterHandle<W> {
+    /// HttpResponse が破棄されていれば書き込み側を取り出す
+    /// まだ何も書き込んでいなければ true も返します
+    pub(crate) fn recover(self) -> Option<(W, bool)> {
+        let mut slot = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let writer = slot.writer.take()?;
+        Some((writer, !slot.touched))
+    }
+
+    /// HttpResponse が破棄されていて、まだ何も書き込んでいなければ書き込み側を取り出す
+    /// 書き込んでいたらそのまま残します
+    pub(crate) fn recover_untouched(&self) -> Option<W> {
+        let mut slot = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if slot.touched {
+            return None;
+        }
+        slot.writer.take()
+    }
+}
+
 /// HTTPレスポンスのリクエストライン
 pub struct HttpResponseLine {
     pub version: HttpVersion,
     pub status_code: HttpStatusCode,
 }
 
+impl Default for HttpResponseLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpResponseLine {
     pub fn new() -> Self {
         HttpResponseLine {
@@ -294,6 +479,7 @@ impl HttpResponseLine {
     }
 
     #[inline(always)]
+    #[allow(clippy::ptr_arg)]
     pub fn write_to_buf(&self, buf: &mut Vec<u8>) {
         // bufの先頭14byteに書き込む
         buf[0..8].copy_from_slice(self.version.as_bytes());
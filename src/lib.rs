@@ -1,6 +1,8 @@
 pub mod connection;
 pub mod error;
+pub mod extract;
 pub mod http;
+pub mod response;
 pub mod router;
 pub mod server;
 pub mod utils;
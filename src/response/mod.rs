@@ -0,0 +1,300 @@
+//! ハンドラが返した値をレスポンスにする
+//!
+//! 文字列やバイト列、`(HttpStatusCode, T)`、`Json<T>` などを `IntoResponse` でレスポンスにします
+//! `Result<T, E>` にも実装しているので、ハンドラの中で `?` が使えます
+//!
+//! `PathRouter` のハンドラはそのまま `IntoResponse` な値を返せます
+//! 組み立て済みの Connection も `IntoResponse` なので、`Ok(conn.text_body(..))` のように返せます
+//! Connection を手放してから値を返したときは、ルーターが Connection を取り戻して書き込みます
+//!
+//! ```ignore
+//! async fn show(Path(id): Path<u32>) -> Result<Json<Item>, (HttpStatusCode, &'static str)> {
+//!     let item = find(id).ok_or((HttpStatusCode::NotFound, "no such item"))?;
+//!     Ok(Json(item))
+//! }
+//!
+//! let router = TokioPathRouter::builder()
+//!     .get("/items/:id", respond(show))
+//!     .get("/raw/:id", |conn| async move {
+//!         let id: u32 = conn.param("id").unwrap_or_default().parse().map_err(|_| HttpStatusCode::BadRequest)?;
+//!         Ok::<_, HttpStatusCode>(conn.text_body(id.to_string()))
+//!     })
+//!     .build()?;
+//! ```
+
+mod problem;
+
+pub use problem::{PROBLEM_JSON, Problem, ProblemValue};
+
+use std::borrow::{Borrow, Cow};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    extract::Rejection,
+    http::HttpStatusCode,
+};
+
+/// レスポンスにできる型
+///
+/// まだレスポンスを組み立てていない Connection を受け取って、レスポンスを書き込みます
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be turned into a response",
+    note = "return a finished `Connection`, or a value such as `&str`, `Html<T>`, `Json<T>` or `(HttpStatusCode, T)`"
+)]
+pub trait IntoResponse<C, R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend>;
+
+    /// ハンドラの戻り値にしたときに呼ばれます
+    /// 既定では、ルーターが取り戻した Connection に `into_response` で書き込みます
+    /// Connection を持っている型はそれを返すように上書きしてください
+    fn into_handler_response(self) -> HandlerResponse<C, R, W>
+    where
+        Self: Sized + 'static,
+        C: 'static,
+    {
+        HandlerResponse::Deferred(Box::new(move |conn| self.into_response(conn)))
+    }
+}
+
+/// ハンドラが手放した Connection に書き込む関数
+pub type DeferredResponse<C, R, W> = Box<dyn FnOnce(Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend>>;
+
+/// ハンドラが返した値
+///
+/// すぐに `finish` で消費されるので、`Ready` は Box に入れずに持ちます
+#[allow(clippy::large_enum_variant)]
+pub enum HandlerResponse<C, R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    /// 組み立て済みの Connection
+    Ready(Connection<C, R, W, ResponseReadyToSend>),
+    /// ハンドラが手放した Connection を取り戻して、これで書き込む
+    Deferred(DeferredResponse<C, R, W>),
+}
+
+impl<C, R, W> HandlerResponse<C, R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    /// 書き込んだ後のステータスコードを差し替える
+    fn with_status(self, status: HttpStatusCode) -> Self
+    where
+        C: 'static,
+    {
+        match self {
+            HandlerResponse::Ready(conn) => HandlerResponse::Ready(conn.set_status_code(status)),
+            HandlerResponse::Deferred(respond) => {
+                HandlerResponse::Deferred(Box::new(move |conn| respond(conn).set_status_code(status)))
+            },
+        }
+    }
+}
+
+/// 組み立て済みの Connection はそのまま返します (渡された Connection は使いません)
+impl<C, R, W> IntoResponse<C, R, W> for Connection<C, R, W, ResponseReadyToSend>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, _conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        self
+    }
+
+    #[inline]
+    fn into_handler_response(self) -> HandlerResponse<C, R, W>
+    where
+        C: 'static,
+    {
+        HandlerResponse::Ready(self)
+    }
+}
+
+/// text/html のボディ
+#[derive(Debug, Clone)]
+pub struct Html<T>(pub T);
+
+impl<C, R, W> IntoResponse<C, R, W> for ()
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.no_body()
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for HttpStatusCode
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.set_status_code(self).no_body()
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for &'static str
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.text_body(self)
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for String
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.text_body(self)
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for Cow<'static, str>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.text_body(self.as_ref())
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for &'static [u8]
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.binary_body(self)
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for Vec<u8>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.binary_body(&self)
+    }
+}
+
+impl<C, R, W, T> IntoResponse<C, R, W> for Html<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: Borrow<str>,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        conn.html_body(self.0)
+    }
+}
+
+/// シリアライズに失敗したら 500 を返します
+#[cfg(feature = "json")]
+impl<C, R, W, T> IntoResponse<C, R, W> for crate::extract::Json<T>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: serde::Serialize,
+{
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        match conn.json_body_serialized(&self.0) {
+            Ok(conn) => conn,
+            Err(e) => {
+                #[cfg(feature = "logging")]
+                log::error!("failed to serialize json response: {}", e.serde_error);
+                e.connection
+                    .set_status_code(HttpStatusCode::InternalServerError)
+                    .no_body()
+            },
+        }
+    }
+}
+
+/// ステータスコードを差し替える
+impl<C, R, W, T> IntoResponse<C, R, W> for (HttpStatusCode, T)
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: IntoResponse<C, R, W>,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        let (status, body) = self;
+        body.into_response(conn).set_status_code(status)
+    }
+
+    #[inline]
+    fn into_handler_response(self) -> HandlerResponse<C, R, W>
+    where
+        Self: 'static,
+        C: 'static,
+    {
+        let (status, body) = self;
+        body.into_handler_response().with_status(status)
+    }
+}
+
+impl<C, R, W, T, E> IntoResponse<C, R, W> for Result<T, E>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    T: IntoResponse<C, R, W>,
+    E: IntoResponse<C, R, W>,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        match self {
+            Ok(value) => value.into_response(conn),
+            Err(e) => e.into_response(conn),
+        }
+    }
+
+    #[inline]
+    fn into_handler_response(self) -> HandlerResponse<C, R, W>
+    where
+        Self: 'static,
+        C: 'static,
+    {
+        match self {
+            Ok(value) => value.into_handler_response(),
+            Err(e) => e.into_handler_response(),
+        }
+    }
+}
+
+impl<C, R, W> IntoResponse<C, R, W> for Rejection
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline]
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        Problem::new(self.status())
+            .with_detail(self.message().to_string())
+            .negotiate(conn)
+    }
+}
@@ -0,0 +1,435 @@
+use std::{borrow::Cow, fmt};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    http::HttpStatusCode,
+    response::IntoResponse,
+    utils::{json_escape_into, prefers_json},
+};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// RFC 9457 の Problem Details
+///
+/// `application/problem+json` で返します
+/// `type` を省略すると `about:blank`、`title` を省略するとステータスの理由句になります
+///
+/// ```ignore
+/// async fn pay(Json(req): Json<Payment>) -> Result<Json<Receipt>, Problem> {
+///     Err(Problem::new(HttpStatusCode::Forbidden)
+///         .with_type("https://example.com/probs/out-of-credit")
+///         .with_title("You do not have enough credit.")
+///         .with_detail("Your current balance is 30, but that costs 50.")
+///         .with_extension("balance", 30))
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Problem {
+    status: HttpStatusCode,
+    type_uri: Option<Cow<'static, str>>,
+    title: Option<Cow<'static, str>>,
+    detail: Option<Cow<'static, str>>,
+    instance: Option<Cow<'static, str>>,
+    extensions: Vec<(Cow<'static, str>, ProblemValue)>,
+}
+
+/// 拡張メンバーの値
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProblemValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(Cow<'static, str>),
+    /// シリアライズ済みの JSON をそのまま埋め込む
+    Raw(String),
+}
+
+impl Problem {
+    pub fn new(status: HttpStatusCode) -> Self {
+        Problem {
+            status,
+            type_uri: None,
+            title: None,
+            detail: None,
+            instance: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// 問題の種類を表す URI
+    pub fn with_type<S>(mut self, type_uri: S) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.type_uri = Some(type_uri.into());
+        self
+    }
+
+    pub fn with_title<S>(mut self, title: S) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn with_detail<S>(mut self, detail: S) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// この発生箇所を表す URI
+    pub fn with_instance<S>(mut self, instance: S) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// 拡張メンバーを追加する
+    /// 標準メンバーと同じ名前は無視されます
+    pub fn with_extension<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<ProblemValue>,
+    {
+        let key = key.into();
+        if !matches!(&*key, "type" | "title" | "status" | "detail" | "instance") {
+            self.extensions.push((key, value.into()));
+        }
+        self
+    }
+
+    #[inline]
+    pub fn status(&self) -> HttpStatusCode {
+        self.status
+    }
+
+    #[inline]
+    pub fn type_uri(&self) -> &str {
+        self.type_uri.as_deref().unwrap_or("about:blank")
+    }
+
+    #[inline]
+    pub fn title(&self) -> &str {
+        match &self.title {
+            Some(title) => title,
+            None => self.status.info().message,
+        }
+    }
+
+    #[inline]
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    #[inline]
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    /// `application/problem+json` の本文
+    pub fn to_json(&self) -> String {
+        let mut out = String::with_capacity(128);
+        out.push_str("{\"type\":");
+        json_escape_into(&mut out, self.type_uri());
+        out.push_str(",\"title\":");
+        json_escape_into(&mut out, self.title());
+        out.push_str(",\"status\":");
+        out.push_str(self.status.as_str());
+        if let Some(detail) = &self.detail {
+            out.push_str(",\"detail\":");
+            json_escape_into(&mut out, detail);
+        }
+        if let Some(instance) = &self.instance {
+            out.push_str(",\"instance\":");
+            json_escape_into(&mut out, instance);
+        }
+        for (key, value) in &self.extensions {
+            out.push(',');
+            json_escape_into(&mut out, key);
+            out.push(':');
+            value.write_json(&mut out);
+        }
+        out.push('}');
+        out
+    }
+
+    /// フレームワーク自身のエラー用
+    /// `Accept` が JSON を好むなら problem+json、そうでなければ text/plain で返します
+    /// text/plain ならエラーページ (`Catchers`) の差し替え対象になります
+    pub(crate) fn negotiate<C, R, W>(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        if prefers_json(conn.req.header_value("Accept")) {
+            return self.into_response(conn);
+        }
+        let status = self.status;
+        let text = match self.detail {
+            Some(detail) => detail,
+            None => Cow::Borrowed(status.info().message),
+        };
+        conn.set_status_code(status).text_body(text.as_ref())
+    }
+}
+
+impl ProblemValue {
+    fn write_json(&self, out: &mut String) {
+        match self {
+            ProblemValue::Null => out.push_str("null"),
+            ProblemValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            ProblemValue::Int(n) => out.push_str(&n.to_string()),
+            // JSON に NaN / Infinity はないので null にする
+            ProblemValue::Float(n) if !n.is_finite() => out.push_str("null"),
+            ProblemValue::Float(n) => out.push_str(&n.to_string()),
+            ProblemValue::String(s) => json_escape_into(out, s),
+            ProblemValue::Raw(raw) => out.push_str(raw),
+        }
+    }
+}
+
+impl From<bool> for ProblemValue {
+    fn from(v: bool) -> Self {
+        ProblemValue::Bool(v)
+    }
+}
+
+macro_rules! problem_value_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for ProblemValue {
+                fn from(v: $t) -> Self {
+                    ProblemValue::Int(v as i64)
+                }
+            }
+        )*
+    };
+}
+
+problem_value_int!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<f64> for ProblemValue {
+    fn from(v: f64) -> Self {
+        ProblemValue::Float(v)
+    }
+}
+
+impl From<&'static str> for ProblemValue {
+    fn from(v: &'static str) -> Self {
+        ProblemValue::String(Cow::Borrowed(v))
+    }
+}
+
+impl From<String> for ProblemValue {
+    fn from(v: String) -> Self {
+        ProblemValue::String(Cow::Owned(v))
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Value> for ProblemValue {
+    fn from(v: serde_json::Value) -> Self {
+        ProblemValue::Raw(v.to_string())
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.detail {
+            Some(detail) => write!(f, "{} {}: {}", self.status.as_str(), self.title(), detail),
+            None => write!(f, "{} {}", self.status.as_str(), self.title()),
+        }
+    }
+}
+
+impl std::error::Error for Problem {}
+
+impl<C, R, W> IntoResponse<C, R, W> for Problem
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    fn into_response(self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        let body = self.to_json();
+        conn.set_status_code(self.status)
+            .add_header("Content-Type", PROBLEM_JSON)
+            .binary_body(body.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, io::Cursor};
+
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse};
+
+    type Done = Connection<(), Cursor<Vec<u8>>, Vec<u8>, ResponseReadyToSend>;
+
+    fn negotiate(problem: Problem, accept: Option<&str>) -> Done {
+        let accept = accept.map_or(String::new(), |a| format!("Accept: {}\r\n", a));
+        let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", accept);
+        let req = async {
+            HttpRequest::new(Cursor::new(raw.into_bytes()))
+                .parse_request_line()
+                .await?
+                .parse_request()
+                .await
+        }
+        .now_or_never()
+        .unwrap()
+        .ok()
+        .unwrap();
+        problem.negotiate(Connection::new((), req, HttpResponse::new(Vec::new())))
+    }
+
+    fn body(conn: &Done) -> &str {
+        std::str::from_utf8(conn.res.body().unwrap_or_default()).unwrap()
+    }
+
+    fn content_type(conn: &Done) -> Option<&str> {
+        conn.res.header_get("Content-Type").map(str::trim_start)
+    }
+
+    #[test]
+    fn defaults_type_and_title() {
+        let problem = Problem::new(HttpStatusCode::NotFound);
+        assert_eq!(
+            problem.to_json(),
+            r#"{"type":"about:blank","title":"Not Found","status":404}"#
+        );
+        assert_eq!(problem.to_string(), "404 Not Found");
+    }
+
+    #[test]
+    fn writes_every_member_in_order() {
+        let problem = Problem::new(HttpStatusCode::Forbidden)
+            .with_type("https://example.com/probs/out-of-credit")
+            .with_title("You do not have enough credit.")
+            .with_detail("Your current balance is 30, but that costs 50.")
+            .with_instance("/account/12345/msgs/abc")
+            .with_extension("balance", 30)
+            .with_extension("ratio", 0.5)
+            .with_extension("nan", f64::NAN)
+            .with_extension("ok", false)
+            .with_extension("none", ProblemValue::Null)
+            .with_extension(
+                "accounts",
+                ProblemValue::Raw(r#"["/account/1"]"#.to_string()),
+            );
+        assert_eq!(
+            problem.to_json(),
+            concat!(
+                r#"{"type":"https://example.com/probs/out-of-credit","#,
+                r#""title":"You do not have enough credit.","status":403,"#,
+                r#""detail":"Your current balance is 30, but that costs 50.","#,
+                r#""instance":"/account/12345/msgs/abc","#,
+                r#""balance":30,"ratio":0.5,"nan":null,"ok":false,"none":null,"accounts":["/account/1"]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn escapes_detail_and_extensions() {
+        let problem = Problem::new(HttpStatusCode::BadRequest)
+            .with_detail("line1\nline2\t\"quoted\" \\ \u{1}\u{1f}")
+            .with_extension("名前\"", "値は「日本語」です 🍵\r");
+        assert_eq!(
+            problem.to_json(),
+            concat!(
+                r#"{"type":"about:blank","title":"Bad Request","status":400,"#,
+                r#""detail":"line1\nline2\t\"quoted\" \\ \u0001\u001f","#,
+                r#""名前\"":"値は「日本語」です 🍵\r"}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn standard_member_names_are_not_extensions() {
+        let problem = Problem::new(HttpStatusCode::Conflict)
+            .with_extension("status", 200)
+            .with_extension("type", "x")
+            .with_extension("title", "x")
+            .with_extension("detail", "x")
+            .with_extension("instance", "x");
+        assert_eq!(
+            problem.to_json(),
+            r#"{"type":"about:blank","title":"Conflict","status":409}"#
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn output_is_valid_json() {
+        let problem = Problem::new(HttpStatusCode::BadRequest)
+            .with_detail("\u{0}\u{7}\u{1b}\"\\/\u{7f}\u{2028}é")
+            .with_extension("k\u{8}", "v\u{c}")
+            .with_extension("n", -12);
+        let value: serde_json::Value = serde_json::from_str(&problem.to_json()).unwrap();
+        assert_eq!(value["status"], 400);
+        assert_eq!(value["detail"], "\u{0}\u{7}\u{1b}\"\\/\u{7f}\u{2028}é");
+        assert_eq!(value["k\u{8}"], "v\u{c}");
+        assert_eq!(value["n"], -12);
+    }
+
+    #[test]
+    fn negotiates_problem_json() {
+        let problem = || Problem::new(HttpStatusCode::BadRequest).with_detail("missing field");
+        for accept in [
+            "application/problem+json",
+            "application/json",
+            "text/html;q=0.5, application/json",
+            "APPLICATION/PROBLEM+JSON; charset=utf-8",
+        ] {
+            let conn = negotiate(problem(), Some(accept));
+            assert_eq!(
+                conn.res.status_code(),
+                HttpStatusCode::BadRequest,
+                "{}",
+                accept
+            );
+            assert_eq!(content_type(&conn), Some(PROBLEM_JSON), "{}", accept);
+            assert!(
+                body(&conn).contains(r#""detail":"missing field""#),
+                "{}",
+                accept
+            );
+        }
+    }
+
+    #[test]
+    fn negotiates_plain_text() {
+        let problem = || Problem::new(HttpStatusCode::BadRequest).with_detail("missing field");
+        for accept in [
+            None,
+            Some("*/*"),
+            Some("text/html, application/json"),
+            Some("application/json;q=0.5, text/html"),
+            Some("application/json;q=0"),
+            Some("text/plain"),
+        ] {
+            let conn = negotiate(problem(), accept);
+            assert_eq!(
+                conn.res.status_code(),
+                HttpStatusCode::BadRequest,
+                "{:?}",
+                accept
+            );
+            assert_ne!(content_type(&conn), Some(PROBLEM_JSON), "{:?}", accept);
+            assert_eq!(body(&conn), "missing field", "{:?}", accept);
+        }
+
+        // detail が無ければ理由句
+        let conn = negotiate(Problem::new(HttpStatusCode::NotFound), Some("*/*"));
+        assert_eq!(body(&conn), "Not Found");
+    }
+}
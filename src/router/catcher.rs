@@ -0,0 +1,198 @@
+use std::{fmt, sync::Arc};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    http::{HttpMethod, HttpStatusCode},
+    response::PROBLEM_JSON,
+    utils::prefers_json,
+};
+
+/// どのステータスコードを捕まえるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Catch {
+    /// 特定のステータスコード
+    Code(u16),
+    /// 4xx なら 4、5xx なら 5
+    Class(u16),
+    /// 400 以上すべて
+    Any,
+}
+
+impl Catch {
+    #[inline]
+    pub const fn client_error() -> Self {
+        Catch::Class(4)
+    }
+
+    #[inline]
+    pub const fn server_error() -> Self {
+        Catch::Class(5)
+    }
+
+    /// 一致したかと、その優先度 (大きいほど具体的)
+    #[inline]
+    fn rank(&self, code: u16) -> Option<u8> {
+        match *self {
+            Catch::Code(c) if c == code => Some(2),
+            Catch::Class(class) if code / 100 == class => Some(1),
+            Catch::Any => Some(0),
+            _ => None,
+        }
+    }
+}
+
+impl From<u16> for Catch {
+    fn from(code: u16) -> Self {
+        Catch::Code(code)
+    }
+}
+
+impl From<HttpStatusCode> for Catch {
+    fn from(code: HttpStatusCode) -> Self {
+        Catch::Code(code.into())
+    }
+}
+
+/// エラーページを作るときに渡される情報
+#[derive(Debug)]
+pub struct ErrorInfo<'a> {
+    pub status: HttpStatusCode,
+    pub method: &'a HttpMethod,
+    pub path: &'a str,
+    /// 元のレスポンスが text/plain ならその本文
+    /// (`invalid_http` や抽出失敗のメッセージなど)
+    pub message: Option<&'a str>,
+}
+
+type Render = Arc<dyn Fn(&ErrorInfo) -> String + Send + Sync>;
+
+/// ステータスコードごとのエラーページ
+///
+/// 400 以上でボディが空か text/plain / problem+json のレスポンスを、`Accept` に合わせて HTML か JSON に差し替えます
+/// ハンドラが HTML や JSON で返したレスポンスには触りません
+/// コードの一致がクラス (4xx / 5xx) の一致より、クラスの一致が `Any` より優先されます
+#[derive(Clone, Default)]
+pub struct Catchers {
+    html: Vec<(Catch, Render)>,
+    json: Vec<(Catch, Render)>,
+}
+
+impl fmt::Debug for Catchers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Catchers")
+            .field(
+                "html",
+                &self.html.iter().map(|(c, _)| c).collect::<Vec<_>>(),
+            )
+            .field(
+                "json",
+                &self.json.iter().map(|(c, _)| c).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+enum Format {
+    Html,
+    Json,
+}
+
+impl Catchers {
+    pub fn new() -> Self {
+        Catchers::default()
+    }
+
+    /// HTML のエラーページを登録する
+    pub fn html<K, F>(mut self, catch: K, render: F) -> Self
+    where
+        K: Into<Catch>,
+        F: Fn(&ErrorInfo) -> String + Send + Sync + 'static,
+    {
+        self.html.push((catch.into(), Arc::new(render)));
+        self
+    }
+
+    /// JSON のエラーレスポンスを登録する
+    pub fn json<K, F>(mut self, catch: K, render: F) -> Self
+    where
+        K: Into<Catch>,
+        F: Fn(&ErrorInfo) -> String + Send + Sync + 'static,
+    {
+        self.json.push((catch.into(), Arc::new(render)));
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.html.is_empty() && self.json.is_empty()
+    }
+
+    /// 差し替え対象ならエラーページに差し替える
+    pub fn apply<C, R, W>(
+        &self,
+        mut conn: Connection<C, R, W, ResponseReadyToSend>,
+    ) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let code: u16 = conn.status_code().into();
+        if code < 400 || conn.is_sent() {
+            return conn;
+        }
+        let content_type = conn.res.header_get("Content-Type");
+        let message = match conn.res.body() {
+            Some([]) => None,
+            Some(body) if mime_is(content_type, "text/plain") => std::str::from_utf8(body).ok(),
+            Some(_) if mime_is(content_type, PROBLEM_JSON) => None,
+            _ => return conn,
+        };
+
+        let info = ErrorInfo {
+            status: conn.status_code(),
+            method: conn.req.method(),
+            path: conn.req.path_full(),
+            message,
+        };
+        let Some((content_type, body)) = self.render(&info, prefers_json(conn.req.header_value("Accept"))) else {
+            return conn;
+        };
+        conn.res.replace_body(content_type, body.as_bytes());
+        conn
+    }
+
+    /// エラーページを作る
+    /// 一致するものがなければ None
+    pub(crate) fn render(&self, info: &ErrorInfo, wants_json: bool) -> Option<(&'static str, String)> {
+        let code: u16 = info.status.into();
+        let (format, render) = match (wants_json, find(&self.html, code), find(&self.json, code)) {
+            (true, _, Some(json)) | (false, None, Some(json)) => (Format::Json, json),
+            (_, Some(html), _) => (Format::Html, html),
+            _ => return None,
+        };
+        let content_type = match format {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json; charset=utf-8",
+        };
+        Some((content_type, render(info)))
+    }
+}
+
+/// 一番具体的に一致したものを探す
+fn find(list: &[(Catch, Render)], code: u16) -> Option<&Render> {
+    list.iter()
+        .filter_map(|(catch, render)| catch.rank(code).map(|rank| (rank, render)))
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, render)| render)
+}
+
+#[inline]
+fn mime_is(content_type: Option<&str>, mime: &str) -> bool {
+    content_type.is_some_and(|ct| {
+        ct.split(';')
+            .next()
+            .is_some_and(|m| m.trim().eq_ignore_ascii_case(mime))
+    })
+}
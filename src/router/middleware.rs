@@ -0,0 +1,163 @@
+use std::sync::Arc;
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    router::Router,
+};
+
+/// ミドルウェアの前処理の結果
+/// `Ok(conn)` で次へ進み、`Err(res)` でそのレスポンスを返して打ち切ります
+pub type BeforeResult<C, R, W> = Result<Connection<C, R, W>, Connection<C, R, W, ResponseReadyToSend>>;
+
+/// 型消去したミドルウェア1層
+///
+/// ユーザーが実装するのは各サーバーの `Middleware` トレイトです
+/// (`server::tokio::Middleware` / `server::compio::Middleware`)
+pub trait MiddlewareLayer<C, R, W>
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    type BeforeFut: Future<Output = BeforeResult<C, R, W>>;
+    type AfterFut: Future<Output = Connection<C, R, W, ResponseReadyToSend>>;
+
+    fn before(&self, conn: Connection<C, R, W>) -> Self::BeforeFut;
+    fn after(&self, conn: Connection<C, R, W, ResponseReadyToSend>) -> Self::AfterFut;
+}
+
+/// クロージャで書く `before` だけのミドルウェア
+pub struct Before<F>(pub F);
+
+/// クロージャで書く `after` だけのミドルウェア
+pub struct After<F>(pub F);
+
+/// 積まれたミドルウェアの列
+///
+/// 積んだ順に `before` が呼ばれ、逆順に `after` が呼ばれます
+/// 打ち切ったミドルウェア自身と、それより内側の `after` は呼ばれません
+/// ハンドラそのものは型消去しないので、ハンドラの Future への追加の制約はありません
+pub struct MiddlewareStack<L> {
+    layers: Arc<[L]>,
+}
+
+impl<L> Clone for MiddlewareStack<L> {
+    fn clone(&self) -> Self {
+        MiddlewareStack { layers: self.layers.clone() }
+    }
+}
+
+impl<L> Default for MiddlewareStack<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> MiddlewareStack<L> {
+    pub fn new() -> Self {
+        MiddlewareStack { layers: Arc::from(Vec::new()) }
+    }
+
+    /// 一番内側 (ハンドラ寄り) に1層積む
+    pub fn push(self, layer: L) -> Self
+    where
+        L: Clone,
+    {
+        let mut layers = self.layers.to_vec();
+        layers.push(layer);
+        MiddlewareStack { layers: Arc::from(layers) }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// ミドルウェアを通してハンドラを呼ぶ
+    pub async fn run<C, R, W, F, Fut>(
+        &self,
+        mut conn: Connection<C, R, W>,
+        handler: F,
+    ) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        L: MiddlewareLayer<C, R, W>,
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+        F: FnOnce(Connection<C, R, W>) -> Fut,
+        Fut: Future<Output = Connection<C, R, W, ResponseReadyToSend>>,
+    {
+        let mut entered = 0;
+        for layer in self.layers.iter() {
+            match layer.before(conn).await {
+                Ok(next) => {
+                    conn = next;
+                    entered += 1;
+                },
+                Err(res) => return self.unwind(res, entered).await,
+            }
+        }
+        let res = handler(conn).await;
+        self.unwind(res, entered).await
+    }
+
+    /// before を通ったミドルウェアの after を逆順に呼ぶ
+    async fn unwind<C, R, W>(
+        &self,
+        mut res: Connection<C, R, W, ResponseReadyToSend>,
+        entered: usize,
+    ) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        L: MiddlewareLayer<C, R, W>,
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        for layer in self.layers[..entered].iter().rev() {
+            res = layer.after(res).await;
+        }
+        res
+    }
+
+    /// Router をこのミドルウェアで包む
+    pub fn wrap<Rt>(self, router: Rt) -> Layered<Rt, L> {
+        Layered { inner: router, stack: self }
+    }
+}
+
+/// ミドルウェアで包んだ Router
+pub struct Layered<Rt, L> {
+    inner: Rt,
+    stack: MiddlewareStack<L>,
+}
+
+impl<Rt: Clone, L> Clone for Layered<Rt, L> {
+    fn clone(&self) -> Self {
+        Layered {
+            inner: self.inner.clone(),
+            stack: self.stack.clone(),
+        }
+    }
+}
+
+impl<Rt, L, C, R, W> Router<C, R, W, ResponseReadyToSend> for Layered<Rt, L>
+where
+    Rt: Router<C, R, W, ResponseReadyToSend>,
+    L: MiddlewareLayer<C, R, W> + Send + Sync,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    #[inline(always)]
+    async fn router(&self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        self.stack.run(conn, |conn| self.inner.router(conn)).await
+    }
+
+    #[inline(always)]
+    async fn invalid_http(&self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        self.inner.invalid_http(conn).await
+    }
+}
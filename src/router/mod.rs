@@ -1,17 +1,47 @@
-use std::time::Duration;
+pub mod catcher;
+pub mod middleware;
+pub mod path;
+
+pub use catcher::{Catch, Catchers, ErrorInfo};
+pub use middleware::{After, Before, BeforeResult, Layered, MiddlewareLayer, MiddlewareStack};
+pub use path::{
+    FutureKind, IntoBoxedFuture, LocalFuture, PathParams, PathRouter, PathRouterBuilder, RouteError, SendFuture,
+};
+
+use std::{
+    any::Any,
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+    time::{Duration, Instant},
+};
 
 #[cfg(feature = "logging")]
 use crate::connection::ConnectionState;
 use futures_io::{AsyncRead, AsyncWrite};
-use futures_util::pin_mut;
+use futures_timer::Delay;
+use futures_util::{
+    AsyncWriteExt, FutureExt,
+    future::{Either, pending, poll_fn, select},
+    pin_mut,
+};
 #[cfg(feature = "logging")]
-use log::debug;
+use log::{debug, error};
 
 use crate::{
     connection::{Connection, NoneBody, ResponseReadyToSend},
     error::{ErrorPare, RouterError},
-    http::{code::HttpStatusCode, request::HttpRequest, response::HttpResponse},
-    utils::with_timeout,
+    http::{
+        ConnectionInfo, Extensions, HttpLimits, HttpMethod, TrustedProxies,
+        code::HttpStatusCode,
+        request::{HandlerTimeout, HttpRequest},
+        response::{HttpResponse, WriterHandle},
+    },
+    response::{PROBLEM_JSON, Problem},
+    server::shutdown::ShutdownHandle,
+    utils::{prefers_json, with_timeout},
 };
 
 pub trait Router<C, R, W, S>: Sync
@@ -26,14 +56,25 @@ where
         conn: Connection<C, R, W>,
     ) -> impl Future<Output = Connection<C, R, W, ResponseReadyToSend>> {
         async move {
-            conn.set_status_code(HttpStatusCode::BadRequest)
-                .text_body("Invalid HTTP request")
+            let (status, detail) = conn
+                .req
+                .parse_error()
+                .unwrap_or((HttpStatusCode::BadRequest, "Invalid HTTP request"));
+            let detail = detail.to_string();
+            Problem::new(status).with_detail(detail).negotiate(conn)
         }
     }
 }
 
 pub const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 pub const DEFAULT_HTTP_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// ボディの読み込みと、レスポンスの書き込みが進まないまま待つ上限
+pub const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// 接続ごとの状態を作るファクトリ
+/// 接続を受け付けたときに一度だけ、相手のアドレスを受け取って呼ばれます
+pub type ConnectionStateFactory = Arc<dyn Fn(Option<SocketAddr>, &mut Extensions) + Send + Sync>;
 
 #[derive(Clone)]
 pub struct KurosabiRouter<D, C: Clone + Sync = DefaultContext> {
@@ -41,25 +82,30 @@ pub struct KurosabiRouter<D, C: Clone + Sync = DefaultContext> {
     router: D,
     keep_alive_timeout: Duration,
     http_header_read_timeout: Duration,
+    body_read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    limits: HttpLimits,
+    handler_timeout: Option<Duration>,
+    handler_timeout_status: HttpStatusCode,
+    connection_state: Option<ConnectionStateFactory>,
+    catchers: Option<Arc<Catchers>>,
+    trusted_proxies: Option<Arc<TrustedProxies>>,
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl<D: Default> Default for KurosabiRouter<D, DefaultContext> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<D: Default> KurosabiRouter<D, DefaultContext> {
     pub fn new() -> Self {
-        Self {
-            context: DefaultContext::default(),
-            router: D::default(),
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_HTTP_HEADER_READ_TIMEOUT,
-        }
+        Self::with_context_and_router(D::default(), DefaultContext::default())
     }
 
     pub fn with_router(router: D) -> Self {
-        Self {
-            context: DefaultContext::default(),
-            router,
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_HTTP_HEADER_READ_TIMEOUT,
-        }
+        Self::with_context_and_router(router, DefaultContext::default())
     }
 }
 
@@ -68,20 +114,25 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
     where
         D: Default,
     {
-        KurosabiRouter {
-            context,
-            router: D::default(),
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_HTTP_HEADER_READ_TIMEOUT,
-        }
+        Self::with_context_and_router(D::default(), context)
     }
 
+    /// `new` / `with_router` / `with_context` もこれを呼びます
     pub fn with_context_and_router(router: D, context: C) -> Self {
         KurosabiRouter {
             context,
             router,
             keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
             http_header_read_timeout: DEFAULT_HTTP_HEADER_READ_TIMEOUT,
+            body_read_timeout: Some(DEFAULT_BODY_READ_TIMEOUT),
+            write_timeout: Some(DEFAULT_WRITE_TIMEOUT),
+            limits: HttpLimits::new(),
+            handler_timeout: None,
+            handler_timeout_status: HttpStatusCode::ServiceUnavailable,
+            connection_state: None,
+            catchers: None,
+            trusted_proxies: None,
+            shutdown: None,
         }
     }
 
@@ -92,6 +143,134 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
     pub fn set_http_header_read_timeout(&mut self, duration: Duration) {
         self.http_header_read_timeout = duration;
     }
+
+    /// ボディの読み込みが進まないまま待つ上限
+    /// 少しずつしか送ってこない相手 (slowloris) で接続を占有されないようにします
+    pub fn set_body_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.body_read_timeout = timeout;
+    }
+
+    /// レスポンスの書き込みが進まないまま待つ上限
+    /// 受け取らない相手にストリーミングなどで接続を占有されないようにします
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    /// リクエストの大きさの上限
+    pub fn set_limits(&mut self, limits: HttpLimits) {
+        self.limits = limits;
+    }
+
+    /// ハンドラのタイムアウト
+    /// 過ぎたらハンドラを打ち切って `handler_timeout_status` (既定は 503) を返し、接続を閉じます
+    /// None ならタイムアウトしません (既定)
+    pub fn set_handler_timeout(&mut self, timeout: Option<Duration>) {
+        self.handler_timeout = timeout;
+    }
+
+    /// ハンドラがタイムアウトしたときのステータスコード
+    /// 上流を待つプロキシなら 504 (GatewayTimeout) が適切です
+    pub fn set_handler_timeout_status(&mut self, status: HttpStatusCode) {
+        self.handler_timeout_status = status;
+    }
+
+    /// 接続ごとの状態のファクトリを追加する
+    /// 作った値は `Connection::connection_state` で型を指定して取り出せます
+    pub fn add_connection_state<T, F>(&mut self, factory: F)
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Option<SocketAddr>) -> T + Send + Sync + 'static,
+    {
+        self.connection_state = Some(chain_connection_state(
+            self.connection_state.take(),
+            factory,
+        ));
+    }
+
+    #[cfg(any(feature = "tokio-server", feature = "compio-server"))]
+    pub(crate) fn set_connection_state_factory(&mut self, factory: Option<ConnectionStateFactory>) {
+        self.connection_state = factory;
+    }
+
+    /// エラーページを設定する
+    pub fn set_catchers(&mut self, catchers: Catchers) {
+        self.catchers = (!catchers.is_empty()).then(|| Arc::new(catchers));
+    }
+
+    /// 信頼するプロキシ
+    /// 相手がこの中にあれば、転送ヘッダから本当のクライアントを求めます
+    pub fn set_trusted_proxies(&mut self, proxies: TrustedProxies) {
+        self.trusted_proxies = (!proxies.is_empty()).then(|| Arc::new(proxies));
+    }
+
+    /// 止めるときのハンドル
+    /// 止め始めたら keep-alive で次のリクエストを待っている接続を閉じ、処理中のレスポンスには `Connection: close` を付けます
+    pub fn set_shutdown(&mut self, shutdown: ShutdownHandle) {
+        self.shutdown = Some(shutdown);
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .is_some_and(ShutdownHandle::is_shutdown)
+    }
+
+    /// エラーページが設定されていれば差し替える
+    #[inline(always)]
+    fn catch<R, W>(&self, conn: Connection<C, R, W, ResponseReadyToSend>) -> Connection<C, R, W, ResponseReadyToSend>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        match &self.catchers {
+            Some(catchers) => catchers.apply(conn),
+            None => conn,
+        }
+    }
+}
+
+/// 前のファクトリの後に、型 `T` の状態を作るファクトリをつなげる
+pub(crate) fn chain_connection_state<T, F>(prev: Option<ConnectionStateFactory>, factory: F) -> ConnectionStateFactory
+where
+    T: Send + Sync + 'static,
+    F: Fn(Option<SocketAddr>) -> T + Send + Sync + 'static,
+{
+    Arc::new(move |peer, state: &mut Extensions| {
+        if let Some(prev) = &prev {
+            prev(peer, state);
+        }
+        state.insert(factory(peer));
+    })
+}
+
+#[inline]
+fn peer_info(peer: Option<SocketAddr>) -> ConnectionInfo {
+    match peer {
+        Some(peer) => ConnectionInfo::new().with_peer_addr(peer),
+        None => ConnectionInfo::new(),
+    }
+}
+
+/// このハンドラだけハンドラのタイムアウトを変える
+/// サーバーの `handler_timeout` より優先され、None ならタイムアウトしません
+///
+/// ```ignore
+/// let router = TokioPathRouter::builder()
+///     .get("/report", handler_timeout(Some(Duration::from_secs(60)), respond(report)))
+///     .get("/events", handler_timeout(None, events))
+///     .build()?;
+/// ```
+pub fn handler_timeout<C, R, W, F, Fut>(
+    timeout: Option<Duration>,
+    handler: F,
+) -> impl Fn(Connection<C, R, W>) -> Fut + Clone
+where
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    F: Fn(Connection<C, R, W>) -> Fut + Clone,
+    Fut: Future,
+{
+    move |conn| handler(conn.with_handler_timeout(timeout))
 }
 
 impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
@@ -101,8 +280,44 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
         R: AsyncRead + Unpin + 'static,
         W: AsyncWrite + Unpin + 'static,
     {
-        let req = HttpRequest::new(reader);
-        let res = HttpResponse::new(writer);
+        self.new_connection_with_peer(reader, writer, None)
+    }
+
+    /// 相手のアドレスを渡して接続ごとの状態を作る
+    #[inline(always)]
+    pub fn new_connection_with_peer<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+        peer: Option<SocketAddr>,
+    ) -> Connection<C, R, W, NoneBody>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        self.new_connection_with_info(reader, writer, peer_info(peer))
+    }
+
+    /// 接続の情報を渡して接続ごとの状態を作る
+    /// 独自のトランスポートでは、わかる範囲でアドレスを詰めて渡してください
+    #[inline(always)]
+    pub fn new_connection_with_info<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+        info: ConnectionInfo,
+    ) -> Connection<C, R, W, NoneBody>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let mut req = HttpRequest::new(reader);
+        if let Some(factory) = &self.connection_state {
+            factory(info.peer_addr(), req.connection_state_mut());
+        }
+        req.set_connection_info(info);
+        let mut res = HttpResponse::new(writer);
+        res.set_write_timeout(self.write_timeout);
         Connection::new(self.context.clone(), req, res)
     }
 
@@ -122,43 +337,208 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
         let http_header_read_timeout = http_header_read_timeout.unwrap_or(self.http_header_read_timeout);
         let Connection { c, req, res, .. } = connection;
         let res = res.reset();
-        let reader = req.into_reader();
-        let new_req = HttpRequest::new(reader);
+        let (reader, scope) = req.into_reader_and_scope();
+        let mut new_req = HttpRequest::new(reader).with_connection_scope(scope);
+        new_req.set_body_read_timeout(self.body_read_timeout);
+        new_req.set_limits(self.limits);
         let new_req_fut = new_req.parse_request_line();
         pin_mut!(new_req_fut);
-        let req_uf = match with_timeout(new_req_fut, keep_alive_timeout).await {
-            Ok(req) => match req {
+        // 次のリクエストを待っている間に止め始めたら、そのまま閉じる
+        let shutdown = async {
+            match &self.shutdown {
+                Some(shutdown) => shutdown.requested().await,
+                None => pending().await,
+            }
+        };
+        pin_mut!(shutdown);
+        let req_uf = match with_timeout(select(new_req_fut, shutdown), keep_alive_timeout).await {
+            Ok(Either::Left((req, _))) => match req {
                 Ok(r) => r,
                 Err(req_err) => {
                     let conn = Connection::new(c, req_err, res);
-                    match self.router.invalid_http(conn).await.flush().await {
-                        Ok(conn) => return RoutingResult::Continue(conn),
-                        Err(e) => return RoutingResult::CloseHaveConnection(e),
-                    }
+                    return self
+                        .finish(self.catch(self.router.invalid_http(conn).await))
+                        .await;
                 },
             },
+            Ok(Either::Right(_)) => return RoutingResult::Close(RouterError::Shutdown),
             Err(_) => return RoutingResult::Close(RouterError::KeepAliveTimeout),
         };
         let req_fut = req_uf.parse_request();
         pin_mut!(req_fut);
-        let req = match with_timeout(req_fut, http_header_read_timeout).await {
+        let mut req = match with_timeout(req_fut, http_header_read_timeout).await {
             Ok(r) => match r {
                 Ok(req) => req,
                 Err(r_err) => {
                     let conn = Connection::new(self.context.clone(), r_err, res);
-                    match self.router.invalid_http(conn).await.flush().await {
-                        Ok(conn) => return RoutingResult::Continue(conn),
-                        Err(e) => return RoutingResult::CloseHaveConnection(e),
-                    }
+                    return self
+                        .finish(self.catch(self.router.invalid_http(conn).await))
+                        .await;
                 },
             },
             Err(_) => return RoutingResult::Close(RouterError::Timeout),
         };
+        if let Some(proxies) = &self.trusted_proxies {
+            req.resolve_forwarded(proxies);
+        }
+        let mut res = res;
+        // HEAD はボディを送らない (Content-Length などはそのまま)
+        res.set_omit_body(*req.method() == HttpMethod::HEAD);
+        // パニックしたら Connection ごと失われるので、500 を返すのに要るものを先に控えておく
+        let method = req.method().clone();
+        let path: Box<str> = req.path_full().into();
+        let wants_json = prefers_json(req.header_value("Accept"));
+        let writer = res.writer_handle();
+        let handler_timeout = req.handler_timeout_handle();
         let conn = Connection::new(self.context.clone(), req, res);
-        match self.router.router(conn).await.flush().await {
-            Ok(conn) => RoutingResult::Continue(conn),
-            Err(e) => RoutingResult::CloseHaveConnection(e),
+        let mut res = match self
+            .run_handler(self.router.router(conn), &handler_timeout)
+            .await
+        {
+            HandlerOutcome::Done(res) => res,
+            HandlerOutcome::Panicked(payload) => {
+                #[cfg(feature = "logging")]
+                {
+                    let message = match payload.downcast_ref::<&str>() {
+                        Some(s) => s,
+                        None => payload
+                            .downcast_ref::<String>()
+                            .map_or("Box<dyn Any>", |s| s.as_str()),
+                    };
+                    error!(
+                        "handler panicked on {} {}: {}",
+                        method.as_str(),
+                        path,
+                        message
+                    );
+                }
+                drop(payload);
+                let status = HttpStatusCode::InternalServerError;
+                self.respond_aborted(writer, status, method, &path, wants_json)
+                    .await;
+                return RoutingResult::Close(RouterError::HandlerPanicked);
+            },
+            HandlerOutcome::TimedOut => {
+                #[cfg(feature = "logging")]
+                error!("handler timed out on {} {}", method.as_str(), path);
+                let status = self.handler_timeout_status;
+                self.respond_aborted(writer, status, method, &path, wants_json)
+                    .await;
+                return RoutingResult::Close(RouterError::HandlerTimeout);
+            },
+        };
+        res.res.release_writer_handle();
+        drop(writer);
+        self.finish(self.catch(res)).await
+    }
+
+    /// レスポンスを送って、次のリクエストに進むか決める
+    /// リクエストを読み切れていなければ、次のリクエストの境目がわからないので閉じます
+    /// 止め始めていても閉じます
+    #[inline(always)]
+    async fn finish<R, W>(
+        &self,
+        mut conn: Connection<C, R, W, ResponseReadyToSend>,
+    ) -> RoutingResult<Connection<C, R, W, NoneBody>>
+    where
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let close = if conn.req.must_close() {
+            Some(RouterError::RequestNotConsumed)
+        } else if self.is_shutdown() {
+            Some(RouterError::Shutdown)
+        } else {
+            None
+        };
+        if close.is_some() && !conn.is_sent() {
+            conn.res.header_add("Connection", "close");
         }
+        match (conn.flush().await, close) {
+            (Ok(_), Some(reason)) => RoutingResult::Close(reason),
+            (Ok(conn), None) => RoutingResult::Continue(conn),
+            (Err(e), _) => RoutingResult::CloseHaveConnection(e),
+        }
+    }
+
+    /// パニックを捕まえつつ、タイムアウトまでハンドラを動かす
+    /// タイムアウトはハンドラが `with_handler_timeout` で上書きしていればそちらを使います
+    async fn run_handler<F>(&self, handler: F, handler_timeout: &HandlerTimeout) -> HandlerOutcome<F::Output>
+    where
+        F: Future,
+    {
+        let handler = AssertUnwindSafe(handler).catch_unwind();
+        pin_mut!(handler);
+        let start = Instant::now();
+        let mut timer: Option<(Duration, Delay)> = None;
+        poll_fn(|cx| {
+            if let Poll::Ready(res) = handler.as_mut().poll(cx) {
+                return Poll::Ready(match res {
+                    Ok(res) => HandlerOutcome::Done(res),
+                    Err(payload) => HandlerOutcome::Panicked(payload),
+                });
+            }
+            // 上書きはハンドラの poll の中で起きるので、ここで見れば間に合う
+            let Some(timeout) = handler_timeout.get().unwrap_or(self.handler_timeout) else {
+                timer = None;
+                return Poll::Pending;
+            };
+            if timer.as_ref().is_none_or(|(t, _)| *t != timeout) {
+                timer = Some((timeout, Delay::new(timeout.saturating_sub(start.elapsed()))));
+            }
+            match timer.as_mut().map(|(_, delay)| Pin::new(delay).poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(HandlerOutcome::TimedOut),
+                _ => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// ハンドラが最後まで動かなかったときにエラーを返して接続を閉じる
+    /// 既に何か書き込んでいたら、壊れたレスポンスを送らないようにそのまま閉じます
+    async fn respond_aborted<W>(
+        &self,
+        writer: WriterHandle<W>,
+        status: HttpStatusCode,
+        method: HttpMethod,
+        path: &str,
+        wants_json: bool,
+    ) where
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let Some((writer, untouched)) = writer.recover() else {
+            return;
+        };
+        let mut res = HttpResponse::new(writer);
+        res.set_write_timeout(self.write_timeout);
+        if untouched {
+            let info = ErrorInfo {
+                status,
+                method: &method,
+                path,
+                message: None,
+            };
+            let (content_type, body) = self
+                .catchers
+                .as_ref()
+                .and_then(|catchers| catchers.render(&info, wants_json))
+                .unwrap_or_else(|| {
+                    let problem = Problem::new(status);
+                    if wants_json {
+                        (PROBLEM_JSON, problem.to_json())
+                    } else {
+                        ("text/plain; charset=utf-8", problem.title().to_string())
+                    }
+                });
+            res.set_status_code(status);
+            res.set_omit_body(method == HttpMethod::HEAD);
+            res.header_add("Connection", "close");
+            res.header_add("Content-Type", content_type);
+            res.binary_body(body.as_bytes());
+            res.response_line_write();
+            let _ = res.send().await;
+        }
+        let _ = res.writer().close().await;
     }
 
     #[inline(always)]
@@ -168,7 +548,52 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
         R: AsyncRead + Unpin + 'static,
         W: AsyncWrite + Unpin + 'static,
     {
-        let mut conn = self.new_connection(reader, writer);
+        self.new_connection_loop_with_peer(reader, writer, None)
+            .await
+    }
+
+    /// 相手のアドレスを渡して接続を処理する
+    /// アドレスは接続ごとの状態のファクトリに渡されます
+    #[inline(always)]
+    pub async fn new_connection_loop_with_peer<R, W>(&self, reader: R, writer: W, peer: Option<SocketAddr>)
+    where
+        D: Router<C, R, W, ResponseReadyToSend>,
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        self.new_connection_loop_with_info(reader, writer, peer_info(peer))
+            .await
+    }
+
+    /// 接続の情報を渡して接続を処理する
+    /// ハンドラからは `conn.connection_info()` で見えます
+    #[inline(always)]
+    pub async fn new_connection_loop_with_info<R, W>(&self, reader: R, writer: W, info: ConnectionInfo)
+    where
+        D: Router<C, R, W, ResponseReadyToSend>,
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        self.new_connection_loop_with_buffered(reader, writer, info, Vec::new())
+            .await
+    }
+
+    /// 先に読んでしまったバイト列を頭に戻して接続を処理する
+    /// PROXY ヘッダのように、HTTP の前に何かを読むトランスポートで使います
+    #[inline(always)]
+    pub async fn new_connection_loop_with_buffered<R, W>(
+        &self,
+        reader: R,
+        writer: W,
+        info: ConnectionInfo,
+        buffered: Vec<u8>,
+    ) where
+        D: Router<C, R, W, ResponseReadyToSend>,
+        R: AsyncRead + Unpin + 'static,
+        W: AsyncWrite + Unpin + 'static,
+    {
+        let mut conn = self.new_connection_with_info(reader, writer, info);
+        conn.req.set_buffered(buffered);
         loop {
             conn = match self.routing(conn, None, None).await {
                 RoutingResult::Continue(c) => {
@@ -195,6 +620,12 @@ impl<D, C: Clone + Sync> KurosabiRouter<D, C> {
     }
 }
 
+enum HandlerOutcome<T> {
+    Done(T),
+    Panicked(Box<dyn Any + Send>),
+    TimedOut,
+}
+
 pub enum RoutingResult<T> {
     Continue(T),
     CloseHaveConnection(ErrorPare<T>),
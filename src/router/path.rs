@@ -0,0 +1,1190 @@
+use std::{fmt, sync::Arc};
+
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::{
+    FutureExt,
+    future::{BoxFuture, LocalBoxFuture},
+};
+
+use crate::{
+    connection::{Connection, ResponseReadyToSend},
+    http::{HttpMethod, HttpStatusCode},
+    response::{HandlerResponse, IntoResponse},
+    router::Router,
+    utils::url_decode_fast,
+};
+
+/// ルーターがキャプチャしたパスパラメータ
+/// 値は URL デコード済み
+#[derive(Debug, Clone, Default)]
+pub struct PathParams {
+    params: Vec<(Arc<str>, String)>,
+}
+
+impl PathParams {
+    pub const fn new() -> Self {
+        PathParams { params: Vec::new() }
+    }
+
+    #[inline]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| &**k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.params.iter().map(|(k, v)| (&**k, v.as_str()))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.params.is_empty()
+    }
+
+    #[inline]
+    pub(crate) fn extend(&mut self, params: Vec<(Arc<str>, String)>) {
+        self.params.extend(params);
+    }
+
+    #[inline]
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.params.truncate(len);
+    }
+}
+
+/// ルート登録時のエラー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    /// パターンが不正
+    InvalidPattern { pattern: String, reason: &'static str },
+    /// 既存のルートと衝突している
+    Conflict { pattern: String, existing: String },
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::InvalidPattern { pattern, reason } => {
+                write!(f, "RouteError: invalid pattern `{}` - {}", pattern, reason)
+            },
+            RouteError::Conflict { pattern, existing } => {
+                write!(f, "RouteError: `{}` conflicts with `{}`", pattern, existing)
+            },
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// ハンドラが返す Future をどう包むか
+///
+/// tokio のようにスレッドをまたいで動かすなら `SendFuture` (既定)、
+/// compio のように1スレッドで動かすなら `LocalFuture` を使います
+pub trait FutureKind: 'static {
+    type Boxed<T>: Future<Output = T>;
+
+    /// 包んだ Future の結果を変換する
+    fn map<T: 'static, U: 'static>(fut: Self::Boxed<T>, f: fn(T) -> U) -> Self::Boxed<U>;
+}
+
+/// `Send` な Future だけを受け付ける (tokio 用)
+pub enum SendFuture {}
+
+/// `Send` でない Future も受け付ける (compio 用)
+pub enum LocalFuture {}
+
+impl FutureKind for SendFuture {
+    type Boxed<T> = BoxFuture<'static, T>;
+
+    #[inline(always)]
+    fn map<T: 'static, U: 'static>(fut: Self::Boxed<T>, f: fn(T) -> U) -> Self::Boxed<U> {
+        Box::pin(fut.map(f))
+    }
+}
+
+impl FutureKind for LocalFuture {
+    type Boxed<T> = LocalBoxFuture<'static, T>;
+
+    #[inline(always)]
+    fn map<T: 'static, U: 'static>(fut: Self::Boxed<T>, f: fn(T) -> U) -> Self::Boxed<U> {
+        Box::pin(fut.map(f))
+    }
+}
+
+/// `K` の形に包めるハンドラの Future
+/// `SendFuture` なら `Send` な Future、`LocalFuture` ならどんな Future でも実装されています
+#[diagnostic::on_unimplemented(
+    message = "the handler future cannot be used with a `{K}` router",
+    note = "`SendFuture` routers (e.g. `TokioPathRouter`) need a `Send` future; use `CompioPathRouter` for compio handlers"
+)]
+pub trait IntoBoxedFuture<K: FutureKind>: Future + 'static {
+    fn into_boxed(self) -> K::Boxed<Self::Output>;
+}
+
+impl<F> IntoBoxedFuture<SendFuture> for F
+where
+    F: Future + Send + 'static,
+{
+    #[inline(always)]
+    fn into_boxed(self) -> BoxFuture<'static, F::Output> {
+        Box::pin(self)
+    }
+}
+
+impl<F> IntoBoxedFuture<LocalFuture> for F
+where
+    F: Future + 'static,
+{
+    #[inline(always)]
+    fn into_boxed(self) -> LocalBoxFuture<'static, F::Output> {
+        Box::pin(self)
+    }
+}
+
+type BoxedHandler<C, R, W, K> =
+    Arc<dyn Fn(Connection<C, R, W>) -> <K as FutureKind>::Boxed<HandlerResponse<C, R, W>> + Send + Sync>;
+
+/// ネストしたルーター
+/// どのルートにもマッチしなければ Err でコネクションを返します
+type BoxedMount<C, R, W, K> = Arc<
+    dyn Fn(
+            Connection<C, R, W>,
+        ) -> <K as FutureKind>::Boxed<Result<Connection<C, R, W, ResponseReadyToSend>, Connection<C, R, W>>>
+        + Send
+        + Sync,
+>;
+
+/// 登録したルート (メソッド, パターン, ハンドラ)
+type Route<C, R, W, K> = (HttpMethod, String, BoxedHandler<C, R, W, K>);
+/// 静的セグメントとその子ノード
+type StaticChild<C, R, W, K> = (Box<str>, Node<C, R, W, K>);
+/// `*name` の名前とそのハンドラ
+type WildcardChild<C, R, W, K> = (Arc<str>, Endpoint<C, R, W, K>);
+/// パスにはマッチしたがメソッドを受け付けなかったエンドポイントと、そのときのパラメータ
+type Mismatch<'n, 'a, C, R, W, K> = Option<(&'n Endpoint<C, R, W, K>, Vec<(Arc<str>, &'a str)>)>;
+
+#[inline]
+fn box_handler<C, R, W, K, F, Fut>(handler: F) -> BoxedHandler<C, R, W, K>
+where
+    C: 'static,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind,
+    F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+    Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+{
+    Arc::new(move |conn| {
+        K::map(
+            handler(conn).into_boxed(),
+            IntoResponse::into_handler_response,
+        )
+    })
+}
+
+/// ハンドラを呼んで、返した値からレスポンスを作る
+/// ハンドラが Connection を手放していたら取り戻して書き込みます
+#[inline]
+async fn call_handler<C, R, W, K>(
+    handler: &BoxedHandler<C, R, W, K>,
+    mut conn: Connection<C, R, W>,
+) -> Connection<C, R, W, ResponseReadyToSend>
+where
+    C: Clone,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind,
+{
+    let recovery = conn.recovery();
+    recovery.finish(handler(conn).await)
+}
+
+/// パスパターンでハンドラを振り分けるルーター
+///
+/// - `/users/:id` のように `:name` で1セグメントをキャプチャ
+/// - `/files/*rest` のように `*name` で残り全部をキャプチャ (最後のセグメントのみ)
+/// - 優先順位は 静的 > `:param` > `*wildcard`
+/// - メソッドが合わなければ次の候補を探し、どれも合わなければ 405
+///
+/// キャプチャした値は `conn.param("id")` で取得できます
+///
+/// `nest` / `mount` で別のルーターやハンドラをプレフィックスの下にマウントできます
+/// マウント先からはプレフィックスを除いたパスが `path_segs` / `req.path()` で見えます
+///
+/// `K` はハンドラの Future の包み方です
+/// tokio では `SendFuture` (`TokioPathRouter`)、compio では `LocalFuture` (`CompioPathRouter`) を使います
+///
+/// ```ignore
+/// let router = PathRouter::builder()
+///     .get("/", |conn| async move { conn.text_body("index") })
+///     .get("/users/:id", |conn| async move {
+///         let id = conn.param("id").unwrap_or_default().to_string();
+///         conn.text_body(id)
+///     })
+///     .build()
+///     .expect("route conflict");
+///
+/// KurosabiTokioServerBuilder::default().handler_and_build(router).run().await
+/// ```
+pub struct PathRouter<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind = SendFuture> {
+    inner: Arc<PathRouterInner<C, R, W, K>>,
+}
+
+struct PathRouterInner<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    root: Node<C, R, W, K>,
+    fallback: Option<BoxedHandler<C, R, W, K>>,
+    /// 登録された全メソッド (ネストしたルーターも含む)
+    methods: Vec<HttpMethod>,
+    /// `OPTIONS *` に返す Allow
+    allow_all: String,
+}
+
+impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> Clone
+    for PathRouter<C, R, W, K>
+{
+    fn clone(&self) -> Self {
+        PathRouter { inner: self.inner.clone() }
+    }
+}
+
+/// `PathRouter` のビルダー
+/// 衝突検出は `build` でまとめて行います
+pub struct PathRouterBuilder<
+    C,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind = SendFuture,
+> {
+    routes: Vec<Route<C, R, W, K>>,
+    mounts: Vec<Mount<C, R, W, K>>,
+    fallback: Option<BoxedHandler<C, R, W, K>>,
+}
+
+impl<C: 'static, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> Default
+    for PathRouterBuilder<C, R, W, K>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: 'static, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind>
+    PathRouter<C, R, W, K>
+{
+    pub fn builder() -> PathRouterBuilder<C, R, W, K> {
+        PathRouterBuilder::new()
+    }
+}
+
+impl<C, R, W, K> PathRouterBuilder<C, R, W, K>
+where
+    C: 'static,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind,
+{
+    pub fn new() -> Self {
+        PathRouterBuilder {
+            routes: Vec::new(),
+            mounts: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// method と pattern にハンドラを登録
+    pub fn route<F, Fut>(mut self, method: HttpMethod, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.routes
+            .push((method, pattern.to_string(), box_handler(handler)));
+        self
+    }
+
+    pub fn get<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::GET, pattern, handler)
+    }
+
+    pub fn post<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::POST, pattern, handler)
+    }
+
+    pub fn put<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::PUT, pattern, handler)
+    }
+
+    pub fn delete<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::DELETE, pattern, handler)
+    }
+
+    pub fn patch<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::PATCH, pattern, handler)
+    }
+
+    pub fn head<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::HEAD, pattern, handler)
+    }
+
+    pub fn options<F, Fut>(self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.route(HttpMethod::OPTIONS, pattern, handler)
+    }
+
+    /// prefix の下にハンドラをマウントする
+    /// ハンドラからはプレフィックスを除いたパスが見えます
+    /// ハンドラがどのメソッドに応えるかは分からないので `OPTIONS *` の Allow には含まれません
+    /// 含めたいときは `mount_with_methods` を使ってください
+    pub fn mount<F, Fut>(self, prefix: &str, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.mount_with_methods(prefix, &[], handler)
+    }
+
+    /// `mount` と同じだが、ハンドラが応えるメソッドを `OPTIONS *` の Allow に加える
+    pub fn mount_with_methods<F, Fut>(mut self, prefix: &str, methods: &[HttpMethod], handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.mounts.push(Mount {
+            prefix: prefix.to_string(),
+            target: MountTarget::Handler(box_handler(handler)),
+            methods: methods.to_vec(),
+        });
+        self
+    }
+
+    #[inline]
+    fn push_router(mut self, prefix: &str, methods: Vec<HttpMethod>, router: BoxedMount<C, R, W, K>) -> Self {
+        self.mounts.push(Mount {
+            prefix: prefix.to_string(),
+            target: MountTarget::Router(router),
+            methods,
+        });
+        self
+    }
+
+    /// どのルートにもマッチしなかったときのハンドラ
+    /// 未設定なら 404
+    pub fn fallback<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Connection<C, R, W>) -> Fut + Send + Sync + 'static,
+        Fut: IntoBoxedFuture<K, Output: IntoResponse<C, R, W> + 'static>,
+    {
+        self.fallback = Some(box_handler(handler));
+        self
+    }
+
+    /// ルーティングツリーを構築する
+    /// 不正なパターンや衝突するルートがあればエラー
+    pub fn build(self) -> Result<PathRouter<C, R, W, K>, RouteError> {
+        let mut root = Node::new();
+        let mut methods = Vec::new();
+        for (method, pattern, handler) in self.routes {
+            if !methods.contains(&method) {
+                methods.push(method.clone());
+            }
+            root.insert(method, &pattern, handler)?;
+        }
+        for mount in self.mounts {
+            for method in &mount.methods {
+                if !methods.contains(method) {
+                    methods.push(method.clone());
+                }
+            }
+            root.insert_mount(mount)?;
+        }
+        let allow_all = allow_header(&methods);
+        Ok(PathRouter {
+            inner: Arc::new(PathRouterInner {
+                root,
+                fallback: self.fallback,
+                methods,
+                allow_all,
+            }),
+        })
+    }
+}
+
+// ネストしたルーターの Future は中身に合わせて包み方が変わるので、`FutureKind` ごとに実装しています
+
+impl<C, R, W> PathRouterBuilder<C, R, W, SendFuture>
+where
+    C: Clone + Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// prefix の下に別の `PathRouter` をネストする
+    /// 子ルーターにマッチせず子に fallback も無ければ、この親の fallback (または 404) が使われます
+    pub fn nest(self, prefix: &str, router: PathRouter<C, R, W>) -> Self {
+        let methods = router.inner.methods.clone();
+        self.push_router(
+            prefix,
+            methods,
+            Arc::new(move |conn| {
+                let router = router.clone();
+                Box::pin(async move { router.try_dispatch(conn).await })
+            }),
+        )
+    }
+
+    /// `nest` と同じだが、子ルーターには context を渡す
+    /// 子の処理が終わると親のコンテキストに戻ります
+    pub fn nest_with_context<C2>(self, prefix: &str, router: PathRouter<C2, R, W>, context: C2) -> Self
+    where
+        C2: Clone + Send + Sync + 'static,
+    {
+        let methods = router.inner.methods.clone();
+        self.push_router(
+            prefix,
+            methods,
+            Arc::new(move |conn| {
+                let router = router.clone();
+                let context = context.clone();
+                Box::pin(async move { nested_with_context(&router, conn, context).await })
+            }),
+        )
+    }
+}
+
+impl<C, R, W> PathRouterBuilder<C, R, W, LocalFuture>
+where
+    C: Clone + 'static,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+{
+    /// prefix の下に別の `PathRouter` をネストする
+    /// 子ルーターにマッチせず子に fallback も無ければ、この親の fallback (または 404) が使われます
+    pub fn nest(self, prefix: &str, router: PathRouter<C, R, W, LocalFuture>) -> Self {
+        let methods = router.inner.methods.clone();
+        self.push_router(
+            prefix,
+            methods,
+            Arc::new(move |conn| {
+                let router = router.clone();
+                Box::pin(async move { router.try_dispatch(conn).await })
+            }),
+        )
+    }
+
+    /// `nest` と同じだが、子ルーターには context を渡す
+    /// 子の処理が終わると親のコンテキストに戻ります
+    pub fn nest_with_context<C2>(self, prefix: &str, router: PathRouter<C2, R, W, LocalFuture>, context: C2) -> Self
+    where
+        C2: Clone + Send + Sync + 'static,
+    {
+        let methods = router.inner.methods.clone();
+        self.push_router(
+            prefix,
+            methods,
+            Arc::new(move |conn| {
+                let router = router.clone();
+                let context = context.clone();
+                Box::pin(async move { nested_with_context(&router, conn, context).await })
+            }),
+        )
+    }
+}
+
+/// 子ルーターのコンテキストに差し替えて動かし、終わったら親のコンテキストに戻す
+async fn nested_with_context<C, C2, R, W, K>(
+    router: &PathRouter<C2, R, W, K>,
+    conn: Connection<C, R, W>,
+    context: C2,
+) -> Result<Connection<C, R, W, ResponseReadyToSend>, Connection<C, R, W>>
+where
+    C2: Clone,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind,
+{
+    let (parent, conn) = conn.replace_context(context);
+    match router.try_dispatch(conn).await {
+        Ok(conn) => Ok(conn.replace_context(parent).1),
+        Err(conn) => Err(conn.replace_context(parent).1),
+    }
+}
+
+/// Allow ヘッダの値を作る
+/// OPTIONS はルーターが自動で応答するので常に含めます
+/// GET があれば HEAD も自動で応答します
+fn allow_header(methods: &[HttpMethod]) -> String {
+    let mut allow: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+    if methods.contains(&HttpMethod::GET) && !methods.contains(&HttpMethod::HEAD) {
+        allow.push(HttpMethod::HEAD.as_str());
+    }
+    if !methods.contains(&HttpMethod::OPTIONS) {
+        allow.push(HttpMethod::OPTIONS.as_str());
+    }
+    allow.join(", ")
+}
+
+/// セグメント単位のトライ木のノード
+struct Node<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    /// 静的セグメントの子 (セグメントでソート済み)
+    statics: Vec<StaticChild<C, R, W, K>>,
+    param: Option<ParamChild<C, R, W, K>>,
+    wildcard: Option<WildcardChild<C, R, W, K>>,
+    endpoint: Option<Endpoint<C, R, W, K>>,
+    /// このノード以下をまとめて渡すマウント
+    mount: Option<Mount<C, R, W, K>>,
+}
+
+struct Mount<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    prefix: String,
+    target: MountTarget<C, R, W, K>,
+    /// マウント先が持つメソッド (`OPTIONS *` 用)
+    methods: Vec<HttpMethod>,
+}
+
+/// マウント先
+enum MountTarget<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    Handler(BoxedHandler<C, R, W, K>),
+    Router(BoxedMount<C, R, W, K>),
+}
+
+/// パス検索の結果
+enum Found<'n, 'a, C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    Endpoint(&'n Endpoint<C, R, W, K>),
+    /// マウントと、その下に残ったパス
+    Mount(&'n Mount<C, R, W, K>, Option<&'a str>),
+}
+
+struct ParamChild<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    name: Arc<str>,
+    /// このパラメータを最初に登録したパターン (衝突時の表示用)
+    pattern: String,
+    node: Box<Node<C, R, W, K>>,
+}
+
+/// 1つのパスに紐づくメソッドごとのハンドラ
+struct Endpoint<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> {
+    pattern: String,
+    handlers: Vec<(HttpMethod, BoxedHandler<C, R, W, K>)>,
+    /// 405 と OPTIONS で返す Allow ヘッダの値
+    allow: String,
+}
+
+impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> Endpoint<C, R, W, K> {
+    fn new(pattern: &str) -> Self {
+        Endpoint {
+            pattern: pattern.to_string(),
+            handlers: Vec::new(),
+            allow: String::new(),
+        }
+    }
+
+    fn add(&mut self, method: HttpMethod, pattern: &str, handler: BoxedHandler<C, R, W, K>) -> Result<(), RouteError> {
+        if self.handlers.iter().any(|(m, _)| *m == method) {
+            return Err(RouteError::Conflict {
+                pattern: format!("{} {}", method.as_str(), pattern),
+                existing: format!("{} {}", method.as_str(), self.pattern),
+            });
+        }
+        self.handlers.push((method, handler));
+        let methods: Vec<HttpMethod> = self.handlers.iter().map(|(m, _)| m.clone()).collect();
+        self.allow = allow_header(&methods);
+        Ok(())
+    }
+
+    /// HEAD のハンドラが無ければ GET のハンドラを使う
+    /// (ボディは HttpResponse 側で捨てられます)
+    #[inline]
+    fn handler(&self, method: &HttpMethod) -> Option<&BoxedHandler<C, R, W, K>> {
+        let find = |method: &HttpMethod| {
+            self.handlers
+                .iter()
+                .find(|(m, _)| m == method)
+                .map(|(_, h)| h)
+        };
+        match find(method) {
+            None if *method == HttpMethod::HEAD => find(&HttpMethod::GET),
+            found => found,
+        }
+    }
+
+    /// method を受け付けるか
+    /// 受け付けなければ、405 を返すために最初に見つかったものを mismatch に覚えておきます
+    #[inline]
+    fn accepts<'n, 'a>(
+        &'n self,
+        method: &HttpMethod,
+        params: &[(Arc<str>, &'a str)],
+        mismatch: &mut Mismatch<'n, 'a, C, R, W, K>,
+    ) -> bool {
+        if self.handler(method).is_some() {
+            return true;
+        }
+        if mismatch.is_none() {
+            *mismatch = Some((self, params.to_vec()));
+        }
+        false
+    }
+}
+
+impl<C, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> Node<C, R, W, K> {
+    fn new() -> Self {
+        Node {
+            statics: Vec::new(),
+            param: None,
+            wildcard: None,
+            endpoint: None,
+            mount: None,
+        }
+    }
+
+    fn insert_mount(&mut self, mount: Mount<C, R, W, K>) -> Result<(), RouteError> {
+        let prefix = mount.prefix.as_str();
+        let invalid = |reason| RouteError::InvalidPattern { pattern: prefix.to_string(), reason };
+        let rest = prefix
+            .strip_prefix('/')
+            .ok_or_else(|| invalid("pattern must start with `/`"))?;
+        let rest = rest.trim_end_matches('/');
+
+        let mut node = self;
+        if !rest.is_empty() {
+            for seg in rest.split('/') {
+                if seg.starts_with('*') {
+                    return Err(invalid("mount prefix cannot contain a wildcard"));
+                }
+                node = node.child_mut(seg, prefix)?;
+            }
+        }
+        if let Some(existing) = &node.mount {
+            return Err(RouteError::Conflict {
+                pattern: prefix.to_string(),
+                existing: existing.prefix.clone(),
+            });
+        }
+        if let Some((_, endpoint)) = &node.wildcard {
+            return Err(RouteError::Conflict {
+                pattern: prefix.to_string(),
+                existing: endpoint.pattern.clone(),
+            });
+        }
+        node.mount = Some(mount);
+        Ok(())
+    }
+
+    /// 静的セグメントか `:param` の子ノードを取得 (無ければ作る)
+    fn child_mut(&mut self, seg: &str, pattern: &str) -> Result<&mut Node<C, R, W, K>, RouteError> {
+        if let Some(name) = seg.strip_prefix(':') {
+            if name.is_empty() {
+                return Err(RouteError::InvalidPattern {
+                    pattern: pattern.to_string(),
+                    reason: "parameter name is empty",
+                });
+            }
+            let param = self.param.get_or_insert_with(|| ParamChild {
+                name: Arc::from(name),
+                pattern: pattern.to_string(),
+                node: Box::new(Node::new()),
+            });
+            if &*param.name != name {
+                return Err(RouteError::Conflict {
+                    pattern: pattern.to_string(),
+                    existing: param.pattern.clone(),
+                });
+            }
+            Ok(&mut param.node)
+        } else {
+            let idx = match self.statics.binary_search_by(|(s, _)| (**s).cmp(seg)) {
+                Ok(idx) => idx,
+                Err(idx) => {
+                    self.statics.insert(idx, (Box::from(seg), Node::new()));
+                    idx
+                },
+            };
+            Ok(&mut self.statics[idx].1)
+        }
+    }
+
+    fn insert(
+        &mut self,
+        method: HttpMethod,
+        pattern: &str,
+        handler: BoxedHandler<C, R, W, K>,
+    ) -> Result<(), RouteError> {
+        let invalid = |reason| RouteError::InvalidPattern { pattern: pattern.to_string(), reason };
+        let rest = pattern
+            .strip_prefix('/')
+            .ok_or_else(|| invalid("pattern must start with `/`"))?;
+        let segs: Vec<&str> = rest.split('/').collect();
+
+        let mut node = self;
+        for (i, seg) in segs.iter().enumerate() {
+            if let Some(name) = seg.strip_prefix('*') {
+                if name.is_empty() {
+                    return Err(invalid("wildcard name is empty"));
+                }
+                if i + 1 != segs.len() {
+                    return Err(invalid("wildcard must be the last segment"));
+                }
+                if let Some(mount) = &node.mount {
+                    return Err(RouteError::Conflict {
+                        pattern: pattern.to_string(),
+                        existing: mount.prefix.clone(),
+                    });
+                }
+                let (wc_name, endpoint) = node
+                    .wildcard
+                    .get_or_insert_with(|| (Arc::from(name), Endpoint::new(pattern)));
+                if &**wc_name != name {
+                    return Err(RouteError::Conflict {
+                        pattern: pattern.to_string(),
+                        existing: endpoint.pattern.clone(),
+                    });
+                }
+                return endpoint.add(method, pattern, handler);
+            } else {
+                node = node.child_mut(seg, pattern)?;
+            }
+        }
+        node.endpoint
+            .get_or_insert_with(|| Endpoint::new(pattern))
+            .add(method, pattern, handler)
+    }
+
+    /// rest は先頭の `/` を除いた残りのパス
+    /// None はセグメントを使い切った状態
+    /// 優先順位は 静的 > `:param` > `*wildcard` > マウント
+    /// method を受け付けないエンドポイントは飛ばして次の候補を探し、最初のものを mismatch に残します
+    fn find<'n, 'a>(
+        &'n self,
+        rest: Option<&'a str>,
+        method: &HttpMethod,
+        params: &mut Vec<(Arc<str>, &'a str)>,
+        mismatch: &mut Mismatch<'n, 'a, C, R, W, K>,
+    ) -> Option<Found<'n, 'a, C, R, W, K>> {
+        let Some(rest) = rest else {
+            if let Some(endpoint) = &self.endpoint
+                && endpoint.accepts(method, params, mismatch)
+            {
+                return Some(Found::Endpoint(endpoint));
+            }
+            if let Some((name, endpoint)) = &self.wildcard {
+                params.push((name.clone(), ""));
+                if endpoint.accepts(method, params, mismatch) {
+                    return Some(Found::Endpoint(endpoint));
+                }
+                params.pop();
+            }
+            return self.mount.as_ref().map(|mount| Found::Mount(mount, None));
+        };
+        let (seg, tail) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+
+        if let Ok(idx) = self.statics.binary_search_by(|(s, _)| (**s).cmp(seg))
+            && let Some(found) = self.statics[idx].1.find(tail, method, params, mismatch)
+        {
+            return Some(found);
+        }
+        if let Some(param) = &self.param
+            && !seg.is_empty()
+        {
+            let len = params.len();
+            params.push((param.name.clone(), seg));
+            if let Some(found) = param.node.find(tail, method, params, mismatch) {
+                return Some(found);
+            }
+            params.truncate(len);
+        }
+        if let Some((name, endpoint)) = &self.wildcard {
+            params.push((name.clone(), rest));
+            if endpoint.accepts(method, params, mismatch) {
+                return Some(Found::Endpoint(endpoint));
+            }
+            params.pop();
+        }
+        self.mount
+            .as_ref()
+            .map(|mount| Found::Mount(mount, Some(rest)))
+    }
+}
+
+impl<C: Clone, R: AsyncRead + Unpin + 'static, W: AsyncWrite + Unpin + 'static, K: FutureKind> PathRouter<C, R, W, K> {
+    /// リクエストをマッチしたハンドラに渡す
+    /// 静的セグメントのルートがメソッドを受け付けなければ `:param` などの候補を探し、
+    /// どれも受け付けなければ最初にマッチしたルートの Allow 付きの 405
+    /// OPTIONS はハンドラが無ければ Allow を返して自動で応答します
+    /// どこにもマッチしなければ fallback か 404
+    pub async fn dispatch(&self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        match self.try_dispatch(conn).await {
+            Ok(conn) => conn,
+            Err(conn) => conn.set_status_code(HttpStatusCode::NotFound).no_body(),
+        }
+    }
+
+    /// `dispatch` と同じだが、どこにもマッチせず fallback も無い場合は Err でコネクションを返す
+    /// 親ルーターの fallback に回すときに使います
+    pub async fn try_dispatch(
+        &self,
+        mut conn: Connection<C, R, W>,
+    ) -> Result<Connection<C, R, W, ResponseReadyToSend>, Connection<C, R, W>> {
+        if *conn.req.method() == HttpMethod::OPTIONS && conn.req.path() == "*" {
+            return Ok(conn
+                .add_header("Allow", self.inner.allow_all.as_str())
+                .no_body());
+        }
+        let found = {
+            let path = conn.req.path();
+            let mut raw = Vec::new();
+            let mut mismatch = None;
+            let found = path
+                .strip_prefix('/')
+                .and_then(|rest| {
+                    self.inner
+                        .root
+                        .find(Some(rest), conn.req.method(), &mut raw, &mut mismatch)
+                })
+                .or_else(|| {
+                    mismatch.map(|(endpoint, params)| {
+                        raw = params;
+                        Found::Endpoint(endpoint)
+                    })
+                });
+            let params: Vec<(Arc<str>, String)> = raw
+                .into_iter()
+                .map(|(name, value)| (name, url_decode_fast(value).into_owned()))
+                .collect();
+            found.map(|found| match found {
+                Found::Endpoint(endpoint) => (Found::Endpoint(endpoint), params, 0),
+                // マウント先に渡すときに取り除くバイト数
+                Found::Mount(mount, rest) => (
+                    Found::Mount(mount, None),
+                    params,
+                    path.len() - rest.map_or(0, |r| r.len() + 1),
+                ),
+            })
+        };
+
+        match found {
+            Some((Found::Endpoint(endpoint), params, _)) => {
+                conn.req.params_mut().extend(params);
+                Ok(match endpoint.handler(conn.req.method()) {
+                    Some(handler) => call_handler::<_, _, _, K>(handler, conn).await,
+                    None if *conn.req.method() == HttpMethod::OPTIONS => {
+                        conn.add_header("Allow", endpoint.allow.as_str()).no_body()
+                    },
+                    None => conn
+                        .set_status_code(HttpStatusCode::MethodNotAllowed)
+                        .add_header("Allow", endpoint.allow.as_str())
+                        .no_body(),
+                })
+            },
+            Some((Found::Mount(mount, _), params, strip)) => {
+                let offset = conn.req.path_offset();
+                let params_len = conn.req.params().len();
+                conn.req.params_mut().extend(params);
+                conn.req.set_path_offset(offset + strip);
+                let res = match &mount.target {
+                    MountTarget::Handler(handler) => Ok(call_handler::<_, _, _, K>(handler, conn).await),
+                    MountTarget::Router(router) => router(conn).await,
+                };
+                match res {
+                    Ok(conn) => Ok(conn),
+                    Err(mut conn) => {
+                        // マウント先で見つからなければ元に戻してこちらの fallback へ
+                        conn.req.set_path_offset(offset);
+                        conn.req.params_mut().truncate(params_len);
+                        self.not_found(conn).await
+                    },
+                }
+            },
+            None => self.not_found(conn).await,
+        }
+    }
+
+    #[inline]
+    async fn not_found(
+        &self,
+        conn: Connection<C, R, W>,
+    ) -> Result<Connection<C, R, W, ResponseReadyToSend>, Connection<C, R, W>> {
+        match &self.inner.fallback {
+            Some(fallback) => Ok(call_handler::<_, _, _, K>(fallback, conn).await),
+            None => Err(conn),
+        }
+    }
+}
+
+impl<C, R, W, K> Router<C, R, W, ResponseReadyToSend> for PathRouter<C, R, W, K>
+where
+    C: Clone,
+    R: AsyncRead + Unpin + 'static,
+    W: AsyncWrite + Unpin + 'static,
+    K: FutureKind,
+{
+    #[inline(always)]
+    async fn router(&self, conn: Connection<C, R, W>) -> Connection<C, R, W, ResponseReadyToSend> {
+        self.dispatch(conn).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::io::Cursor;
+
+    use super::*;
+    use crate::http::{HttpRequest, HttpResponse};
+
+    type Conn = Connection<(), Cursor<Vec<u8>>, Vec<u8>>;
+    type Done = Connection<(), Cursor<Vec<u8>>, Vec<u8>, ResponseReadyToSend>;
+    type Builder = PathRouterBuilder<(), Cursor<Vec<u8>>, Vec<u8>>;
+
+    /// リクエストを1つ流して、レスポンスを組み立て済みの Connection を返す
+    fn send(router: &PathRouter<(), Cursor<Vec<u8>>, Vec<u8>>, method: &str, path: &str) -> Done {
+        let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        let req = async {
+            HttpRequest::new(Cursor::new(raw.into_bytes()))
+                .parse_request_line()
+                .await?
+                .parse_request()
+                .await
+        }
+        .now_or_never()
+        .unwrap()
+        .ok()
+        .unwrap();
+        let conn = Connection::new((), req, HttpResponse::new(Vec::new()));
+        router.dispatch(conn).now_or_never().unwrap()
+    }
+
+    /// 組み立てたヘッダの値は先頭の空白を含むので落とす
+    fn allow(conn: &Done) -> Option<&str> {
+        conn.res.header_get("Allow").map(str::trim_start)
+    }
+
+    fn body(conn: &Done) -> &str {
+        std::str::from_utf8(conn.res.body().unwrap_or_default()).unwrap()
+    }
+
+    /// ハンドラ名とキャプチャしたパラメータを返すハンドラ
+    fn reply(name: &'static str) -> impl Fn(Conn) -> BoxFuture<'static, Done> + Send + Sync + 'static {
+        move |conn| {
+            Box::pin(async move {
+                let params: Vec<String> = conn
+                    .req
+                    .params()
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                let body = format!("{} {} {}", name, params.join(","), conn.req.path());
+                conn.text_body(body.trim_end())
+            })
+        }
+    }
+
+    #[test]
+    fn captures_params_and_wildcards() {
+        let router = Builder::new()
+            .get("/users/:id", reply("user"))
+            .get("/users/:id/posts/:post", reply("post"))
+            .get("/files/*rest", reply("files"))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            body(&send(&router, "GET", "/users/42")),
+            "user id=42 /users/42"
+        );
+        assert_eq!(
+            body(&send(&router, "GET", "/users/a%20b/posts/7")),
+            "post id=a b,post=7 /users/a%20b/posts/7"
+        );
+        assert_eq!(
+            body(&send(&router, "GET", "/files/a/b/c.txt")),
+            "files rest=a/b/c.txt /files/a/b/c.txt"
+        );
+        assert_eq!(body(&send(&router, "GET", "/files")), "files rest= /files");
+        // 空のセグメントは :param にマッチしない
+        assert_eq!(
+            send(&router, "GET", "/users/").res.status_code(),
+            HttpStatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn static_beats_param_beats_wildcard() {
+        let router = Builder::new()
+            .get("/a/me", reply("static"))
+            .get("/a/:id", reply("param"))
+            .get("/a/*rest", reply("wildcard"))
+            .build()
+            .unwrap();
+
+        assert!(body(&send(&router, "GET", "/a/me")).starts_with("static"));
+        assert!(body(&send(&router, "GET", "/a/you")).starts_with("param"));
+        assert!(body(&send(&router, "GET", "/a/you/too")).starts_with("wildcard"));
+    }
+
+    #[test]
+    fn falls_back_to_param_when_static_lacks_the_method() {
+        let router = Builder::new()
+            .get("/users/me", reply("me"))
+            .post("/users/:id", reply("update"))
+            .build()
+            .unwrap();
+
+        assert!(body(&send(&router, "GET", "/users/me")).starts_with("me"));
+        assert_eq!(
+            body(&send(&router, "POST", "/users/me")),
+            "update id=me /users/me"
+        );
+
+        // どちらも受け付けなければ、静的なルートの Allow で 405
+        let conn = send(&router, "DELETE", "/users/me");
+        assert_eq!(conn.res.status_code(), HttpStatusCode::MethodNotAllowed);
+        assert_eq!(allow(&conn), Some("GET, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn method_not_allowed_lists_allow() {
+        let router = Builder::new()
+            .get("/items", reply("list"))
+            .post("/items", reply("create"))
+            .build()
+            .unwrap();
+
+        let conn = send(&router, "PUT", "/items");
+        assert_eq!(conn.res.status_code(), HttpStatusCode::MethodNotAllowed);
+        assert_eq!(allow(&conn), Some("GET, POST, HEAD, OPTIONS"));
+
+        let conn = send(&router, "OPTIONS", "/items");
+        assert_eq!(conn.res.status_code(), HttpStatusCode::OK);
+        assert_eq!(allow(&conn), Some("GET, POST, HEAD, OPTIONS"));
+
+        assert_eq!(
+            send(&router, "GET", "/nothing").res.status_code(),
+            HttpStatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn options_asterisk_lists_every_method() {
+        let api = Builder::new()
+            .delete("/:id", reply("delete"))
+            .build()
+            .unwrap();
+        let router = Builder::new()
+            .get("/", reply("index"))
+            .post("/items", reply("create"))
+            .nest("/api", api)
+            .build()
+            .unwrap();
+
+        let conn = send(&router, "OPTIONS", "*");
+        assert_eq!(conn.res.status_code(), HttpStatusCode::OK);
+        assert_eq!(allow(&conn), Some("GET, POST, DELETE, HEAD, OPTIONS"));
+    }
+
+    #[test]
+    fn head_is_served_by_get() {
+        let router = Builder::new()
+            .get("/page", reply("get"))
+            .get("/own", reply("get"))
+            .head("/own", reply("head"))
+            .build()
+            .unwrap();
+
+        let conn = send(&router, "HEAD", "/page");
+        assert_eq!(conn.res.status_code(), HttpStatusCode::OK);
+        assert!(body(&conn).starts_with("get"));
+        assert!(body(&send(&router, "HEAD", "/own")).starts_with("head"));
+    }
+
+    #[test]
+    fn nest_and_mount_strip_the_prefix() {
+        let api = Builder::new()
+            .get("/", reply("api-index"))
+            .get("/users/:id", reply("api-user"))
+            .build()
+            .unwrap();
+        let router = Builder::new()
+            .nest("/api/:version", api)
+            .mount("/static", |conn: Conn| async move {
+                let body = format!("{} {}", conn.req.mount_prefix(), conn.req.path());
+                conn.text_body(body)
+            })
+            .get("/api/:version/users/me", reply("me"))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            body(&send(&router, "GET", "/api/v1/users/7")),
+            "api-user version=v1,id=7 /users/7"
+        );
+        assert_eq!(
+            body(&send(&router, "GET", "/api/v1")),
+            "api-index version=v1 /"
+        );
+        assert_eq!(
+            body(&send(&router, "GET", "/api/v1/users/me")),
+            "me version=v1 /api/v1/users/me"
+        );
+        assert_eq!(
+            body(&send(&router, "GET", "/static/css/site.css?v=1")),
+            "/static /css/site.css"
+        );
+        assert_eq!(body(&send(&router, "GET", "/static")), "/static /");
+        // マウント先で見つからなければ 404 (プレフィックスは元に戻る)
+        assert_eq!(
+            send(&router, "GET", "/api/v1/nothing").res.status_code(),
+            HttpStatusCode::NotFound
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_routes() {
+        let err = Builder::new()
+            .get("/users/:id", reply("a"))
+            .get("/users/:name", reply("b"))
+            .build()
+            .err();
+        assert!(matches!(err, Some(RouteError::Conflict { .. })));
+
+        let err = Builder::new()
+            .get("/files/*rest/more", reply("a"))
+            .build()
+            .err();
+        assert!(matches!(err, Some(RouteError::InvalidPattern { .. })));
+    }
+}
@@ -1,23 +1,56 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{marker::PhantomData, net::SocketAddr, pin::pin, sync::Arc, time::Duration};
 
 use compio::net::{OwnedReadHalf, OwnedWriteHalf, TcpListener, TcpStream};
 use compio_io::compat::AsyncStream;
+use futures_util::future::{Either, LocalBoxFuture, pending, select};
 
 use crate::{
     connection::{Connection, ResponseReadyToSend},
-    router::{DEFAULT_KEEP_ALIVE_TIMEOUT, DefaultContext, KurosabiRouter, Router},
+    http::{HttpLimits, HttpStatusCode, ProxyProtocol, TrustedProxies},
+    router::{
+        After, Before, BeforeResult, Catchers, ConnectionStateFactory, DEFAULT_BODY_READ_TIMEOUT,
+        DEFAULT_KEEP_ALIVE_TIMEOUT, DEFAULT_WRITE_TIMEOUT, DefaultContext, KurosabiRouter, LocalFuture,
+        MiddlewareLayer, MiddlewareStack, PathRouter, Router, chain_connection_state,
+    },
+    server::{DEFAULT_SHUTDOWN_TIMEOUT, accept_proxy_header, accepted_info, listener_info, shutdown::ShutdownHandle},
 };
 
+type Reader = AsyncStream<OwnedReadHalf<TcpStream>>;
+type Writer = AsyncStream<OwnedWriteHalf<TcpStream>>;
+
+pub type ConnReq<C = DefaultContext> = Connection<C, Reader, Writer>;
+pub type ConnRes<C = DefaultContext> = Connection<C, Reader, Writer, ResponseReadyToSend>;
+/// compio 用の `PathRouter`
+/// compio のソケットは `Send` でないので、ハンドラの Future に `Send` を求めません
+pub type CompioPathRouter<C = DefaultContext> = PathRouter<C, Reader, Writer, LocalFuture>;
+
 pub struct KurosabiCompioServerBuilder<C: Clone = DefaultContext> {
     context: C,
     bind: String,
     port: u16,
     keep_alive_timeout: Duration,
     http_header_read_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
+    limits: HttpLimits,
+    handler_timeout: Option<Duration>,
+    handler_timeout_status: HttpStatusCode,
+    middlewares: MiddlewareStack<DynMiddleware<C>>,
+    connection_state: Option<ConnectionStateFactory>,
+    catchers: Catchers,
+    listener_name: Option<Arc<str>>,
+    trusted_proxies: TrustedProxies,
+    proxy_protocol: ProxyProtocol,
+    shutdown_timeout: Duration,
 }
 
 pub struct KurosabiCompioServer<C: Clone + Sync + Send, H> {
     router: KurosabiRouter<MyRouter<C, H>, C>,
+    listener_name: Option<Arc<str>>,
+    proxy_protocol: ProxyProtocol,
+    proxy_header_timeout: Duration,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
     bind: String,
     port: u16,
 }
@@ -64,27 +97,116 @@ where
     }
 }
 
+impl<C: Clone + 'static> Handler<C> for PathRouter<C, Reader, Writer, LocalFuture> {
+    type Fut = LocalBoxFuture<
+        'static,
+        Connection<
+            C,
+            AsyncStream<OwnedReadHalf<TcpStream>>,
+            AsyncStream<OwnedWriteHalf<TcpStream>>,
+            ResponseReadyToSend,
+        >,
+    >;
+
+    #[inline(always)]
+    fn call(
+        &self,
+        conn: Connection<C, AsyncStream<OwnedReadHalf<TcpStream>>, AsyncStream<OwnedWriteHalf<TcpStream>>>,
+    ) -> Self::Fut {
+        let router = self.clone();
+        Box::pin(async move { router.dispatch(conn).await })
+    }
+}
+
+/// ハンドラの前後に処理を挟むミドルウェア
+///
+/// `before` で `Err` を返すとハンドラを呼ばずにそのレスポンスを返します
+/// `after` はハンドラが返したレスポンスを書き換えられます
+pub trait Middleware<C: 'static>: Send + Sync + 'static {
+    fn before(&self, conn: ConnReq<C>) -> impl Future<Output = BeforeResult<C, Reader, Writer>> {
+        async move { Ok(conn) }
+    }
+
+    fn after(&self, conn: ConnRes<C>) -> impl Future<Output = ConnRes<C>> {
+        async move { conn }
+    }
+}
+
+impl<C, F, Fut> Middleware<C> for Before<F>
+where
+    C: 'static,
+    F: Fn(ConnReq<C>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = BeforeResult<C, Reader, Writer>>,
+{
+    #[inline(always)]
+    fn before(&self, conn: ConnReq<C>) -> impl Future<Output = BeforeResult<C, Reader, Writer>> {
+        (self.0)(conn)
+    }
+}
+
+impl<C, F, Fut> Middleware<C> for After<F>
+where
+    C: 'static,
+    F: Fn(ConnRes<C>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ConnRes<C>>,
+{
+    #[inline(always)]
+    fn after(&self, conn: ConnRes<C>) -> impl Future<Output = ConnRes<C>> {
+        (self.0)(conn)
+    }
+}
+
+/// 型消去した `Middleware`
+pub trait ErasedMiddleware<C>: Send + Sync {
+    fn before_boxed(self: Arc<Self>, conn: ConnReq<C>) -> LocalBoxFuture<'static, BeforeResult<C, Reader, Writer>>;
+    fn after_boxed(self: Arc<Self>, conn: ConnRes<C>) -> LocalBoxFuture<'static, ConnRes<C>>;
+}
+
+impl<C: 'static, M: Middleware<C>> ErasedMiddleware<C> for M {
+    #[inline(always)]
+    fn before_boxed(self: Arc<Self>, conn: ConnReq<C>) -> LocalBoxFuture<'static, BeforeResult<C, Reader, Writer>> {
+        Box::pin(async move { self.before(conn).await })
+    }
+
+    #[inline(always)]
+    fn after_boxed(self: Arc<Self>, conn: ConnRes<C>) -> LocalBoxFuture<'static, ConnRes<C>> {
+        Box::pin(async move { self.after(conn).await })
+    }
+}
+
+/// 型消去した `Middleware` への参照
+pub struct DynMiddleware<C>(Arc<dyn ErasedMiddleware<C>>);
+
+impl<C> Clone for DynMiddleware<C> {
+    fn clone(&self) -> Self {
+        DynMiddleware(self.0.clone())
+    }
+}
+
+impl<C> MiddlewareLayer<C, Reader, Writer> for DynMiddleware<C> {
+    type BeforeFut = LocalBoxFuture<'static, BeforeResult<C, Reader, Writer>>;
+    type AfterFut = LocalBoxFuture<'static, ConnRes<C>>;
+
+    #[inline(always)]
+    fn before(&self, conn: ConnReq<C>) -> Self::BeforeFut {
+        self.0.clone().before_boxed(conn)
+    }
+
+    #[inline(always)]
+    fn after(&self, conn: ConnRes<C>) -> Self::AfterFut {
+        self.0.clone().after_boxed(conn)
+    }
+}
+
 impl<C: Clone + Sync + Send + Default> KurosabiCompioServerBuilder<C> {
     pub fn new() -> Self {
-        KurosabiCompioServerBuilder {
-            context: C::default(),
-            bind: "0.0.0.0".to_string(),
-            port: 8080,
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-        }
+        Self::base(C::default())
     }
 }
 
 impl KurosabiCompioServerBuilder<DefaultContext> {
     pub fn default() -> Self {
-        KurosabiCompioServerBuilder {
-            context: DefaultContext::default(),
-            bind: "0.0.0.0".to_string(),
-            port: 8080,
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-        }
+        Self::base(DefaultContext::default())
     }
 }
 
@@ -93,12 +215,29 @@ impl<C: Clone + Sync + Send> KurosabiCompioServerBuilder<C> {
     where
         C: Default,
     {
+        Self::base(context)
+    }
+
+    /// 既定の設定で作る (`new` / `default` / `with_context` の中身)
+    fn base(context: C) -> Self {
         KurosabiCompioServerBuilder {
             context,
             bind: "0.0.0.0".to_string(),
             port: 8080,
             keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
             http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            limits: HttpLimits::new(),
+            handler_timeout: None,
+            handler_timeout_status: HttpStatusCode::ServiceUnavailable,
+            middlewares: MiddlewareStack::new(),
+            connection_state: None,
+            catchers: Catchers::new(),
+            listener_name: None,
+            trusted_proxies: TrustedProxies::new(),
+            proxy_protocol: ProxyProtocol::Disabled,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
         }
     }
 
@@ -122,13 +261,193 @@ impl<C: Clone + Sync + Send> KurosabiCompioServerBuilder<C> {
         self
     }
 
+    /// ボディの読み込みが進まないまま待つ上限 (既定は30秒)
+    pub fn body_read_timeout(mut self, duration: Duration) -> Self {
+        self.body_read_timeout = duration;
+        self
+    }
+
+    /// レスポンスの書き込みが進まないまま待つ上限 (既定は30秒)
+    pub fn write_timeout(mut self, duration: Duration) -> Self {
+        self.write_timeout = duration;
+        self
+    }
+
+    /// リクエストの大きさの上限をまとめて設定する
+    pub fn limits(mut self, limits: HttpLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// リクエストラインの上限 (既定は 8KiB、超えたら 414)
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.limits.max_request_line = bytes;
+        self
+    }
+
+    /// ヘッダ全体のバイト数の上限 (既定は 32KiB、超えたら 431)
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.limits.max_header_bytes = bytes;
+        self
+    }
+
+    /// ヘッダの数の上限 (既定は 128、超えたら 431)
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.limits.max_headers = count;
+        self
+    }
+
+    /// メモリに読み込むボディの上限 (既定は 2MiB、超えたら 413)
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.limits.max_body = bytes;
+        self
+    }
+
+    /// パスのセグメント数の上限 (既定は 64、超えたら 414)
+    pub fn max_uri_segments(mut self, count: usize) -> Self {
+        self.limits.max_uri_segments = count;
+        self
+    }
+
+    /// ハンドラのタイムアウト
+    /// 過ぎたらハンドラを打ち切り、まだ何も送っていなければ 503 を返して接続を閉じます
+    /// ルートごとに変えるには `router::handler_timeout` で包みます
+    pub fn handler_timeout(mut self, duration: Duration) -> Self {
+        self.handler_timeout = Some(duration);
+        self
+    }
+
+    /// ハンドラがタイムアウトしたときのステータスコード (既定は 503)
+    pub fn handler_timeout_status(mut self, status: HttpStatusCode) -> Self {
+        self.handler_timeout_status = status;
+        self
+    }
+
+    /// ミドルウェアを積む
+    /// 積んだ順に before が呼ばれ、逆順に after が呼ばれます
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        C: 'static,
+        M: Middleware<C>,
+    {
+        self.middlewares = self.middlewares.push(DynMiddleware(Arc::new(middleware)));
+        self
+    }
+
+    /// ハンドラの前に呼ばれるクロージャを積む
+    /// `Err` を返すとそのレスポンスで打ち切ります
+    pub fn before<F, Fut>(self, f: F) -> Self
+    where
+        C: 'static,
+        F: Fn(ConnReq<C>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = BeforeResult<C, Reader, Writer>>,
+    {
+        self.middleware(Before(f))
+    }
+
+    /// ハンドラの後に呼ばれるクロージャを積む
+    pub fn after<F, Fut>(self, f: F) -> Self
+    where
+        C: 'static,
+        F: Fn(ConnRes<C>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ConnRes<C>>,
+    {
+        self.middleware(After(f))
+    }
+
+    /// 接続ごとの状態を作るファクトリを追加する
+    /// 接続を受け付けたときに相手のアドレスを受け取って一度だけ呼ばれ、
+    /// 作った値はその接続の全リクエストで `conn.connection_state::<T>()` から使えます
+    pub fn connection_state<T, F>(mut self, factory: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Option<SocketAddr>) -> T + Send + Sync + 'static,
+    {
+        self.connection_state = Some(chain_connection_state(
+            self.connection_state.take(),
+            factory,
+        ));
+        self
+    }
+
+    /// エラーページを設定する
+    /// ボディが空か text/plain のエラーレスポンスを `Accept` に合わせて差し替えます
+    pub fn catchers(mut self, catchers: Catchers) -> Self {
+        self.catchers = catchers;
+        self
+    }
+
+    /// 信頼するプロキシ (ロードバランサーなど) のアドレス範囲
+    /// 相手がこの中にあれば `Forwarded` / `X-Forwarded-*` から本当のクライアントを求め、
+    /// `conn.client_ip()` / `client_scheme()` / `client_host()` で見えるようにします
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// PROXY protocol (v1 / v2) のヘッダを HTTP の前に読む
+    /// 読んだクライアントのアドレスは `conn.peer_addr()` に、TLV は `conn.connection_info().proxy_header()` に入ります
+    /// ヘッダを待つ上限は `http_header_read_timeout` です
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> Self {
+        self.proxy_protocol = mode;
+        self
+    }
+
+    /// 止めるときに処理中のリクエストを待つ上限 (既定は30秒)
+    /// 過ぎたら残っている接続を打ち切って `run` から返ります
+    pub fn shutdown_timeout(mut self, duration: Duration) -> Self {
+        self.shutdown_timeout = duration;
+        self
+    }
+
+    /// リスナーの名前を付ける
+    /// ハンドラからは `conn.connection_info().listener_name()` で見えます
+    pub fn listener_name<S>(mut self, name: S) -> Self
+    where
+        S: Into<Arc<str>>,
+    {
+        self.listener_name = Some(name.into());
+        self
+    }
+
     pub(crate) fn router_and_build_inner<H>(self, handler: H) -> KurosabiCompioServer<C, H>
     where
         H: Handler<C>,
     {
-        let my_router = MyRouter { handler, _marker: PhantomData };
-        let router = KurosabiRouter::with_context_and_router(my_router, self.context);
-        KurosabiCompioServer { router, bind: self.bind, port: self.port }
+        let my_router = MyRouter {
+            handler,
+            middlewares: self.middlewares,
+            _marker: PhantomData,
+        };
+        let mut router = KurosabiRouter::with_context_and_router(my_router, self.context);
+        router.set_connection_state_factory(self.connection_state);
+        router.set_catchers(self.catchers);
+        router.set_trusted_proxies(self.trusted_proxies);
+        router.set_body_read_timeout(Some(self.body_read_timeout));
+        router.set_write_timeout(Some(self.write_timeout));
+        router.set_limits(self.limits);
+        router.set_handler_timeout(self.handler_timeout);
+        router.set_handler_timeout_status(self.handler_timeout_status);
+        let shutdown = ShutdownHandle::new();
+        router.set_shutdown(shutdown.clone());
+        KurosabiCompioServer {
+            router,
+            listener_name: self.listener_name,
+            proxy_protocol: self.proxy_protocol,
+            proxy_header_timeout: self.http_header_read_timeout,
+            shutdown,
+            shutdown_timeout: self.shutdown_timeout,
+            bind: self.bind,
+            port: self.port,
+        }
+    }
+
+    /// `PathRouter` など `Handler` を実装した型でサーバーを構築する
+    pub fn handler_and_build<H>(self, handler: H) -> KurosabiCompioServer<C, H>
+    where
+        H: Handler<C>,
+    {
+        self.router_and_build_inner(handler)
     }
 
     pub fn router_and_build<F, Fut>(self, handler: F) -> KurosabiCompioServer<C, F>
@@ -151,25 +470,92 @@ impl<C: Clone + Sync + Send> KurosabiCompioServerBuilder<C> {
 }
 
 impl<C: Clone + Sync + Send + 'static, H: Handler<C>> KurosabiCompioServer<C, H> {
+    /// サーバーを止めるハンドル
+    /// `run` の前に取り出しておき、別のタスクから `shutdown` を呼んでください
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// 止めるまで接続を受け付ける
+    /// `shutdown_handle` で止めると、処理中のリクエストを待ってから返ります
     pub async fn run(self) -> std::io::Result<()> {
+        self.run_until(pending()).await
+    }
+
+    /// `signal` が完了するか `shutdown_handle` で止めるまで接続を受け付ける
+    pub async fn run_until<F>(self, signal: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
         let listener = TcpListener::bind((self.bind.as_str(), self.port)).await?;
+        let listener_info = listener_info(listener.local_addr().ok(), self.listener_name);
+        let (proxy_protocol, proxy_header_timeout) = (self.proxy_protocol, self.proxy_header_timeout);
+        let shutdown = self.shutdown;
+        let mut stop = pin!(async {
+            select(pin!(signal), pin!(shutdown.requested())).await;
+        });
+        // 受け付けに失敗しても、処理中の接続を止めてから返す
+        let mut result = Ok(());
         loop {
-            let (stream, _addr) = listener.accept().await?;
+            let (stream, addr) = match select(pin!(listener.accept()), stop.as_mut()).await {
+                Either::Left((Ok(accepted), _)) => accepted,
+                Either::Left((Err(e), _)) => {
+                    #[cfg(feature = "logging")]
+                    log::warn!("Failed to accept a connection: {}", e);
+                    result = Err(e);
+                    break;
+                },
+                Either::Right(_) => break,
+            };
             let router_ref = self.router.clone();
-            compio::runtime::spawn(async move {
+            let info = accepted_info(&listener_info, addr, stream.local_addr().ok());
+            let guard = shutdown.track();
+            let forced = shutdown.clone();
+            let task = async move {
                 let (reader, writer) = stream.into_split();
-                let reader: AsyncStream<OwnedReadHalf<TcpStream>> = AsyncStream::new(reader);
+                let mut reader: AsyncStream<OwnedReadHalf<TcpStream>> = AsyncStream::new(reader);
                 let writer: AsyncStream<OwnedWriteHalf<TcpStream>> = AsyncStream::new(writer);
-                router_ref.new_connection_loop(reader, writer).await;
+                let Some((info, buffered)) =
+                    accept_proxy_header(&mut reader, proxy_protocol, proxy_header_timeout, info).await
+                else {
+                    return;
+                };
+                router_ref
+                    .new_connection_loop_with_buffered(reader, writer, info, buffered)
+                    .await;
+            };
+            compio::runtime::spawn(async move {
+                let _guard = guard;
+                // 止めるときに待ちきれなければ、接続ごと打ち切る
+                select(pin!(task), pin!(forced.forced())).await;
             })
             .detach();
         }
+
+        // 受け付けをやめて、処理中の接続が終わるのを待つ
+        shutdown.shutdown();
+        drop(listener);
+        #[cfg(feature = "logging")]
+        log::info!(
+            "Shutting down, waiting for {} connections",
+            shutdown.active_connections()
+        );
+        let _aborted = shutdown.drain(self.shutdown_timeout).await;
+        #[cfg(feature = "logging")]
+        if _aborted > 0 {
+            log::warn!(
+                "Aborted {} connections after the shutdown timeout",
+                _aborted
+            );
+        }
+        result
     }
 }
 
 #[derive(Clone)]
 struct MyRouter<C: Clone + Sync + Send, H> {
     handler: H,
+    middlewares: MiddlewareStack<DynMiddleware<C>>,
     _marker: PhantomData<fn() -> C>,
 }
 
@@ -185,6 +571,11 @@ where
         conn: Connection<C, AsyncStream<OwnedReadHalf<TcpStream>>, AsyncStream<OwnedWriteHalf<TcpStream>>>,
     ) -> Connection<C, AsyncStream<OwnedReadHalf<TcpStream>>, AsyncStream<OwnedWriteHalf<TcpStream>>, ResponseReadyToSend>
     {
-        self.handler.call(conn).await
+        if self.middlewares.is_empty() {
+            return self.handler.call(conn).await;
+        }
+        self.middlewares
+            .run(conn, |conn| self.handler.call(conn))
+            .await
     }
 }
@@ -1,7 +1,80 @@
 #[cfg(feature = "compio-server")]
 pub mod compio;
+pub mod shutdown;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(feature = "tokio-server")]
 pub mod tokio;
+#[cfg(feature = "tls")]
+mod x509;
 
 pub const DEFAULT_LIMIT_HANDLE_NUM: usize = 2048;
 pub const DEFAULT_TCP_BACKLOG: u32 = 4096;
+/// 止めるときに処理中のリクエストを待つ上限
+pub const DEFAULT_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// リスナーごとに共通の接続情報
+#[cfg(any(feature = "tokio-server", feature = "compio-server"))]
+pub(crate) fn listener_info(
+    listener_addr: Option<std::net::SocketAddr>,
+    name: Option<std::sync::Arc<str>>,
+) -> crate::http::ConnectionInfo {
+    let mut info = crate::http::ConnectionInfo::new();
+    if let Some(addr) = listener_addr {
+        info = info.with_listener_addr(addr);
+    }
+    if let Some(name) = name {
+        info = info.with_listener_name(name);
+    }
+    info
+}
+
+/// 受け付けた接続の情報
+#[cfg(any(feature = "tokio-server", feature = "compio-server"))]
+pub(crate) fn accepted_info(
+    listener: &crate::http::ConnectionInfo,
+    peer: std::net::SocketAddr,
+    local: Option<std::net::SocketAddr>,
+) -> crate::http::ConnectionInfo {
+    let info = listener.clone().with_peer_addr(peer);
+    match local {
+        Some(local) => info.with_local_addr(local),
+        None => info,
+    }
+}
+
+/// PROXY ヘッダを読んで接続情報に入れる
+/// ヘッダが壊れているか、必須なのになければ None (接続を閉じる)
+#[cfg(any(feature = "tokio-server", feature = "compio-server"))]
+pub(crate) async fn accept_proxy_header<R>(
+    reader: &mut R,
+    mode: crate::http::ProxyProtocol,
+    timeout: std::time::Duration,
+    info: crate::http::ConnectionInfo,
+) -> Option<(crate::http::ConnectionInfo, Vec<u8>)>
+where
+    R: futures_io::AsyncRead + Unpin,
+{
+    if mode == crate::http::ProxyProtocol::Disabled {
+        return Some((info, Vec::new()));
+    }
+    let read = crate::http::ProxyHeader::read(reader, mode);
+    futures_util::pin_mut!(read);
+    match crate::utils::with_timeout(read, timeout).await {
+        Ok(Ok((Some(header), buffered))) => Some((info.with_proxy_header(header), buffered)),
+        Ok(Ok((None, buffered))) => Some((info, buffered)),
+        Ok(Err(_e)) => {
+            #[cfg(feature = "logging")]
+            log::debug!("Rejected connection from {:?}: {}", info.peer_addr(), _e);
+            None
+        },
+        Err(()) => {
+            #[cfg(feature = "logging")]
+            log::debug!(
+                "Timed out waiting for PROXY header from {:?}",
+                info.peer_addr()
+            );
+            None
+        },
+    }
+}
@@ -0,0 +1,213 @@
+// 接続を数えたり待ったりするのはサーバーだけなので、サーバーがなければ使われない
+#![cfg_attr(not(any(feature = "tokio-server", feature = "compio-server")), allow(dead_code))]
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use futures_util::pin_mut;
+
+use crate::utils::with_timeout;
+
+/// サーバーを止めるハンドル
+///
+/// `shutdown` を呼ぶと新しい接続を受け付けなくなり、keep-alive で次のリクエストを待っている接続を閉じます
+/// 処理中のリクエストには `Connection: close` を付けて最後まで返し、すべて終わるか
+/// `shutdown_timeout` が過ぎたら (残りは打ち切って) `run` が返ります
+/// クローンしたものはすべて同じサーバーを指します
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: Signal,
+    forced: Signal,
+    drained: Signal,
+    active: AtomicUsize,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 止め始める (何度呼んでもかまいません)
+    pub fn shutdown(&self) {
+        self.inner.requested.fire();
+    }
+
+    /// 止め始めているか
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.is_fired()
+    }
+
+    /// 止め始めたら完了する
+    pub fn requested(&self) -> impl Future<Output = ()> + Send + '_ {
+        let requested = &self.inner.requested;
+        requested.wait(|| requested.is_fired())
+    }
+
+    /// いま処理している接続の数
+    pub fn active_connections(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// 接続を数え始める (返した値を drop すると数え終わります)
+    pub(crate) fn track(&self) -> ConnectionGuard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { inner: self.inner.clone() }
+    }
+
+    /// 待ちきれずに打ち切るときに完了する
+    pub(crate) fn forced(&self) -> impl Future<Output = ()> + Send + '_ {
+        let forced = &self.inner.forced;
+        forced.wait(|| forced.is_fired())
+    }
+
+    /// 接続がなくなるのを `timeout` まで待ち、過ぎたら残りを打ち切る
+    /// 打ち切った接続の数を返します
+    pub(crate) async fn drain(&self, timeout: Duration) -> usize {
+        let drained = self.inner.drained.wait(|| self.active_connections() == 0);
+        pin_mut!(drained);
+        if with_timeout(drained.as_mut(), timeout).await.is_ok() {
+            return 0;
+        }
+        let remaining = self.active_connections();
+        self.inner.forced.fire();
+        drained.await;
+        remaining
+    }
+}
+
+impl std::fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("is_shutdown", &self.is_shutdown())
+            .field("active_connections", &self.active_connections())
+            .finish()
+    }
+}
+
+/// 処理中の接続1つ分
+pub(crate) struct ConnectionGuard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.inner.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.drained.notify();
+        }
+    }
+}
+
+/// 待っているものをまとめて起こす
+/// ランタイムに依らないように自前で持っています
+#[derive(Default)]
+struct Signal {
+    fired: AtomicBool,
+    waiters: Mutex<Waiters>,
+}
+
+#[derive(Default)]
+struct Waiters {
+    next_key: u64,
+    wakers: HashMap<u64, Waker>,
+}
+
+impl Signal {
+    /// 一度きりの合図を送る
+    fn fire(&self) {
+        if !self.fired.swap(true, Ordering::SeqCst) {
+            self.notify();
+        }
+    }
+
+    /// 待っているものを起こす (起きたほうで条件を見直します)
+    fn notify(&self) {
+        let wakers = {
+            let mut waiters = self.waiters.lock().unwrap_or_else(PoisonError::into_inner);
+            std::mem::take(&mut waiters.wakers)
+        };
+        for waker in wakers.into_values() {
+            waker.wake();
+        }
+    }
+
+    fn is_fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+
+    /// `ready` が true になるまで待つ
+    fn wait<F>(&self, ready: F) -> Wait<'_, F>
+    where
+        F: Fn() -> bool + Unpin,
+    {
+        Wait { signal: self, key: None, ready }
+    }
+}
+
+struct Wait<'a, F> {
+    signal: &'a Signal,
+    key: Option<u64>,
+    ready: F,
+}
+
+impl<F> Future for Wait<'_, F>
+where
+    F: Fn() -> bool + Unpin,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if (this.ready)() {
+            return Poll::Ready(());
+        }
+        {
+            let mut waiters = this
+                .signal
+                .waiters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let key = *this.key.get_or_insert_with(|| {
+                waiters.next_key += 1;
+                waiters.next_key
+            });
+            match waiters.wakers.get_mut(&key) {
+                Some(waker) if waker.will_wake(cx.waker()) => {},
+                Some(waker) => waker.clone_from(cx.waker()),
+                None => {
+                    waiters.wakers.insert(key, cx.waker().clone());
+                },
+            }
+        }
+        // 登録する前に条件がそろっていたら、もう誰も起こしてくれない
+        if (this.ready)() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for Wait<'_, F> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            let mut waiters = self
+                .signal
+                .waiters
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            waiters.wakers.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    task::JoinHandle,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        crypto::{CryptoProvider, ring},
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier, danger::ClientCertVerifier},
+        sign::CertifiedKey,
+    },
+};
+
+use crate::{http::TlsInfo, server::x509};
+
+/// rustls のバージョンをそろえて `ServerConfig` を組むために使えます
+pub use tokio_rustls::rustls;
+
+/// TLS の設定
+///
+/// 証明書と鍵は PEM ファイル、PEM のバイト列、DER から読めます
+/// rustls の `ServerConfig` を自分で組んだなら `from_server_config` でそのまま使えます
+#[derive(Clone)]
+pub struct TlsConfig {
+    source: TlsSource,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_auth: Option<ClientAuth>,
+}
+
+#[derive(Clone)]
+enum TlsSource {
+    Single(Arc<CertifiedKey>),
+    Sni(SniResolver),
+    ServerConfig(Arc<ServerConfig>),
+}
+
+impl TlsConfig {
+    /// 証明書チェーンと秘密鍵の PEM ファイルから読む
+    pub fn from_pem_files<P, Q>(cert_path: P, key_path: Q) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let cert = TlsCertificate::from_pem_files(cert_path, key_path)?;
+        Ok(Self::from_certificate(cert))
+    }
+
+    /// 証明書チェーンと秘密鍵の PEM のバイト列から読む
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<Self> {
+        let cert = TlsCertificate::from_pem(cert_pem, key_pem)?;
+        Ok(Self::from_certificate(cert))
+    }
+
+    /// DER の証明書チェーンと秘密鍵から作る
+    pub fn from_der(chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Self> {
+        let cert = TlsCertificate::from_der(chain, key)?;
+        Ok(Self::from_certificate(cert))
+    }
+
+    /// 読み込み済みの証明書を使う
+    pub fn from_certificate(cert: TlsCertificate) -> Self {
+        Self::with_source(TlsSource::Single(cert.0))
+    }
+
+    /// SNI のホスト名で証明書を選ぶ
+    /// `SniResolver` の中身はサーバーを止めずに差し替えられます
+    pub fn from_resolver(resolver: SniResolver) -> Self {
+        Self::with_source(TlsSource::Sni(resolver))
+    }
+
+    /// 組み立て済みの rustls の設定をそのまま使う
+    /// ALPN などもこの設定のものが使われます
+    pub fn from_server_config(config: Arc<ServerConfig>) -> Self {
+        Self::with_source(TlsSource::ServerConfig(config))
+    }
+
+    /// ALPN で広告するプロトコル (既定は `http/1.1` だけ)
+    /// kurosabi は HTTP/1.1 しか話さないので、`h2` は入れないでください
+    pub fn alpn_protocols<I, P>(mut self, protocols: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Vec<u8>>,
+    {
+        self.alpn_protocols = protocols.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// クライアント証明書を求める (mTLS)
+    /// `from_server_config` で作った設定では使われません
+    pub fn client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    fn with_source(source: TlsSource) -> Self {
+        TlsConfig {
+            source,
+            alpn_protocols: vec![b"http/1.1".to_vec()],
+            client_auth: None,
+        }
+    }
+
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let config = match &self.source {
+            TlsSource::ServerConfig(config) => config.clone(),
+            TlsSource::Single(key) => self.server_config(Arc::new(SingleCert(key.clone())))?,
+            TlsSource::Sni(resolver) => self.server_config(Arc::new(resolver.clone()))?,
+        };
+        Ok(TlsAcceptor::from(config))
+    }
+}
+
+impl TlsConfig {
+    fn server_config(&self, resolver: Arc<dyn ResolvesServerCert>) -> io::Result<Arc<ServerConfig>> {
+        let verifier = match &self.client_auth {
+            Some(client_auth) => client_auth.verifier()?,
+            None => WebPkiClientVerifier::no_client_auth(),
+        };
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(Arc::new(config))
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match &self.source {
+            TlsSource::Single(_) => "Single",
+            TlsSource::Sni(_) => "Sni",
+            TlsSource::ServerConfig(_) => "ServerConfig",
+        };
+        f.debug_struct("TlsConfig")
+            .field("source", &source)
+            .field("alpn_protocols", &self.alpn_protocols)
+            .field("client_auth", &self.client_auth)
+            .finish()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+fn parse_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if chain.is_empty() {
+        return Err(invalid("no certificate found in PEM"));
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(invalid)?;
+    Ok((chain, key))
+}
+
+/// クライアント証明書がなかったときにどうするか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuthMode {
+    /// なければハンドシェイクに失敗する (既定)
+    #[default]
+    Required,
+    /// なくても受け付ける。送られてきたものは検証します
+    Optional,
+}
+
+/// クライアント証明書を検証する CA の組と、求め方
+///
+/// 検証できた証明書のチェーンは `conn.peer_certificate()` や `TlsInfo::peer_certificates` で見えます
+#[derive(Clone)]
+pub struct ClientAuth {
+    roots: Arc<RootCertStore>,
+    mode: ClientAuthMode,
+}
+
+impl ClientAuth {
+    /// CA 証明書を並べた PEM ファイルから読む
+    pub fn from_pem_file<P>(path: P, mode: ClientAuthMode) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let pem = std::fs::read(path)?;
+        Self::from_pem(&pem, mode)
+    }
+
+    /// CA 証明書を並べた PEM のバイト列から読む
+    pub fn from_pem(pem: &[u8], mode: ClientAuthMode) -> io::Result<Self> {
+        let certs = CertificateDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        Self::from_der(certs, mode)
+    }
+
+    /// DER の CA 証明書から作る
+    pub fn from_der(certs: Vec<CertificateDer<'static>>, mode: ClientAuthMode) -> io::Result<Self> {
+        if certs.is_empty() {
+            return Err(invalid("no CA certificate found"));
+        }
+        let mut roots = RootCertStore::empty();
+        for cert in certs {
+            roots.add(cert).map_err(invalid)?;
+        }
+        Ok(ClientAuth { roots: Arc::new(roots), mode })
+    }
+
+    pub fn mode(&self) -> ClientAuthMode {
+        self.mode
+    }
+
+    fn verifier(&self) -> io::Result<Arc<dyn ClientCertVerifier>> {
+        let builder = WebPkiClientVerifier::builder_with_provider(self.roots.clone(), provider());
+        let builder = match self.mode {
+            ClientAuthMode::Required => builder,
+            ClientAuthMode::Optional => builder.allow_unauthenticated(),
+        };
+        builder.build().map_err(invalid)
+    }
+}
+
+impl fmt::Debug for ClientAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientAuth")
+            .field("roots", &self.roots.len())
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+/// 証明書チェーンと秘密鍵の組
+#[derive(Clone)]
+pub struct TlsCertificate(Arc<CertifiedKey>);
+
+impl TlsCertificate {
+    /// 証明書チェーンと秘密鍵の PEM ファイルから読む
+    pub fn from_pem_files<P, Q>(cert_path: P, key_path: Q) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let cert = std::fs::read(cert_path)?;
+        let key = std::fs::read(key_path)?;
+        Self::from_pem(&cert, &key)
+    }
+
+    /// 証明書チェーンと秘密鍵の PEM のバイト列から読む
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<Self> {
+        let (chain, key) = parse_pem(cert_pem, key_pem)?;
+        Self::from_der(chain, key)
+    }
+
+    /// DER の証明書チェーンと秘密鍵から作る
+    /// 鍵が証明書と合っているかも確かめます
+    pub fn from_der(chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Self> {
+        let key = CertifiedKey::from_der(chain, key, &provider()).map_err(invalid)?;
+        Ok(TlsCertificate(Arc::new(key)))
+    }
+}
+
+impl fmt::Debug for TlsCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsCertificate")
+            .field("chain_len", &self.0.cert.len())
+            .finish()
+    }
+}
+
+/// ホスト名ごとの証明書の組
+///
+/// `example.com` は完全一致、`*.example.com` は1段のサブドメイン (`a.example.com`) に一致します
+/// どれにも一致しないか、SNI がなければ既定の証明書を使います (なければハンドシェイクに失敗します)
+#[derive(Clone, Default)]
+pub struct SniCertificates {
+    exact: HashMap<Box<str>, Arc<CertifiedKey>>,
+    wildcard: HashMap<Box<str>, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniCertificates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ホスト名 (`*.` で始めればワイルドカード) に証明書を割り当てる
+    pub fn with(mut self, name: &str, cert: TlsCertificate) -> Self {
+        let name = normalize_name(name);
+        match name.strip_prefix("*.") {
+            Some(suffix) => self.wildcard.insert(suffix.into(), cert.0),
+            None => self.exact.insert(name.into(), cert.0),
+        };
+        self
+    }
+
+    /// どれにも一致しないときの証明書
+    pub fn with_default(mut self, cert: TlsCertificate) -> Self {
+        self.default = Some(cert.0);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty() && self.default.is_none()
+    }
+
+    fn resolve(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = server_name.map(normalize_name) {
+            if let Some(key) = self.exact.get(name.as_str()) {
+                return Some(key.clone());
+            }
+            if let Some((_, parent)) = name.split_once('.')
+                && let Some(key) = self.wildcard.get(parent)
+            {
+                return Some(key.clone());
+            }
+        }
+        self.default.clone()
+    }
+}
+
+impl fmt::Debug for SniCertificates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniCertificates")
+            .field("exact", &self.exact.keys().collect::<Vec<_>>())
+            .field("wildcard", &self.wildcard.keys().collect::<Vec<_>>())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// SNI で証明書を選ぶもの
+///
+/// クローンしても同じ組を指すので、手元に残したものから `replace` すれば
+/// 動いているサーバーの証明書をそのまま差し替えられます (次のハンドシェイクから効きます)
+#[derive(Clone, Default)]
+pub struct SniResolver {
+    certs: Arc<RwLock<Arc<SniCertificates>>>,
+}
+
+impl SniResolver {
+    pub fn new(certs: SniCertificates) -> Self {
+        SniResolver {
+            certs: Arc::new(RwLock::new(Arc::new(certs))),
+        }
+    }
+
+    /// 証明書の組をまるごと差し替える
+    pub fn replace(&self, certs: SniCertificates) {
+        let certs = Arc::new(certs);
+        match self.certs.write() {
+            Ok(mut current) => *current = certs,
+            Err(poisoned) => *poisoned.into_inner() = certs,
+        }
+    }
+
+    /// いまの証明書の組
+    pub fn current(&self) -> Arc<SniCertificates> {
+        match self.certs.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// ファイルの更新時刻を `interval` ごとに見て、変わっていたら `load` で読み直して差し替える
+    /// 読み直しに失敗したら、いまの組をそのまま使い続けます
+    /// 返したタスクを abort すると止まります
+    pub fn watch_files<I, P, F>(&self, paths: I, interval: Duration, load: F) -> JoinHandle<()>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+        F: Fn() -> io::Result<SniCertificates> + Send + 'static,
+    {
+        let paths: Vec<PathBuf> = paths.into_iter().map(Into::into).collect();
+        let resolver = self.clone();
+        tokio::spawn(async move {
+            let mut last = modified_times(&paths);
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = modified_times(&paths);
+                if now == last {
+                    continue;
+                }
+                match load() {
+                    Ok(certs) => {
+                        #[cfg(feature = "logging")]
+                        log::info!("Reloaded TLS certificates: {:?}", certs);
+                        resolver.replace(certs);
+                        last = now;
+                    },
+                    Err(_e) => {
+                        // 書き込みの途中かもしれないので、次の周期でもう一度試す
+                        #[cfg(feature = "logging")]
+                        log::warn!("Failed to reload TLS certificates: {}", _e);
+                    },
+                }
+            }
+        })
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SniResolver").field(&self.current()).finish()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current().resolve(client_hello.server_name())
+    }
+}
+
+/// いつも同じ証明書を返す
+#[derive(Debug)]
+struct SingleCert(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for SingleCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+pub(crate) type TlsStream = tokio_rustls::server::TlsStream<Rewind>;
+
+/// ハンドシェイクして、交渉の結果を返す
+pub(crate) async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    buffered: Vec<u8>,
+) -> io::Result<(TlsStream, TlsInfo)> {
+    let stream = acceptor
+        .accept(Rewind { buffered, pos: 0, inner: stream })
+        .await?;
+    let (_, conn) = stream.get_ref();
+    let mut info = TlsInfo::new();
+    if let Some(protocol) = conn.alpn_protocol() {
+        info = info.with_alpn_protocol(protocol);
+    }
+    if let Some(name) = conn.server_name() {
+        info = info.with_server_name(name);
+    }
+    if let Some(version) = conn.protocol_version().and_then(|v| v.as_str()) {
+        info = info.with_protocol_version(version);
+    }
+    if let Some(suite) = conn
+        .negotiated_cipher_suite()
+        .and_then(|s| s.suite().as_str())
+    {
+        info = info.with_cipher_suite(suite);
+    }
+    if let Some(chain) = conn.peer_certificates() {
+        let chain = chain
+            .iter()
+            .map(|cert| x509::peer_certificate(cert))
+            .collect();
+        info = info.with_peer_certificates(chain);
+    }
+    Ok((stream, info))
+}
+
+/// 先に読んでしまったバイト列 (PROXY ヘッダの後ろ) を頭に戻した TCP ストリーム
+pub(crate) struct Rewind {
+    buffered: Vec<u8>,
+    pos: usize,
+    inner: TcpStream,
+}
+
+impl AsyncRead for Rewind {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.buffered.len() {
+            let rest = &this.buffered[this.pos..];
+            let n = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..n]);
+            this.pos += n;
+            if this.pos == this.buffered.len() {
+                this.buffered = Vec::new();
+                this.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Rewind {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_rustls::rustls::{
+        SignatureAlgorithm, SignatureScheme,
+        sign::{Signer, SigningKey},
+    };
+
+    use super::*;
+
+    /// 署名しない鍵 (どの証明書が選ばれたかだけを見る)
+    #[derive(Debug)]
+    struct NoKey;
+
+    impl SigningKey for NoKey {
+        fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+            None
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            SignatureAlgorithm::ED25519
+        }
+    }
+
+    /// 中身の1バイトで見分けられる証明書
+    fn cert(tag: u8) -> TlsCertificate {
+        let chain = vec![CertificateDer::from(vec![tag])];
+        TlsCertificate(Arc::new(CertifiedKey::new(chain, Arc::new(NoKey))))
+    }
+
+    fn resolved(certs: &SniCertificates, name: Option<&str>) -> Option<u8> {
+        certs.resolve(name).map(|key| key.cert[0].as_ref()[0])
+    }
+
+    #[test]
+    fn normalizes_case_and_trailing_dot() {
+        assert_eq!(normalize_name("Example.COM"), "example.com");
+        assert_eq!(normalize_name("example.com."), "example.com");
+        assert_eq!(normalize_name("*.Example.com."), "*.example.com");
+        assert_eq!(normalize_name(""), "");
+    }
+
+    #[test]
+    fn exact_names_ignore_case() {
+        let certs = SniCertificates::new()
+            .with("Example.com", cert(1))
+            .with("api.example.com.", cert(2));
+        assert_eq!(resolved(&certs, Some("example.com")), Some(1));
+        assert_eq!(resolved(&certs, Some("EXAMPLE.COM.")), Some(1));
+        assert_eq!(resolved(&certs, Some("Api.Example.Com")), Some(2));
+        assert_eq!(resolved(&certs, Some("other.com")), None);
+        assert_eq!(resolved(&certs, None), None);
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        let certs = SniCertificates::new()
+            .with("*.Example.com", cert(1))
+            .with("www.example.com", cert(2));
+        assert_eq!(resolved(&certs, Some("a.example.com")), Some(1));
+        assert_eq!(resolved(&certs, Some("B.EXAMPLE.COM")), Some(1));
+        // 完全一致が優先
+        assert_eq!(resolved(&certs, Some("www.example.com")), Some(2));
+        // 2段のサブドメインや親ドメインそのものには一致しない
+        assert_eq!(resolved(&certs, Some("a.b.example.com")), None);
+        assert_eq!(resolved(&certs, Some("example.com")), None);
+    }
+
+    #[test]
+    fn falls_back_to_the_default() {
+        let certs = SniCertificates::new()
+            .with("example.com", cert(1))
+            .with("*.example.com", cert(2))
+            .with_default(cert(9));
+        assert_eq!(resolved(&certs, Some("example.com")), Some(1));
+        assert_eq!(resolved(&certs, Some("a.example.com")), Some(2));
+        assert_eq!(resolved(&certs, Some("a.b.example.com")), Some(9));
+        assert_eq!(resolved(&certs, Some("other.org")), Some(9));
+        // SNI を送らないクライアント (IP 直打ちなど)
+        assert_eq!(resolved(&certs, None), Some(9));
+    }
+
+    #[test]
+    fn later_registrations_replace_earlier_ones() {
+        let certs = SniCertificates::new()
+            .with("example.com", cert(1))
+            .with("EXAMPLE.com", cert(2))
+            .with_default(cert(3))
+            .with_default(cert(4));
+        assert_eq!(resolved(&certs, Some("example.com")), Some(2));
+        assert_eq!(resolved(&certs, None), Some(4));
+    }
+
+    #[test]
+    fn resolver_replace_is_shared_by_clones() {
+        assert!(SniCertificates::new().is_empty());
+        assert!(!SniCertificates::new().with_default(cert(1)).is_empty());
+
+        let resolver = SniResolver::new(SniCertificates::new().with("example.com", cert(1)));
+        let running = resolver.clone();
+        resolver.replace(SniCertificates::new().with("example.com", cert(2)));
+        assert_eq!(resolved(&running.current(), Some("example.com")), Some(2));
+    }
+}
@@ -1,11 +1,12 @@
 use std::{
     marker::PhantomData,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    pin::pin,
     sync::Arc,
     time::Duration,
 };
 
-use log::{debug, info};
+use futures_util::future::{BoxFuture, pending, select};
 use tokio::{
     net::{
         TcpSocket,
@@ -17,13 +18,49 @@ use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatE
 
 use crate::{
     connection::{Connection, NoneBody, ResponseReadyToSend},
-    router::{DEFAULT_KEEP_ALIVE_TIMEOUT, DefaultContext, KurosabiRouter, Router},
-    server::{DEFAULT_LIMIT_HANDLE_NUM, DEFAULT_TCP_BACKLOG},
+    http::{HttpLimits, HttpStatusCode, ProxyProtocol, TrustedProxies},
+    router::{
+        After, Before, BeforeResult, Catchers, ConnectionStateFactory, DEFAULT_BODY_READ_TIMEOUT,
+        DEFAULT_KEEP_ALIVE_TIMEOUT, DEFAULT_WRITE_TIMEOUT, DefaultContext, KurosabiRouter, MiddlewareLayer,
+        MiddlewareStack, PathRouter, Router, chain_connection_state,
+    },
+    server::{
+        DEFAULT_LIMIT_HANDLE_NUM, DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_TCP_BACKLOG, accept_proxy_header, accepted_info,
+        listener_info, shutdown::ShutdownHandle,
+    },
+};
+#[cfg(feature = "tls")]
+use crate::{
+    server::tls::{self, TlsConfig, TlsStream},
+    utils::with_timeout,
 };
+#[cfg(feature = "tls")]
+use futures_util::pin_mut;
+#[cfg(feature = "tls")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(feature = "tls")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// 受け付けた接続の読み込み側
+#[cfg(not(feature = "tls"))]
+pub type Reader = Compat<OwnedReadHalf>;
+/// 受け付けた接続の書き込み側
+#[cfg(not(feature = "tls"))]
+pub type Writer = Compat<OwnedWriteHalf>;
+/// 受け付けた接続の読み込み側 (平文の TCP か TLS)
+#[cfg(feature = "tls")]
+pub type Reader = Compat<TokioReadHalf>;
+/// 受け付けた接続の書き込み側 (平文の TCP か TLS)
+#[cfg(feature = "tls")]
+pub type Writer = Compat<TokioWriteHalf>;
 
-pub type Conn<C = DefaultContext, S = NoneBody> = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, S>;
-pub type ConnReq<C = DefaultContext> = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, NoneBody>;
-pub type ConnRes<C = DefaultContext> = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend>;
+pub type Conn<C = DefaultContext, S = NoneBody> = Connection<C, Reader, Writer, S>;
+pub type ConnReq<C = DefaultContext> = Connection<C, Reader, Writer, NoneBody>;
+pub type ConnRes<C = DefaultContext> = Connection<C, Reader, Writer, ResponseReadyToSend>;
+pub type TokioPathRouter<C = DefaultContext> = PathRouter<C, Reader, Writer>;
 
 pub struct KurosabiServerBuilder {}
 pub struct KurosabiTokioServerBuilder<C: Clone = DefaultContext> {
@@ -32,12 +69,33 @@ pub struct KurosabiTokioServerBuilder<C: Clone = DefaultContext> {
     port: u16,
     keep_alive_timeout: Duration,
     http_header_read_timeout: Duration,
+    body_read_timeout: Duration,
+    write_timeout: Duration,
+    limits: HttpLimits,
+    handler_timeout: Option<Duration>,
+    handler_timeout_status: HttpStatusCode,
+    middlewares: MiddlewareStack<DynMiddleware<C>>,
+    connection_state: Option<ConnectionStateFactory>,
+    catchers: Catchers,
+    listener_name: Option<Arc<str>>,
+    trusted_proxies: TrustedProxies,
+    proxy_protocol: ProxyProtocol,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    shutdown_timeout: Duration,
     limit_handle_num: usize,
     tcp_backlog: u32,
 }
 
 pub struct KurosabiTokioServer<C: Clone + Sync + Send, H> {
     router: KurosabiRouter<MyRouter<C, H>, C>,
+    listener_name: Option<Arc<str>>,
+    proxy_protocol: ProxyProtocol,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    handshake_timeout: Duration,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
     bind: [u8; 4],
     port: u16,
     limit_handle_num: usize,
@@ -45,53 +103,133 @@ pub struct KurosabiTokioServer<C: Clone + Sync + Send, H> {
 }
 
 pub trait Handler<C>: Clone + Send + Sync + 'static {
-    type Fut: Future<Output = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend>>
-        + Send
-        + 'static;
+    type Fut: Future<Output = Connection<C, Reader, Writer, ResponseReadyToSend>> + Send + 'static;
 
-    fn call(&self, conn: Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>) -> Self::Fut;
+    fn call(&self, conn: Connection<C, Reader, Writer>) -> Self::Fut;
 }
 
 impl<C, F, Fut> Handler<C> for F
 where
-    F: Fn(Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>) -> Fut + Clone + Send + Sync + 'static,
-    Fut: Future<Output = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend>>
-        + Send
-        + 'static,
+    F: Fn(Connection<C, Reader, Writer>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Connection<C, Reader, Writer, ResponseReadyToSend>> + Send + 'static,
 {
     type Fut = Fut;
 
     #[inline(always)]
-    fn call(&self, conn: Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>) -> Self::Fut {
+    fn call(&self, conn: Connection<C, Reader, Writer>) -> Self::Fut {
         (self)(conn)
     }
 }
 
+impl<C> Handler<C> for PathRouter<C, Reader, Writer>
+where
+    C: Clone + Send + 'static,
+{
+    type Fut = BoxFuture<'static, Connection<C, Reader, Writer, ResponseReadyToSend>>;
+
+    #[inline(always)]
+    fn call(&self, conn: Connection<C, Reader, Writer>) -> Self::Fut {
+        let router = self.clone();
+        Box::pin(async move { router.dispatch(conn).await })
+    }
+}
+
+/// ハンドラの前後に処理を挟むミドルウェア
+///
+/// `before` で `Err` を返すとハンドラを呼ばずにそのレスポンスを返します
+/// `after` はハンドラが返したレスポンスを書き換えられます
+pub trait Middleware<C: Send + 'static>: Send + Sync + 'static {
+    fn before(&self, conn: ConnReq<C>) -> impl Future<Output = BeforeResult<C, Reader, Writer>> + Send {
+        async move { Ok(conn) }
+    }
+
+    fn after(&self, conn: ConnRes<C>) -> impl Future<Output = ConnRes<C>> + Send {
+        async move { conn }
+    }
+}
+
+impl<C, F, Fut> Middleware<C> for Before<F>
+where
+    C: Send + 'static,
+    F: Fn(ConnReq<C>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = BeforeResult<C, Reader, Writer>> + Send,
+{
+    #[inline(always)]
+    fn before(&self, conn: ConnReq<C>) -> impl Future<Output = BeforeResult<C, Reader, Writer>> + Send {
+        (self.0)(conn)
+    }
+}
+
+impl<C, F, Fut> Middleware<C> for After<F>
+where
+    C: Send + 'static,
+    F: Fn(ConnRes<C>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ConnRes<C>> + Send,
+{
+    #[inline(always)]
+    fn after(&self, conn: ConnRes<C>) -> impl Future<Output = ConnRes<C>> + Send {
+        (self.0)(conn)
+    }
+}
+
+/// 型消去した `Middleware`
+pub trait ErasedMiddleware<C>: Send + Sync {
+    fn before_boxed(self: Arc<Self>, conn: ConnReq<C>) -> BoxFuture<'static, BeforeResult<C, Reader, Writer>>;
+    fn after_boxed(self: Arc<Self>, conn: ConnRes<C>) -> BoxFuture<'static, ConnRes<C>>;
+}
+
+impl<C: Send + 'static, M: Middleware<C>> ErasedMiddleware<C> for M {
+    #[inline(always)]
+    fn before_boxed(self: Arc<Self>, conn: ConnReq<C>) -> BoxFuture<'static, BeforeResult<C, Reader, Writer>> {
+        Box::pin(async move { self.before(conn).await })
+    }
+
+    #[inline(always)]
+    fn after_boxed(self: Arc<Self>, conn: ConnRes<C>) -> BoxFuture<'static, ConnRes<C>> {
+        Box::pin(async move { self.after(conn).await })
+    }
+}
+
+/// 型消去した `Middleware` への参照
+pub struct DynMiddleware<C>(Arc<dyn ErasedMiddleware<C>>);
+
+impl<C> Clone for DynMiddleware<C> {
+    fn clone(&self) -> Self {
+        DynMiddleware(self.0.clone())
+    }
+}
+
+impl<C> MiddlewareLayer<C, Reader, Writer> for DynMiddleware<C> {
+    type BeforeFut = BoxFuture<'static, BeforeResult<C, Reader, Writer>>;
+    type AfterFut = BoxFuture<'static, ConnRes<C>>;
+
+    #[inline(always)]
+    fn before(&self, conn: ConnReq<C>) -> Self::BeforeFut {
+        self.0.clone().before_boxed(conn)
+    }
+
+    #[inline(always)]
+    fn after(&self, conn: ConnRes<C>) -> Self::AfterFut {
+        self.0.clone().after_boxed(conn)
+    }
+}
+
+impl<C: Clone + Sync + Send + Default> Default for KurosabiTokioServerBuilder<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<C: Clone + Sync + Send + Default> KurosabiTokioServerBuilder<C> {
     pub fn new() -> Self {
-        KurosabiTokioServerBuilder {
-            context: C::default(),
-            bind: [0, 0, 0, 0],
-            port: 8080,
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            limit_handle_num: DEFAULT_LIMIT_HANDLE_NUM,
-            tcp_backlog: DEFAULT_TCP_BACKLOG,
-        }
+        Self::base(C::default())
     }
 }
 
 impl KurosabiTokioServerBuilder<DefaultContext> {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
-        KurosabiTokioServerBuilder {
-            context: DefaultContext::default(),
-            bind: [0, 0, 0, 0],
-            port: 8080,
-            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
-            limit_handle_num: DEFAULT_LIMIT_HANDLE_NUM,
-            tcp_backlog: DEFAULT_TCP_BACKLOG,
-        }
+        Self::base(DefaultContext::default())
     }
 }
 
@@ -100,12 +238,31 @@ impl<C: Clone + Sync + Send> KurosabiTokioServerBuilder<C> {
     where
         C: Default,
     {
+        Self::base(context)
+    }
+
+    /// ほかのコンストラクタはすべてここを通るので、設定の既定値はここにだけ書きます
+    fn base(context: C) -> Self {
         KurosabiTokioServerBuilder {
             context,
             bind: [0, 0, 0, 0],
             port: 8080,
             keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
             http_header_read_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            limits: HttpLimits::new(),
+            handler_timeout: None,
+            handler_timeout_status: HttpStatusCode::ServiceUnavailable,
+            middlewares: MiddlewareStack::new(),
+            connection_state: None,
+            catchers: Catchers::new(),
+            listener_name: None,
+            trusted_proxies: TrustedProxies::new(),
+            proxy_protocol: ProxyProtocol::Disabled,
+            #[cfg(feature = "tls")]
+            tls: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
             limit_handle_num: DEFAULT_LIMIT_HANDLE_NUM,
             tcp_backlog: DEFAULT_TCP_BACKLOG,
         }
@@ -131,6 +288,75 @@ impl<C: Clone + Sync + Send> KurosabiTokioServerBuilder<C> {
         self
     }
 
+    /// ボディの読み込みが進まないまま待つ上限 (既定は30秒)
+    pub fn body_read_timeout(mut self, duration: Duration) -> Self {
+        self.body_read_timeout = duration;
+        self
+    }
+
+    /// レスポンスの書き込みが進まないまま待つ上限 (既定は30秒)
+    pub fn write_timeout(mut self, duration: Duration) -> Self {
+        self.write_timeout = duration;
+        self
+    }
+
+    /// リクエストの大きさの上限をまとめて設定する
+    pub fn limits(mut self, limits: HttpLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// リクエストラインの上限 (既定は 8KiB、超えたら 414)
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.limits.max_request_line = bytes;
+        self
+    }
+
+    /// ヘッダ全体のバイト数の上限 (既定は 32KiB、超えたら 431)
+    pub fn max_header_bytes(mut self, bytes: usize) -> Self {
+        self.limits.max_header_bytes = bytes;
+        self
+    }
+
+    /// ヘッダの数の上限 (既定は 128、超えたら 431)
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.limits.max_headers = count;
+        self
+    }
+
+    /// メモリに読み込むボディの上限 (既定は 2MiB、超えたら 413)
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.limits.max_body = bytes;
+        self
+    }
+
+    /// パスのセグメント数の上限 (既定は 64、超えたら 414)
+    pub fn max_uri_segments(mut self, count: usize) -> Self {
+        self.limits.max_uri_segments = count;
+        self
+    }
+
+    /// ハンドラのタイムアウト
+    /// 過ぎたらハンドラを打ち切り、まだ何も送っていなければ 503 を返して接続を閉じます
+    /// ルートごとに変えるには `router::handler_timeout` で包みます
+    pub fn handler_timeout(mut self, duration: Duration) -> Self {
+        self.handler_timeout = Some(duration);
+        self
+    }
+
+    /// ハンドラがタイムアウトしたときのステータスコード (既定は 503)
+    pub fn handler_timeout_status(mut self, status: HttpStatusCode) -> Self {
+        self.handler_timeout_status = status;
+        self
+    }
+
+    /// 止めるときに処理中のリクエストを待つ上限 (既定は30秒)
+    /// 過ぎたら残っている接続を打ち切って `run` から返ります
+    pub fn shutdown_timeout(mut self, duration: Duration) -> Self {
+        self.shutdown_timeout = duration;
+        self
+    }
+
     pub fn limit_handle_num(mut self, num: usize) -> Self {
         self.limit_handle_num = num;
         self
@@ -141,14 +367,123 @@ impl<C: Clone + Sync + Send> KurosabiTokioServerBuilder<C> {
         self
     }
 
+    /// ミドルウェアを積む
+    /// 積んだ順に before が呼ばれ、逆順に after が呼ばれます
+    pub fn middleware<M>(mut self, middleware: M) -> Self
+    where
+        C: 'static,
+        M: Middleware<C>,
+    {
+        self.middlewares = self.middlewares.push(DynMiddleware(Arc::new(middleware)));
+        self
+    }
+
+    /// ハンドラの前に呼ばれるクロージャを積む
+    /// `Err` を返すとそのレスポンスで打ち切ります
+    pub fn before<F, Fut>(self, f: F) -> Self
+    where
+        C: 'static,
+        F: Fn(Connection<C, Reader, Writer>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = BeforeResult<C, Reader, Writer>> + Send,
+    {
+        self.middleware(Before(f))
+    }
+
+    /// ハンドラの後に呼ばれるクロージャを積む
+    pub fn after<F, Fut>(self, f: F) -> Self
+    where
+        C: 'static,
+        F: Fn(Connection<C, Reader, Writer, ResponseReadyToSend>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Connection<C, Reader, Writer, ResponseReadyToSend>> + Send,
+    {
+        self.middleware(After(f))
+    }
+
+    /// 接続ごとの状態を作るファクトリを追加する
+    /// 接続を受け付けたときに相手のアドレスを受け取って一度だけ呼ばれ、
+    /// 作った値はその接続の全リクエストで `conn.connection_state::<T>()` から使えます
+    pub fn connection_state<T, F>(mut self, factory: F) -> Self
+    where
+        T: Send + Sync + 'static,
+        F: Fn(Option<SocketAddr>) -> T + Send + Sync + 'static,
+    {
+        self.connection_state = Some(chain_connection_state(
+            self.connection_state.take(),
+            factory,
+        ));
+        self
+    }
+
+    /// エラーページを設定する
+    /// ボディが空か text/plain のエラーレスポンスを `Accept` に合わせて差し替えます
+    pub fn catchers(mut self, catchers: Catchers) -> Self {
+        self.catchers = catchers;
+        self
+    }
+
+    /// 信頼するプロキシ (ロードバランサーなど) のアドレス範囲
+    /// 相手がこの中にあれば `Forwarded` / `X-Forwarded-*` から本当のクライアントを求め、
+    /// `conn.client_ip()` / `client_scheme()` / `client_host()` で見えるようにします
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// PROXY protocol (v1 / v2) のヘッダを HTTP の前に読む
+    /// 読んだクライアントのアドレスは `conn.peer_addr()` に、TLV は `conn.connection_info().proxy_header()` に入ります
+    /// ヘッダを待つ上限は `http_header_read_timeout` です
+    pub fn proxy_protocol(mut self, mode: ProxyProtocol) -> Self {
+        self.proxy_protocol = mode;
+        self
+    }
+
+    /// TLS で受ける
+    /// ハンドシェイクを待つ上限は `http_header_read_timeout` です
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// リスナーの名前を付ける
+    /// ハンドラからは `conn.connection_info().listener_name()` で見えます
+    pub fn listener_name<S>(mut self, name: S) -> Self
+    where
+        S: Into<Arc<str>>,
+    {
+        self.listener_name = Some(name.into());
+        self
+    }
+
     pub(crate) fn router_and_build_inner<H>(self, handler: H) -> KurosabiTokioServer<C, H>
     where
         H: Handler<C>,
     {
-        let my_router = MyRouter { handler, _marker: PhantomData };
-        let router = KurosabiRouter::with_context_and_router(my_router, self.context);
+        let my_router = MyRouter {
+            handler,
+            middlewares: self.middlewares,
+            _marker: PhantomData,
+        };
+        let mut router = KurosabiRouter::with_context_and_router(my_router, self.context);
+        router.set_connection_state_factory(self.connection_state);
+        router.set_catchers(self.catchers);
+        router.set_trusted_proxies(self.trusted_proxies);
+        router.set_body_read_timeout(Some(self.body_read_timeout));
+        router.set_write_timeout(Some(self.write_timeout));
+        router.set_limits(self.limits);
+        router.set_handler_timeout(self.handler_timeout);
+        router.set_handler_timeout_status(self.handler_timeout_status);
+        let shutdown = ShutdownHandle::new();
+        router.set_shutdown(shutdown.clone());
         KurosabiTokioServer {
             router,
+            listener_name: self.listener_name,
+            proxy_protocol: self.proxy_protocol,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            handshake_timeout: self.http_header_read_timeout,
+            shutdown,
+            shutdown_timeout: self.shutdown_timeout,
             bind: self.bind,
             port: self.port,
             limit_handle_num: self.limit_handle_num,
@@ -156,26 +491,52 @@ impl<C: Clone + Sync + Send> KurosabiTokioServerBuilder<C> {
         }
     }
 
+    /// `PathRouter` など `Handler` を実装した型でサーバーを構築する
+    pub fn handler_and_build<H>(self, handler: H) -> KurosabiTokioServer<C, H>
+    where
+        H: Handler<C>,
+    {
+        self.router_and_build_inner(handler)
+    }
+
     pub fn router_and_build<F, Fut>(self, handler: F) -> KurosabiTokioServer<C, F>
     where
-        F: Fn(Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>) -> Fut + Clone + Send + Sync + 'static,
-        Fut: Future<Output = Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend>>
-            + Send
-            + 'static,
+        F: Fn(Connection<C, Reader, Writer>) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Connection<C, Reader, Writer, ResponseReadyToSend>> + Send + 'static,
     {
         self.router_and_build_inner(handler)
     }
 }
 
 impl<C: Clone + Sync + Send + 'static, H: Handler<C>> KurosabiTokioServer<C, H> {
+    /// サーバーを止めるハンドル
+    /// `run` の前に取り出しておき、別のタスクから `shutdown` を呼んでください
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// 止めるまで接続を受け付ける
+    /// `shutdown_handle` で止めると、処理中のリクエストを待ってから返ります
     pub async fn run(self) -> std::io::Result<()> {
+        self.run_until(pending()).await
+    }
+
+    /// `signal` が完了するか `shutdown_handle` で止めるまで接続を受け付ける
+    ///
+    /// ```ignore
+    /// server.run_until(async { tokio::signal::ctrl_c().await.ok(); }).await?;
+    /// ```
+    pub async fn run_until<F>(self, signal: F) -> std::io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
         let socket = TcpSocket::new_v4()?;
         let addr = SocketAddrV4::new(Ipv4Addr::from(self.bind), self.port);
         socket.bind(SocketAddr::V4(addr))?;
 
         let listener = socket.listen(self.tcp_backlog)?;
         #[cfg(feature = "logging")]
-        info!(
+        log::info!(
             "Server listening on {}:{}",
             self.bind
                 .iter()
@@ -188,45 +549,241 @@ impl<C: Clone + Sync + Send + 'static, H: Handler<C>> KurosabiTokioServer<C, H>
         // 同時に処理する接続数を制限
         let sem = Arc::new(Semaphore::new(self.limit_handle_num));
         let router = self.router;
+        let shutdown = self.shutdown;
+        let (proxy_protocol, handshake_timeout) = (self.proxy_protocol, self.handshake_timeout);
+        let listener_info = listener_info(listener.local_addr().ok(), self.listener_name);
+        #[cfg(feature = "tls")]
+        let acceptor = self.tls.as_ref().map(TlsConfig::acceptor).transpose()?;
 
+        let mut stop = pin!(async {
+            select(pin!(signal), pin!(shutdown.requested())).await;
+        });
+        // 受け付けに失敗しても、処理中の接続を止めてから返す
+        let mut result = Ok(());
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let (stream, addr) = tokio::select! {
+                biased;
+                _ = &mut stop => break,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        #[cfg(feature = "logging")]
+                        log::warn!("Failed to accept a connection: {}", e);
+                        result = Err(e);
+                        break;
+                    },
+                },
+            };
             #[cfg(feature = "logging")]
-            debug!("Accepted connection from {}", addr);
-            let permit = sem
-                .clone()
-                .acquire_owned()
-                .await
-                .expect("Semaphore unexpectedly closed");
+            log::debug!("Accepted connection from {}", addr);
+            let permit = tokio::select! {
+                biased;
+                _ = &mut stop => break,
+                permit = sem.clone().acquire_owned() => permit.expect("Semaphore unexpectedly closed"),
+            };
 
             let router_ref = router.clone();
+            let listener_info = listener_info.clone();
+            #[cfg(feature = "tls")]
+            let acceptor = acceptor.clone();
+            let guard = shutdown.track();
+            let forced = shutdown.clone();
+            let task = async move {
+                let mut stream = stream;
+                let info = accepted_info(&listener_info, addr, stream.local_addr().ok());
+                let Some((info, buffered)) = accept_proxy_header(
+                    &mut (&mut stream).compat(),
+                    proxy_protocol,
+                    handshake_timeout,
+                    info,
+                )
+                .await
+                else {
+                    return;
+                };
+                #[cfg(feature = "tls")]
+                if let Some(acceptor) = acceptor {
+                    let handshake = tls::accept(&acceptor, stream, buffered);
+                    pin_mut!(handshake);
+                    let (stream, tls_info) = match with_timeout(handshake, handshake_timeout).await {
+                        Ok(Ok(accepted)) => accepted,
+                        Ok(Err(_e)) => {
+                            #[cfg(feature = "logging")]
+                            log::debug!("TLS handshake with {} failed: {}", addr, _e);
+                            return;
+                        },
+                        Err(()) => {
+                            #[cfg(feature = "logging")]
+                            log::debug!("TLS handshake with {} timed out", addr);
+                            return;
+                        },
+                    };
+                    let (reader, writer) = tokio::io::split(stream);
+                    let reader = TokioReadHalf(ReadInner::Tls(reader)).compat();
+                    let writer = TokioWriteHalf(WriteInner::Tls(writer)).compat_write();
+                    let _ = router_ref
+                        .new_connection_loop_with_info(reader, writer, info.with_tls(tls_info))
+                        .await;
+                    return;
+                }
+                let (reader, writer) = stream.into_split();
+                #[cfg(feature = "tls")]
+                let (reader, writer) = (TokioReadHalf::from(reader), TokioWriteHalf::from(writer));
+                let (reader, writer) = (reader.compat(), writer.compat_write());
+                let _ = router_ref
+                    .new_connection_loop_with_buffered(reader, writer, info, buffered)
+                    .await;
+            };
             tokio::spawn(async move {
                 let _permit = permit; // dropで返却される
-                let (reader, writer) = stream.into_split();
-                let reader = reader.compat();
-                let writer = writer.compat_write();
-                let _ = router_ref.new_connection_loop(reader, writer).await;
+                let _guard = guard;
+                // 止めるときに待ちきれなければ、接続ごと打ち切る
+                select(pin!(task), pin!(forced.forced())).await;
             });
         }
+
+        // 受け付けをやめて、処理中の接続が終わるのを待つ
+        shutdown.shutdown();
+        drop(listener);
+        #[cfg(feature = "logging")]
+        log::info!(
+            "Shutting down, waiting for {} connections",
+            shutdown.active_connections()
+        );
+        let _aborted = shutdown.drain(self.shutdown_timeout).await;
+        #[cfg(feature = "logging")]
+        if _aborted > 0 {
+            log::warn!(
+                "Aborted {} connections after the shutdown timeout",
+                _aborted
+            );
+        }
+        result
     }
 }
 
 #[derive(Clone)]
 struct MyRouter<C: Clone + Sync + Send, H> {
     handler: H,
+    middlewares: MiddlewareStack<DynMiddleware<C>>,
     _marker: PhantomData<fn() -> C>,
 }
 
-impl<C, H> Router<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend> for MyRouter<C, H>
+impl<C, H> Router<C, Reader, Writer, ResponseReadyToSend> for MyRouter<C, H>
 where
     C: Clone + Sync + Send + 'static,
     H: Handler<C>,
 {
     #[inline(always)]
-    async fn router(
-        &self,
-        conn: Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>>,
-    ) -> Connection<C, Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>, ResponseReadyToSend> {
-        self.handler.call(conn).await
+    async fn router(&self, conn: Connection<C, Reader, Writer>) -> Connection<C, Reader, Writer, ResponseReadyToSend> {
+        if self.middlewares.is_empty() {
+            return self.handler.call(conn).await;
+        }
+        self.middlewares
+            .run(conn, |conn| self.handler.call(conn))
+            .await
+    }
+}
+
+/// TLS を有効にしたときの、受け付けた接続の読み込み側 (平文の TCP か TLS)
+#[cfg(feature = "tls")]
+pub struct TokioReadHalf(ReadInner);
+
+/// TLS を有効にしたときの、受け付けた接続の書き込み側 (平文の TCP か TLS)
+#[cfg(feature = "tls")]
+pub struct TokioWriteHalf(WriteInner);
+
+#[cfg(feature = "tls")]
+enum ReadInner {
+    Plain(OwnedReadHalf),
+    Tls(tokio::io::ReadHalf<TlsStream>),
+}
+
+#[cfg(feature = "tls")]
+enum WriteInner {
+    Plain(OwnedWriteHalf),
+    Tls(tokio::io::WriteHalf<TlsStream>),
+}
+
+#[cfg(feature = "tls")]
+impl TokioReadHalf {
+    /// この接続が TLS か
+    pub fn is_tls(&self) -> bool {
+        match &self.0 {
+            ReadInner::Plain(_) => false,
+            ReadInner::Tls(_) => true,
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<OwnedReadHalf> for TokioReadHalf {
+    fn from(half: OwnedReadHalf) -> Self {
+        TokioReadHalf(ReadInner::Plain(half))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<OwnedWriteHalf> for TokioWriteHalf {
+    fn from(half: OwnedWriteHalf) -> Self {
+        TokioWriteHalf(WriteInner::Plain(half))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl AsyncRead for TokioReadHalf {
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().0 {
+            ReadInner::Plain(half) => Pin::new(half).poll_read(cx, buf),
+            ReadInner::Tls(half) => Pin::new(half).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl AsyncWrite for TokioWriteHalf {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().0 {
+            WriteInner::Plain(half) => Pin::new(half).poll_write(cx, buf),
+            WriteInner::Tls(half) => Pin::new(half).poll_write(cx, buf),
+        }
+    }
+
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().0 {
+            WriteInner::Plain(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+            WriteInner::Tls(half) => Pin::new(half).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        match &self.0 {
+            WriteInner::Plain(half) => half.is_write_vectored(),
+            WriteInner::Tls(half) => half.is_write_vectored(),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().0 {
+            WriteInner::Plain(half) => Pin::new(half).poll_flush(cx),
+            WriteInner::Tls(half) => Pin::new(half).poll_flush(cx),
+        }
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().0 {
+            WriteInner::Plain(half) => Pin::new(half).poll_shutdown(cx),
+            WriteInner::Tls(half) => Pin::new(half).poll_shutdown(cx),
+        }
     }
 }
@@ -0,0 +1,482 @@
+//! クライアント証明書から Subject と SAN を読むだけの、最小限の DER の読み取り
+//! 証明書の検証は rustls (webpki) が済ませているので、ここでは中身を取り出すだけです
+
+use std::net::IpAddr;
+
+use crate::http::{PeerCertificate, SubjectAltName};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const EXPLICIT_VERSION: u8 = 0xa0;
+const EXPLICIT_EXTENSIONS: u8 = 0xa3;
+
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_DOMAIN_COMPONENT: &[u8] = &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19];
+const OID_USER_ID: &[u8] = &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01];
+
+/// RFC 4514 で短い名前が決まっている属性
+const ATTRIBUTE_NAMES: &[(&[u8], &str)] = &[
+    (OID_COMMON_NAME, "CN"),
+    (&[0x55, 0x04, 0x06], "C"),
+    (&[0x55, 0x04, 0x07], "L"),
+    (&[0x55, 0x04, 0x08], "ST"),
+    (&[0x55, 0x04, 0x09], "STREET"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (OID_DOMAIN_COMPONENT, "DC"),
+    (OID_USER_ID, "UID"),
+];
+
+/// 証明書の DER から `PeerCertificate` を作る
+/// 読めなかった部分は空のままにします
+pub(crate) fn peer_certificate(der: &[u8]) -> PeerCertificate {
+    let digest = ring::digest::digest(&ring::digest::SHA256, der);
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    let mut cert = PeerCertificate::new(der, fingerprint);
+    let Some(tbs) = tbs_certificate(der) else {
+        return cert;
+    };
+    if let Some((subject, common_name)) = name(tbs.subject) {
+        cert = cert.with_subject(&subject);
+        if let Some(common_name) = common_name {
+            cert = cert.with_common_name(&common_name);
+        }
+    }
+    if let Some(names) = tbs.extensions.and_then(subject_alt_names) {
+        cert = cert.with_subject_alt_names(names);
+    }
+    cert
+}
+
+/// DER の読み手
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.0.first().copied()
+    }
+
+    /// タグと中身を1つ読む
+    fn read(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let n = (first & 0x7f) as usize;
+            if n == 0 || n > 4 {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at_checked(n)?;
+            let len = bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+            (len, rest)
+        };
+        let (value, rest) = rest.split_at_checked(len)?;
+        self.0 = rest;
+        Some((tag, value))
+    }
+
+    /// 指定したタグのものを1つ読む
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.read()? {
+            (t, value) if t == tag => Some(value),
+            _ => None,
+        }
+    }
+}
+
+struct TbsCertificate<'a> {
+    subject: &'a [u8],
+    extensions: Option<&'a [u8]>,
+}
+
+fn tbs_certificate(der: &[u8]) -> Option<TbsCertificate<'_>> {
+    let cert = Der(der).expect(SEQUENCE)?;
+    let mut tbs = Der(Der(cert).expect(SEQUENCE)?);
+    if tbs.peek_tag() == Some(EXPLICIT_VERSION) {
+        tbs.read()?;
+    }
+    // serialNumber, signature, issuer, validity
+    for _ in 0..4 {
+        tbs.read()?;
+    }
+    let subject = tbs.expect(SEQUENCE)?;
+    // subjectPublicKeyInfo
+    tbs.read()?;
+    let mut extensions = None;
+    while let Some((tag, value)) = tbs.read() {
+        if tag == EXPLICIT_EXTENSIONS {
+            extensions = Some(value);
+        }
+    }
+    Some(TbsCertificate { subject, extensions })
+}
+
+/// Name を RFC 4514 の文字列にする (RDN は逆順に並べます)
+/// CN も一緒に返します
+fn name(der: &[u8]) -> Option<(String, Option<String>)> {
+    let mut rdns = Vec::new();
+    let mut common_name = None;
+    let mut seq = Der(der);
+    while !seq.is_empty() {
+        let mut set = Der(seq.expect(SET)?);
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let mut atv = Der(set.expect(SEQUENCE)?);
+            let oid = atv.expect(OID)?;
+            let raw = atv.0;
+            let (tag, value) = atv.read()?;
+            let text = string_value(tag, value);
+            if oid == OID_COMMON_NAME
+                && let Some(text) = &text
+            {
+                common_name = Some(text.clone());
+            }
+            let key = ATTRIBUTE_NAMES
+                .iter()
+                .find(|(known, _)| *known == oid)
+                .map_or_else(|| dotted_oid(oid), |(_, short)| short.to_string());
+            let value = match text {
+                Some(text) => escape(&text),
+                None => format!("#{}", hex(raw)),
+            };
+            attributes.push(format!("{}={}", key, value));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some((rdns.join(","), common_name))
+}
+
+/// 文字列型の値を読む (知らない型なら None)
+fn string_value(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, TeletexString, IA5String
+        0x0c | 0x13 | 0x14 | 0x16 => std::str::from_utf8(value).ok().map(str::to_string),
+        // BMPString
+        0x1e => {
+            let units: Vec<u16> = value
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&units).ok()
+        },
+        _ => None,
+    }
+}
+
+/// RFC 4514 のエスケープ
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        if c == '\0' {
+            escaped.push_str("\\00");
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn dotted_oid(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut value = 0u64;
+    for &b in oid {
+        value = (value << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (value / 40).min(2);
+                arcs.push(first);
+                arcs.push(value - first * 40);
+            } else {
+                arcs.push(value);
+            }
+            value = 0;
+        }
+    }
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// extensions から SAN を探して読む
+fn subject_alt_names(extensions: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut seq = Der(Der(extensions).expect(SEQUENCE)?);
+    while !seq.is_empty() {
+        let mut extension = Der(seq.expect(SEQUENCE)?);
+        let oid = extension.expect(OID)?;
+        if extension.peek_tag() == Some(BOOLEAN) {
+            extension.read()?;
+        }
+        let value = extension.expect(OCTET_STRING)?;
+        if oid == OID_SUBJECT_ALT_NAME {
+            return general_names(value);
+        }
+    }
+    None
+}
+
+fn general_names(der: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut seq = Der(Der(der).expect(SEQUENCE)?);
+    let mut names = Vec::new();
+    while let Some((tag, value)) = seq.read() {
+        let text = || std::str::from_utf8(value).ok().map(Into::into);
+        let name = match tag {
+            // rfc822Name
+            0x81 => text().map(SubjectAltName::Email),
+            // dNSName
+            0x82 => text().map(SubjectAltName::Dns),
+            // uniformResourceIdentifier
+            0x86 => text().map(SubjectAltName::Uri),
+            // iPAddress
+            0x87 => match value.len() {
+                4 => <[u8; 4]>::try_from(value).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(value).ok().map(IpAddr::from),
+                _ => None,
+            }
+            .map(SubjectAltName::Ip),
+            // otherName, directoryName などは読まない
+            _ => None,
+        };
+        names.extend(name);
+    }
+    Some(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    /// テスト用の DER の書き手 (長さは必要なら長形式)
+    fn tlv(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+        let value = parts.concat();
+        let mut out = vec![tag];
+        match value.len() {
+            len @ 0..0x80 => out.push(len as u8),
+            len @ 0x80..0x100 => out.extend([0x81, len as u8]),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend(value);
+        out
+    }
+
+    fn atv(oid: &[u8], tag: u8, value: &[u8]) -> Vec<u8> {
+        tlv(SEQUENCE, &[&tlv(OID, &[oid]), &tlv(tag, &[value])])
+    }
+
+    fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+        let critical = if critical { tlv(BOOLEAN, &[&[0xff]]) } else { Vec::new() };
+        tlv(
+            SEQUENCE,
+            &[&tlv(OID, &[oid]), &critical, &tlv(OCTET_STRING, &[value])],
+        )
+    }
+
+    fn certificate(version: bool, subject: &[u8], extensions: Option<&[u8]>) -> Vec<u8> {
+        let algorithm = tlv(
+            SEQUENCE,
+            &[&tlv(OID, &[&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]])],
+        );
+        let version = if version {
+            tlv(EXPLICIT_VERSION, &[&tlv(0x02, &[&[2]])])
+        } else {
+            Vec::new()
+        };
+        let extensions = extensions.map_or_else(Vec::new, |e| {
+            tlv(EXPLICIT_EXTENSIONS, &[&tlv(SEQUENCE, &[e])])
+        });
+        let tbs = tlv(
+            SEQUENCE,
+            &[
+                &version,
+                &tlv(0x02, &[&[0x01, 0x23]]),
+                &algorithm,
+                &tlv(SEQUENCE, &[]),
+                &tlv(
+                    SEQUENCE,
+                    &[&tlv(0x17, &[b"260101000000Z"]), &tlv(0x17, &[b"270101000000Z"])],
+                ),
+                subject,
+                &tlv(SEQUENCE, &[&algorithm, &tlv(0x03, &[&[0, 1, 2, 3]])]),
+                &extensions,
+            ],
+        );
+        tlv(SEQUENCE, &[&tbs, &algorithm, &tlv(0x03, &[&[0, 0xaa]])])
+    }
+
+    fn subject() -> Vec<u8> {
+        tlv(
+            SEQUENCE,
+            &[
+                &tlv(SET, &[&atv(&[0x55, 0x04, 0x06], 0x13, b"JP")]),
+                &tlv(SET, &[&atv(&[0x55, 0x04, 0x0a], 0x0c, b"Example, Inc.")]),
+                &tlv(
+                    SET,
+                    &[&atv(OID_COMMON_NAME, 0x0c, b"client"), &atv(&[0x2a, 0x03, 0x04], 0x02, &[5])],
+                ),
+            ],
+        )
+    }
+
+    fn san() -> Vec<u8> {
+        tlv(
+            SEQUENCE,
+            &[
+                &tlv(0x82, &[b"client.example"]),
+                &tlv(0x81, &[b"client@example.com"]),
+                &tlv(0x86, &[b"spiffe://example/client"]),
+                &tlv(0x87, &[&[192, 0, 2, 1]]),
+                &tlv(0x87, &[&Ipv6Addr::LOCALHOST.octets()]),
+                // 長さのおかしい iPAddress と otherName は読み飛ばす
+                &tlv(0x87, &[&[1, 2, 3]]),
+                &tlv(0xa0, &[&tlv(OID, &[&[0x2a]])]),
+            ],
+        )
+    }
+
+    #[test]
+    fn full_certificate() {
+        let basic_constraints = extension(&[0x55, 0x1d, 0x13], true, &tlv(SEQUENCE, &[]));
+        let extensions = [basic_constraints, extension(OID_SUBJECT_ALT_NAME, false, &san())].concat();
+        let der = certificate(true, &subject(), Some(&extensions));
+        let cert = peer_certificate(&der);
+        assert_eq!(cert.der(), der);
+        assert_eq!(cert.fingerprint_hex().len(), 64);
+        assert_eq!(
+            cert.subject(),
+            r"CN=client+1.2.3.4=#020105,O=Example\, Inc.,C=JP"
+        );
+        assert_eq!(cert.common_name(), Some("client"));
+        assert_eq!(
+            cert.subject_alt_names(),
+            [
+                SubjectAltName::Dns("client.example".into()),
+                SubjectAltName::Email("client@example.com".into()),
+                SubjectAltName::Uri("spiffe://example/client".into()),
+                SubjectAltName::Ip("192.0.2.1".parse().unwrap()),
+                SubjectAltName::Ip("::1".parse().unwrap()),
+            ]
+        );
+        assert_eq!(cert.dns_names().collect::<Vec<_>>(), ["client.example"]);
+    }
+
+    #[test]
+    fn without_version_and_extensions() {
+        let der = certificate(false, &subject(), None);
+        let cert = peer_certificate(&der);
+        assert_eq!(cert.common_name(), Some("client"));
+        assert!(cert.subject_alt_names().is_empty());
+
+        // SAN のない extensions
+        let extensions = extension(&[0x55, 0x1d, 0x0f], true, &tlv(0x03, &[&[0x07, 0x80]]));
+        let cert = peer_certificate(&certificate(true, &tlv(SEQUENCE, &[]), Some(&extensions)));
+        assert_eq!(cert.subject(), "");
+        assert_eq!(cert.common_name(), None);
+        assert!(cert.subject_alt_names().is_empty());
+    }
+
+    #[test]
+    fn long_form_lengths() {
+        let long = "x".repeat(300);
+        let subject = tlv(
+            SEQUENCE,
+            &[&tlv(SET, &[&atv(OID_COMMON_NAME, 0x0c, long.as_bytes())])],
+        );
+        let cert = peer_certificate(&certificate(true, &subject, None));
+        assert_eq!(cert.common_name(), Some(long.as_str()));
+
+        let mut der = Der(&[0x04, 0x81, 0x02, b'h', b'i', 0x05, 0x00]);
+        assert_eq!(der.read(), Some((0x04, &b"hi"[..])));
+        assert_eq!(der.expect(0x05), Some(&[][..]));
+        assert!(der.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        // 長さが足りない、長さのバイト数が 0 か 4 より多い、タグが違う
+        assert_eq!(Der(&[0x30]).read(), None);
+        assert_eq!(Der(&[0x30, 0x03, 0x01]).read(), None);
+        assert_eq!(Der(&[0x30, 0x82, 0x01]).read(), None);
+        assert_eq!(Der(&[0x30, 0x80]).read(), None);
+        assert_eq!(Der(&[0x30, 0x85, 0, 0, 0, 0, 0]).read(), None);
+        assert_eq!(Der(&[0x31, 0x00]).expect(SEQUENCE), None);
+
+        // 読めなければ DER とハッシュだけ
+        for der in [&b""[..], b"not a certificate", &[0x30, 0x02, 0x30, 0x00]] {
+            let cert = peer_certificate(der);
+            assert_eq!(cert.der(), der);
+            assert_eq!(cert.subject(), "");
+            assert!(cert.subject_alt_names().is_empty());
+        }
+        let mut der = certificate(
+            true,
+            &subject(),
+            Some(&extension(OID_SUBJECT_ALT_NAME, false, &san())),
+        );
+        der.truncate(der.len() / 2);
+        assert_eq!(peer_certificate(&der).subject(), "");
+    }
+
+    #[test]
+    fn string_values() {
+        assert_eq!(string_value(0x13, b"JP").as_deref(), Some("JP"));
+        assert_eq!(
+            string_value(0x16, b"a@example").as_deref(),
+            Some("a@example")
+        );
+        assert_eq!(
+            string_value(0x1e, &[0x30, 0x8d, 0x30, 0x4f]).as_deref(),
+            Some("ろく")
+        );
+        assert_eq!(string_value(0x0c, &[0xff]), None);
+        assert_eq!(string_value(0x02, &[5]), None);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("a,b+c"), r"a\,b\+c");
+        assert_eq!(escape(r#""<x>;\"#), r#"\"\<x\>\;\\"#);
+        assert_eq!(escape("#a b "), r"\#a b\ ");
+        assert_eq!(escape(" "), r"\ ");
+        assert_eq!(escape("a\0b"), r"a\00b");
+        assert_eq!(escape(""), "");
+    }
+
+    #[test]
+    fn oids() {
+        assert_eq!(dotted_oid(OID_COMMON_NAME), "2.5.4.3");
+        assert_eq!(
+            dotted_oid(OID_DOMAIN_COMPONENT),
+            "0.9.2342.19200300.100.1.25"
+        );
+        assert_eq!(
+            dotted_oid(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+            "1.2.840.10045.4.3.2"
+        );
+        assert_eq!(dotted_oid(&[0x88, 0x37]), "2.999");
+        assert_eq!(hex(&[0x00, 0xab]), "00ab");
+    }
+}
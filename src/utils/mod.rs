@@ -1,10 +1,16 @@
-use futures_io::AsyncWrite;
+use futures_io::{AsyncRead, AsyncWrite};
 use futures_timer::Delay;
 use futures_util::{
     AsyncWriteExt,
     future::{Either, select},
 };
-use std::{borrow::Cow, io::IoSlice, time::Duration};
+use std::{
+    borrow::Cow,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 #[inline(always)]
 pub async fn with_timeout<F, T>(fut: F, dur: Duration) -> Result<T, ()>
@@ -17,6 +23,76 @@ where
     }
 }
 
+/// 読み書きが進まないまま一定時間たったら `TimedOut` にする
+///
+/// 進んでいる間はタイマーを作らないので、速い相手にはほとんどコストがかかりません
+/// timeout が None なら素通しです
+pub struct IdleTimeout<'a, T> {
+    inner: &'a mut T,
+    timeout: Option<Duration>,
+    delay: Option<Delay>,
+}
+
+impl<'a, T> IdleTimeout<'a, T> {
+    #[inline]
+    pub fn new(inner: &'a mut T, timeout: Option<Duration>) -> Self {
+        IdleTimeout { inner, timeout, delay: None }
+    }
+
+    /// 内側が Pending のときに、止まってからの時間を見る
+    #[inline]
+    fn poll_idle<V>(&mut self, cx: &mut Context<'_>, res: Poll<io::Result<V>>) -> Poll<io::Result<V>> {
+        let Some(timeout) = self.timeout else {
+            return res;
+        };
+        if res.is_ready() {
+            self.delay = None;
+            return res;
+        }
+        let delay = self.delay.get_or_insert_with(|| Delay::new(timeout));
+        match Pin::new(delay).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "peer stalled longer than the idle timeout",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<'_, T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        self.poll_idle(cx, res)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<'_, T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        self.poll_idle(cx, res)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut *self.inner).poll_write_vectored(cx, bufs);
+        self.poll_idle(cx, res)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = Pin::new(&mut *self.inner).poll_flush(cx);
+        self.poll_idle(cx, res)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = Pin::new(&mut *self.inner).poll_close(cx);
+        self.poll_idle(cx, res)
+    }
+}
+
 #[inline]
 pub fn write_hex_crlf(mut n: usize, out: &mut [u8; 32]) -> &[u8] {
     // 最大でも "FFFFFFFFFFFFFFFF\r\n" 程度なので 32 で十分
@@ -34,7 +110,7 @@ pub fn write_hex_crlf(mut n: usize, out: &mut [u8; 32]) -> &[u8] {
     }
 
     while n != 0 {
-        let d = (n & 0xF) as usize;
+        let d = n & 0xF;
         i -= 1;
         out[i] = HEX[d];
         n >>= 4;
@@ -196,12 +272,12 @@ pub fn url_decode_fast(input: &str) -> Cow<'_, str> {
     let mut i = 0;
     while i < bytes.len() {
         if bytes[i] == b'%' {
-            if i + 2 < bytes.len() {
-                if let (Some(hi), Some(lo)) = (hex_val(bytes[i + 1]), hex_val(bytes[i + 2])) {
-                    out.push((hi << 4) | lo);
-                    i += 3;
-                    continue;
-                }
+            if i + 2 < bytes.len()
+                && let (Some(hi), Some(lo)) = (hex_val(bytes[i + 1]), hex_val(bytes[i + 2]))
+            {
+                out.push((hi << 4) | lo);
+                i += 3;
+                continue;
             }
             out.push(b'%');
             i += 1;
@@ -233,3 +309,273 @@ pub enum UrlDecodeError {
     InvalidHex,
     InvalidUtf8,
 }
+
+/// `Accept` で JSON が HTML より好まれているか
+/// 同じ重みなら HTML を選びます
+pub(crate) fn prefers_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let mut html_q = 0.0f32;
+    let mut json_q = 0.0f32;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let mime = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match mime.as_str() {
+            "text/html" | "application/xhtml+xml" => html_q = html_q.max(q),
+            "application/json" => json_q = json_q.max(q),
+            m if m.starts_with("application/") && m.ends_with("+json") => json_q = json_q.max(q),
+            _ => {},
+        }
+    }
+    json_q > html_q
+}
+
+/// JSON の文字列リテラルとして書き出す (前後の `"` を含む)
+pub fn json_escape_into(out: &mut String, input: &str) {
+    out.push('"');
+    for c in input.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::pin,
+        sync::Arc,
+        task::{Wake, Waker},
+        thread::{self, Thread},
+        time::Instant,
+    };
+
+    use futures_util::{AsyncReadExt, FutureExt};
+
+    use super::*;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// タイマーを本当に待つので、スレッドを止めて起こされるのを待つ
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(v) = fut.as_mut().poll(&mut cx) {
+                return v;
+            }
+            thread::park();
+        }
+    }
+
+    /// interval ごとに1バイトずつ進み、使い切ったら止まる相手
+    struct Trickle {
+        remaining: usize,
+        interval: Duration,
+        delay: Option<Delay>,
+    }
+
+    impl Trickle {
+        fn new(remaining: usize, interval: Duration) -> Self {
+            Trickle { remaining, interval, delay: None }
+        }
+
+        fn poll_step(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+            if self.remaining == 0 {
+                return Poll::Pending;
+            }
+            let delay = self.delay.get_or_insert_with(|| Delay::new(self.interval));
+            match Pin::new(delay).poll(cx) {
+                Poll::Ready(()) => {
+                    self.delay = None;
+                    self.remaining -= 1;
+                    Poll::Ready(true)
+                },
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.poll_step(cx).map(|_| {
+                buf[0] = b'x';
+                Ok(1)
+            })
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.poll_step(cx).map(|_| Ok(1))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const STEP: Duration = Duration::from_millis(20);
+    const IDLE: Duration = Duration::from_millis(150);
+
+    #[test]
+    fn stalled_reader_times_out() {
+        let mut reader = Trickle::new(0, STEP);
+        let started = Instant::now();
+        let mut buf = [0u8; 8];
+        let err = block_on(IdleTimeout::new(&mut reader, Some(IDLE)).read(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= IDLE);
+    }
+
+    #[test]
+    fn stalled_writer_times_out() {
+        let mut writer = Trickle::new(0, STEP);
+        let started = Instant::now();
+        let err = block_on(IdleTimeout::new(&mut writer, Some(IDLE)).write_all(b"hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= IDLE);
+    }
+
+    #[test]
+    fn progress_resets_the_timer() {
+        // 全体では IDLE の2倍かかるが、1バイトごとには IDLE より早く進む
+        let steps = (IDLE * 2).as_millis() as usize / STEP.as_millis() as usize;
+
+        let mut reader = Trickle::new(steps, STEP);
+        let mut buf = vec![0u8; steps];
+        let started = Instant::now();
+        block_on(IdleTimeout::new(&mut reader, Some(IDLE)).read_exact(&mut buf)).unwrap();
+        assert!(started.elapsed() >= IDLE);
+        assert!(buf.iter().all(|&b| b == b'x'));
+
+        let mut writer = Trickle::new(steps, STEP);
+        let started = Instant::now();
+        block_on(IdleTimeout::new(&mut writer, Some(IDLE)).write_all(&buf)).unwrap();
+        assert!(started.elapsed() >= IDLE);
+    }
+
+    #[test]
+    fn stalls_after_progress_still_time_out() {
+        let mut reader = Trickle::new(3, STEP);
+        let mut buf = [0u8; 8];
+        let err = block_on(IdleTimeout::new(&mut reader, Some(IDLE)).read_exact(&mut buf)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reader.remaining, 0);
+    }
+
+    #[test]
+    fn ready_io_does_not_start_a_timer() {
+        let mut data: &[u8] = b"abc";
+        let mut idle = IdleTimeout::new(&mut data, Some(IDLE));
+        let mut buf = [0u8; 3];
+        assert_eq!(idle.read(&mut buf).now_or_never().unwrap().unwrap(), 3);
+        assert!(idle.delay.is_none());
+    }
+
+    #[test]
+    fn without_timeout_waits_forever() {
+        let mut reader = Trickle::new(0, STEP);
+        let mut idle = IdleTimeout::new(&mut reader, None);
+        let mut buf = [0u8; 1];
+        assert!(idle.read(&mut buf).now_or_never().is_none());
+        thread::sleep(STEP);
+        assert!(idle.read(&mut buf).now_or_never().is_none());
+        assert!(idle.delay.is_none());
+    }
+
+    fn escaped(input: &str) -> String {
+        let mut out = String::new();
+        json_escape_into(&mut out, input);
+        out
+    }
+
+    #[test]
+    fn json_escape_quotes_and_backslashes() {
+        assert_eq!(escaped(""), r#""""#);
+        assert_eq!(escaped(r#"say "hi" \o/"#), r#""say \"hi\" \\o/""#);
+    }
+
+    #[test]
+    fn json_escape_control_characters() {
+        assert_eq!(escaped("a\nb\rc\td"), r#""a\nb\rc\td""#);
+        assert_eq!(
+            escaped("\u{0}\u{8}\u{c}\u{1b}\u{1f}"),
+            r#""\u0000\u0008\u000c\u001b\u001f""#
+        );
+        // 0x20 以上はそのまま
+        assert_eq!(escaped(" \u{7f}"), "\" \u{7f}\"");
+    }
+
+    #[test]
+    fn json_escape_keeps_non_ascii() {
+        assert_eq!(escaped("くろさび 🍵 é"), "\"くろさび 🍵 é\"");
+        assert_eq!(escaped("\u{2028}\u{2029}"), "\"\u{2028}\u{2029}\"");
+    }
+
+    #[test]
+    fn json_escape_appends() {
+        let mut out = String::from("[");
+        json_escape_into(&mut out, "a");
+        out.push(',');
+        json_escape_into(&mut out, "b");
+        assert_eq!(out, r#"["a","b""#);
+    }
+
+    #[test]
+    fn prefers_json_when_json_outweighs_html() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("application/problem+json")));
+        assert!(prefers_json(Some("Application/JSON")));
+        assert!(prefers_json(Some(
+            "application/vnd.api+json; charset=utf-8"
+        )));
+        assert!(prefers_json(Some("text/html;q=0.9, application/json")));
+        assert!(prefers_json(Some(
+            "application/xhtml+xml;q=0.1, application/json;q=0.2"
+        )));
+        assert!(prefers_json(Some("application/json, */*;q=0.1")));
+    }
+
+    #[test]
+    fn prefers_html_otherwise() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("")));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(Some("application/*")));
+        assert!(!prefers_json(Some("text/plain")));
+        // 同じ重みなら HTML
+        assert!(!prefers_json(Some("application/json, text/html")));
+        assert!(!prefers_json(Some("text/html, application/json;q=0.9")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        // 不正な q は 1 とみなす
+        assert!(!prefers_json(Some(
+            "application/json;q=0.5, text/html;q=abc"
+        )));
+    }
+}